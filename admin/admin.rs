@@ -0,0 +1,116 @@
+//! Command line client for the server's admin endpoints.
+
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use uuid::Uuid;
+
+/// Runs admin commands against a server
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    /// The server's admin token
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    token: String,
+    /// Address of the server
+    #[arg(long, env = "SERVER_URL", default_value = "http://localhost:3000")]
+    server: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List every connection and its state
+    Connections,
+    /// Disconnect a user
+    Kick { user_id: Uuid },
+    /// Stop a running game
+    Abort { game_id: Uuid },
+    /// Show the queue length, table size and whether matchmaking is paused
+    Queue,
+    /// Disconnect everyone waiting in the queue
+    Drain,
+    /// Stop starting new games
+    Pause,
+    /// Start new games again
+    Resume,
+    /// Change the number of players per game
+    Size { players: usize },
+}
+
+impl Command {
+    /// The method, path and body of the request the command makes
+    fn request(&self) -> (&'static str, String, Option<serde_json::Value>) {
+        match self {
+            Command::Connections => ("GET", "/admin/connections".to_string(), None),
+            Command::Kick { user_id } => ("POST", format!("/admin/users/{user_id}/kick"), None),
+            Command::Abort { game_id } => ("POST", format!("/admin/games/{game_id}/abort"), None),
+            Command::Queue => ("GET", "/admin/queue".to_string(), None),
+            Command::Drain => ("POST", "/admin/queue/drain".to_string(), None),
+            Command::Pause => ("POST", "/admin/matchmaking/pause".to_string(), None),
+            Command::Resume => ("POST", "/admin/matchmaking/resume".to_string(), None),
+            Command::Size { players } => (
+                "PUT",
+                "/admin/queue/size".to_string(),
+                Some(serde_json::json!({ "size": players })),
+            ),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let server = cli.server.trim_end_matches('/');
+    let (method, path, body) = cli.command.request();
+
+    let request = ureq::request(method, &format!("{server}{path}"))
+        .set("Authorization", &format!("Bearer {}", cli.token));
+    let response = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+
+    match response {
+        Ok(response) => {
+            let body = response.into_string().unwrap_or_default();
+            match serde_json::from_str::<serde_json::Value>(&body) {
+                Ok(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
+                Err(_) => println!("OK"),
+            }
+            ExitCode::SUCCESS
+        }
+        Err(ureq::Error::Status(status, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            eprintln!("Server returned {status}: {body}");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("Couldn't reach server: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_commands_are_parsed() {
+        let cli = Cli::try_parse_from(["admin", "--token", "secret", "size", "3"]).unwrap();
+        let (method, path, body) = cli.command.request();
+        assert_eq!((method, path.as_str()), ("PUT", "/admin/queue/size"));
+        assert_eq!(body, Some(serde_json::json!({ "size": 3 })));
+
+        assert!(Cli::try_parse_from(["admin", "--token", "secret", "size", "many"]).is_err());
+        assert!(Cli::try_parse_from(["admin", "--token", "secret", "kick", "someone"]).is_err());
+        assert!(Cli::try_parse_from(["admin", "--token", "secret", "unpause"]).is_err());
+    }
+}
@@ -1,177 +1,111 @@
-#![feature(async_closure)]
-
-use core::panic;
-use std::sync::Mutex;
-
-use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::{self, tungstenite::Message};
-use url;
-
 use skitgubbe_game::{
-    api::{player_messages, server_messages},
+    api::player_messages::action::{PlayAction, SetupAction},
+    bot::{
+        ismcts::{Ismcts, IsmctsConfig},
+        reference::{Heuristic, LowestCard, Random},
+        runner::{self, RunnerConfig},
+        Event, Strategy, View,
+    },
     deck::Card,
 };
 
-#[tokio::main]
-async fn main() {
-    let addr = std::env::args().nth(1).expect("No arg for address");
-    let url = url::Url::parse(&(format!("ws://{addr}/queue"))).expect("Invalid ws url");
-
-    let (mut stream, _) = tokio_tungstenite::connect_async(&url)
-        .await
-        .expect("connecting to address");
-    println!("Connected to {addr}");
-
-    //              GET ID
-    let our_id = serde_json::from_str::<server_messages::ServerNotification>(
-        &stream.next().await.unwrap().unwrap().to_string(),
-    )
-    .expect("expect server notification game start");
-    let server_messages::ServerNotification::Id(our_id) = our_id else {
-        panic!("message isn't id");
-    };
-    println!("ID is: {our_id}");
-
-    // Wait for game to start
-    let s_notif = serde_json::from_str::<server_messages::ServerNotification>(
-        &stream.next().await.unwrap().unwrap().to_string(),
-    )
-    .expect("expect server notification game start");
-
-    // let server_messages::ServerNotification::GameStart(player_ids) = s_notif else {
-    //     panic!("server notif not game start");
-    // };
-    // let other_player_ids: Vec<String> = player_ids.into_iter().filter(|id| *id != our_id).collect();
+/// Example bot: swaps its highest cards to the bottom, then always plays its lowest card
+struct ExampleBot;
 
-    //              GAME STARTED
-    println!("Game starting");
-
-    //              SWAP STAGE
-    let stage = serde_json::from_str::<server_messages::Stage>(
-        &stream.next().await.unwrap().unwrap().to_string(),
-    )
-    .expect("expect game stage swap");
-
-    if let server_messages::Stage::Swap = stage {
-        println!("Swap stage");
-    } else {
-        panic!("stage not swap");
-    }
-
-    let mut cards;
-
-    loop {
-        let message = stream.next().await.unwrap().unwrap().to_string();
-        let state = serde_json::from_str::<server_messages::GameState>(&message)
-            .expect("expect game state notif");
-
-        cards = state.cards;
-
-        //          CARD SWAPPING STRATEGY
-
-        let hand_high = cards
-            .hand
-            .iter()
-            .max()
-            .expect("we should never have an empty hand");
-        let bottom_min = cards
+impl Strategy for ExampleBot {
+    fn on_setup(&mut self, view: &View) -> SetupAction {
+        let cards = &view.state.cards;
+        let Some(hand_high) = cards.hand.iter().max() else {
+            return SetupAction::FinishExchange;
+        };
+        let Some((bottom_index, bottom_min)) = cards
             .bottom_cards
             .iter()
             .enumerate()
-            .map(|(i, vec)| {
-                (
-                    i,
-                    vec.get(0)
-                        .expect("Each bottom card stack should never be empty"),
-                )
-            })
+            .filter_map(|(i, stack)| Some((i, stack.first()?)))
             .min_by_key(|&(_, card)| card)
-            .unwrap();
+        else {
+            return SetupAction::FinishExchange;
+        };
 
         // if we have a higher card than one of the cards below then swap as many as we can
-        if hand_high > bottom_min.1 {
-            // count how many we have
+        if hand_high > bottom_min {
             let num_cards = cards
                 .hand
                 .iter()
                 .filter(|card| card.rank == hand_high.rank)
                 .count();
-            let swap_cards = vec![hand_high.clone(); num_cards];
-            let msg = player_messages::action::SetupAction::ExchangeCard {
-                hand: swap_cards,
-                bottom: bottom_min.0,
-            };
-
-            let _ = stream
-                .send(Message::Text(serde_json::to_string(&msg).unwrap()))
-                .await;
             println!("Swapping high card");
-
-            continue;
-        }
-
-        // if able to compound card then do it
-        let compoundable_card_hand_indexes: Option<(usize, Vec<&Card>)> = cards
-            .bottom_cards
-            .iter()
-            .enumerate()
-            .find_map(|(bottom_card_index, vis_card_vec)| {
-                let vis_card = &vis_card_vec[0];
-
-                let matching_cards = cards
-                    .hand
-                    .iter()
-                    .filter(|hand_card| hand_card.rank == vis_card.rank)
-                    .collect::<Vec<&Card>>();
-
-                if !matching_cards.is_empty() {
-                    return Some((bottom_card_index, matching_cards));
-                }
-                None
-            });
-        if let Some((bottom_index, compound_cards)) = compoundable_card_hand_indexes {
-            let msg = player_messages::action::SetupAction::CompoundCard {
-                hand: compound_cards.iter().map(|&card| card.clone()).collect(),
+            return SetupAction::ExchangeCard {
+                hand: vec![hand_high.clone(); num_cards],
                 bottom: bottom_index,
             };
-            let _ = stream
-                .send(Message::Text(serde_json::to_string(&msg).unwrap()))
-                .await;
-            println!("compounding cards");
+        }
 
-            continue;
+        // if able to compound card then do it
+        let compoundable =
+            cards
+                .bottom_cards
+                .iter()
+                .enumerate()
+                .find_map(|(bottom_index, stack)| {
+                    let bottom_card = stack.first()?;
+                    let matching_cards: Vec<Card> = cards
+                        .hand
+                        .iter()
+                        .filter(|hand_card| hand_card.rank == bottom_card.rank)
+                        .cloned()
+                        .collect();
+                    (!matching_cards.is_empty()).then_some((bottom_index, matching_cards))
+                });
+        if let Some((bottom, hand)) = compoundable {
+            println!("Compounding cards");
+            return SetupAction::CompoundCard { hand, bottom };
         }
 
-        break;
+        println!("Finished exchange");
+        SetupAction::FinishExchange
     }
 
-    let _ = stream
-        .send(Message::Text(
-            serde_json::to_string(&player_messages::action::SetupAction::FinishExchange).unwrap(),
-        ))
-        .await;
-    println!("Finished exchange");
-
-    let stage = serde_json::from_str::<server_messages::Stage>(
-        &stream.next().await.unwrap().unwrap().to_string(),
-    )
-    .expect("expect game stage play");
-    if let server_messages::Stage::Play = stage {
-        println!("Play stage");
-    } else {
-        panic!("stage not play");
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        // legal plays are in hand order, which is sorted lowest first
+        let action = view.legal_plays().swap_remove(0);
+        println!("Playing {action:?}");
+        action
     }
 
-    //                  CARD PLAYING STRATEGY
+    fn on_event(&mut self, event: &Event) {
+        match event {
+            Event::State(_) => {}
+            event => println!("{event:?}"),
+        }
+    }
+}
 
-    // loop {
-    //     todo!();
-    // }
+/// Usage: `client <address> [example|random|lowest|heuristic|ismcts]`
+#[tokio::main]
+async fn main() {
+    let addr = std::env::args().nth(1).expect("No arg for address");
+    let mut strategy: Box<dyn Strategy> = match std::env::args().nth(2).as_deref() {
+        None | Some("example") => Box::new(ExampleBot),
+        Some("random") => Box::new(Random::new(rand::random())),
+        Some("lowest") => Box::new(LowestCard),
+        Some("heuristic") => Box::new(Heuristic),
+        Some("ismcts") => Box::new(Ismcts::new(IsmctsConfig {
+            time_limit: Some(std::time::Duration::from_secs(1)),
+            seed: rand::random(),
+            ..IsmctsConfig::default()
+        })),
+        Some(other) => panic!("Unknown bot {other}"),
+    };
+    let config = RunnerConfig {
+        url: format!("ws://{addr}/queue"),
+        games: Some(1),
+        ..RunnerConfig::default()
+    };
 
-    loop {
-        println!(
-            "Received message: {}",
-            stream.next().await.unwrap().unwrap().to_string()
-        );
+    match runner::run(&mut strategy, &config).await {
+        Ok(summary) => println!("{summary:?}"),
+        Err(e) => eprintln!("{e}"),
     }
 }
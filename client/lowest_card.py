@@ -0,0 +1,49 @@
+#!/usr/bin/env python3
+"""Example bot for the subprocess protocol, plays its lowest card that can go on the stack.
+
+Try it with `skitgubbe-sim play "python3 client/lowest_card.py" heuristic`.
+"""
+
+import json
+import sys
+
+
+def placeable(view):
+    state = view["state"]
+    cards = state["cards"]
+    if cards["hand"]:
+        options = cards["hand"]
+    else:
+        # the visible cards, or the hidden ones once they are gone
+        options = [card for stack in cards["bottom_cards"] for card in stack]
+        ours = dict((id, visible) for id, visible in state["other_players"])
+        if not ours.get(view["id"]) and len(options) == 1:
+            # the last hidden card can't be a 2, 10 or ace
+            options = [card for card in options if card["rank"] not in (2, 10, 14)]
+
+    top = state["stack"][-1]["rank"] if state["stack"] else None
+    return [
+        card
+        for card in options
+        if card["rank"] in (2, 10) or top is None or card["rank"] >= top
+    ]
+
+
+def reply(message):
+    print(json.dumps(message), flush=True)
+
+
+for line in sys.stdin:
+    request = json.loads(line)
+    if request == "Quit":
+        break
+    if "Hello" in request:
+        reply({"name": "lowest card (python)"})
+    elif "Setup" in request:
+        reply("FinishExchange")
+    elif "Turn" in request:
+        cards = placeable(request["Turn"])
+        if cards:
+            reply({"PlaceCard": {"card": min(cards, key=lambda card: card["rank"])}})
+        else:
+            reply("PickupStack")
@@ -0,0 +1,102 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::games::AbortReason;
+use crate::AppState;
+
+/// Endpoints for intervening in a running server. Every request must have the header
+/// `Authorization: Bearer <ADMIN_TOKEN>`.
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/connections", get(connections))
+        .route("/admin/users/:user_id/kick", post(kick))
+        .route("/admin/games/:game_id/abort", post(abort_game))
+        .route("/admin/queue", get(queue_status))
+        .route("/admin/queue/drain", post(drain_queue))
+        .route("/admin/queue/size", put(set_table_size))
+        .route("/admin/matchmaking/pause", post(pause))
+        .route("/admin/matchmaking/resume", post(resume))
+        .route_layer(middleware::from_fn_with_state(state, authenticate))
+}
+
+async fn authenticate(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.admin_token.is_none() {
+        return (StatusCode::FORBIDDEN, "Admin endpoints are disabled").into_response();
+    }
+
+    if !bearer_token(request.headers()).is_some_and(|token| state.is_admin(token)) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// The token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+async fn connections(State(state): State<AppState>) -> Response {
+    Json(state.registry.list()).into_response()
+}
+
+async fn kick(Path(user_id): Path<Uuid>, State(state): State<AppState>) -> Response {
+    if !state.registry.kick(user_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn abort_game(Path(game_id): Path<Uuid>, State(state): State<AppState>) -> Response {
+    if !state.games.abort(game_id, AbortReason::Admin) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn queue_status(State(state): State<AppState>) -> Response {
+    Json(state.queue.status().await).into_response()
+}
+
+#[derive(Serialize)]
+struct Drained {
+    drained: usize,
+}
+
+async fn drain_queue(State(state): State<AppState>) -> Response {
+    let drained = state.queue.drain().await;
+    Json(Drained { drained }).into_response()
+}
+
+#[derive(Deserialize)]
+struct TableSize {
+    size: usize,
+}
+
+async fn set_table_size(State(state): State<AppState>, Json(body): Json<TableSize>) -> Response {
+    match state.queue.set_table_size(body.size).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn pause(State(state): State<AppState>) -> Response {
+    state.queue.set_paused(true).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn resume(State(state): State<AppState>) -> Response {
+    state.queue.set_paused(false).await;
+    StatusCode::NO_CONTENT.into_response()
+}
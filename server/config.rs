@@ -0,0 +1,547 @@
+use std::{fmt, io, net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use skitgubbe_game::game::RuleSet;
+use skitgubbe_game::user::Heartbeat;
+
+use crate::game_manager::MAX_TABLE_SIZE;
+
+/// Hosts Skitgubbe games over websockets
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Args {
+    /// TOML config file, flags take precedence over it
+    #[arg(short, long, env = "SKITGUBBE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long)]
+    bind: Option<SocketAddr>,
+    /// Overrides the port of the bind address
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+    /// Players per game
+    #[arg(long)]
+    table_size: Option<usize>,
+    /// Users turned away once this many are waiting
+    #[arg(long)]
+    max_queue_length: Option<usize>,
+    /// How often connections are pinged
+    #[arg(long)]
+    heartbeat_interval_secs: Option<u64>,
+    /// How long a connection may go without answering a ping before it is dropped
+    #[arg(long)]
+    heartbeat_timeout_secs: Option<u64>,
+    /// How long selected players have to acknowledge a ready check
+    #[arg(long)]
+    ready_check_secs: Option<u64>,
+    /// How long players have to choose what to do after a game
+    #[arg(long)]
+    post_game_secs: Option<u64>,
+    /// How far behind the admin view of a spectated game is
+    #[arg(long)]
+    god_view_delay_secs: Option<u64>,
+    /// How long running games have to finish when shutting down
+    #[arg(long)]
+    shutdown_grace_secs: Option<u64>,
+    /// How long players of games suspended by a restart have to reconnect
+    #[arg(long)]
+    resume_secs: Option<u64>,
+    /// Rounds before a game is declared a draw
+    #[arg(long)]
+    max_turns: Option<usize>,
+    /// Cards players draw up to
+    #[arg(long)]
+    hand_size: Option<usize>,
+    /// Whether four of a kind on top of the stack clears it
+    #[arg(long)]
+    clear_on_four_of_a_kind: Option<bool>,
+    /// JSON file finished games and player stats are kept in
+    #[arg(long)]
+    storage_path: Option<PathBuf>,
+    /// Makes the deals of every game reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Log filter, eg. `debug` or `info,skitgubbe_game=trace`
+    #[arg(long, env = "RUST_LOG")]
+    log_level: Option<String>,
+    /// Enables the admin endpoints, which must be called with this token
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+}
+
+/// Settings for the whole server. Every field has a default so a config file only needs to
+/// contain what it changes.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// Token required by admin only endpoints, if unset they are disabled
+    pub admin_token: Option<String>,
+    /// Seeds the generator every game's deal is drawn from, random if unset
+    pub seed: Option<u64>,
+    /// Origins browsers may call the server from, any if empty
+    pub cors_origins: Vec<String>,
+    pub queue: QueueConfig,
+    pub timeouts: Timeouts,
+    pub rules: RuleSet,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub table_size: usize,
+    pub max_length: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub heartbeat_interval_secs: u64,
+    /// A connection that hasn't answered a ping for this long is considered disconnected
+    pub heartbeat_timeout_secs: u64,
+    pub ready_check_secs: u64,
+    pub post_game_secs: u64,
+    /// How far behind the admin view of a spectated game is
+    pub god_view_delay_secs: u64,
+    pub shutdown_grace_secs: u64,
+    /// How long players of games suspended by a restart have to reconnect
+    pub resume_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub path: PathBuf,
+    pub flush_interval_secs: u64,
+    /// How often running games are checkpointed so they survive a crash
+    pub checkpoint_interval_secs: u64,
+    pub crash_dump_dir: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Filter in the same syntax as `RUST_LOG`
+    pub level: String,
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            admin_token: None,
+            seed: None,
+            cors_origins: vec![],
+            queue: QueueConfig::default(),
+            timeouts: Timeouts::default(),
+            rules: RuleSet::default(),
+            storage: StorageConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            table_size: 1,
+            max_length: 1000,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_secs: 10,
+            heartbeat_timeout_secs: 30,
+            ready_check_secs: 10,
+            post_game_secs: 30,
+            god_view_delay_secs: 30,
+            shutdown_grace_secs: 60,
+            resume_secs: 120,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: "skitgubbe.json".into(),
+            flush_interval_secs: 30,
+            checkpoint_interval_secs: 10,
+            crash_dump_dir: "crash_dumps".into(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            interval: Duration::from_secs(self.heartbeat_interval_secs),
+            timeout: Duration::from_secs(self.heartbeat_timeout_secs),
+        }
+    }
+
+    pub fn ready_check(&self) -> Duration {
+        Duration::from_secs(self.ready_check_secs)
+    }
+
+    pub fn post_game(&self) -> Duration {
+        Duration::from_secs(self.post_game_secs)
+    }
+
+    pub fn god_view_delay(&self) -> Duration {
+        Duration::from_secs(self.god_view_delay_secs)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+
+    pub fn resume(&self) -> Duration {
+        Duration::from_secs(self.resume_secs)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// Every setting that has an invalid value
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Couldn't read config {path:?}: {e}"),
+            ConfigError::Parse(path, e) => write!(f, "Couldn't parse config {path:?}: {e}"),
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid config:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the config file given in `args`, if any, and applies the flags on top of it
+    ///
+    /// # Errors
+    /// If the file can't be read or parsed, or the resulting config is invalid
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let s = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&s).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => Config::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, args: Args) {
+        if let Some(bind) = args.bind {
+            self.bind = bind;
+        }
+        if let Some(port) = args.port {
+            self.bind.set_port(port);
+        }
+        if let Some(table_size) = args.table_size {
+            self.queue.table_size = table_size;
+        }
+        if let Some(max_length) = args.max_queue_length {
+            self.queue.max_length = max_length;
+        }
+        if let Some(secs) = args.heartbeat_interval_secs {
+            self.timeouts.heartbeat_interval_secs = secs;
+        }
+        if let Some(secs) = args.heartbeat_timeout_secs {
+            self.timeouts.heartbeat_timeout_secs = secs;
+        }
+        if let Some(secs) = args.ready_check_secs {
+            self.timeouts.ready_check_secs = secs;
+        }
+        if let Some(secs) = args.post_game_secs {
+            self.timeouts.post_game_secs = secs;
+        }
+        if let Some(secs) = args.god_view_delay_secs {
+            self.timeouts.god_view_delay_secs = secs;
+        }
+        if let Some(secs) = args.shutdown_grace_secs {
+            self.timeouts.shutdown_grace_secs = secs;
+        }
+        if let Some(secs) = args.resume_secs {
+            self.timeouts.resume_secs = secs;
+        }
+        if let Some(max_turns) = args.max_turns {
+            self.rules.max_turns = max_turns;
+        }
+        if let Some(hand_size) = args.hand_size {
+            self.rules.hand_size = hand_size;
+        }
+        if let Some(clear) = args.clear_on_four_of_a_kind {
+            self.rules.clear_on_four_of_a_kind = clear;
+        }
+        if let Some(path) = args.storage_path {
+            self.storage.path = path;
+        }
+        if args.seed.is_some() {
+            self.seed = args.seed;
+        }
+        if let Some(format) = args.log_format {
+            self.log.format = format;
+        }
+        if let Some(level) = args.log_level {
+            self.log.level = level;
+        }
+        if args.admin_token.is_some() {
+            self.admin_token = args.admin_token;
+        }
+    }
+
+    /// # Errors
+    /// Lists every invalid setting rather than stopping at the first
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        if !(1..=MAX_TABLE_SIZE).contains(&self.queue.table_size) {
+            problems.push(format!(
+                "queue.table_size must be between 1 and {MAX_TABLE_SIZE}"
+            ));
+        }
+        if self.queue.max_length < self.queue.table_size {
+            problems.push("queue.max_length must be at least queue.table_size".to_string());
+        }
+
+        let timeouts = &self.timeouts;
+        for (name, secs) in [
+            ("heartbeat_interval_secs", timeouts.heartbeat_interval_secs),
+            ("heartbeat_timeout_secs", timeouts.heartbeat_timeout_secs),
+            ("ready_check_secs", timeouts.ready_check_secs),
+            ("post_game_secs", timeouts.post_game_secs),
+            // without a delay the admin view could be used to help a player
+            ("god_view_delay_secs", timeouts.god_view_delay_secs),
+            ("resume_secs", timeouts.resume_secs),
+        ] {
+            if secs == 0 {
+                problems.push(format!("timeouts.{name} must be at least 1"));
+            }
+        }
+        if timeouts.heartbeat_timeout_secs <= timeouts.heartbeat_interval_secs {
+            problems.push(
+                "timeouts.heartbeat_timeout_secs must be longer than heartbeat_interval_secs"
+                    .to_string(),
+            );
+        }
+
+        if let Err(e) = self.rules.validate() {
+            problems.push(format!("rules.{e}"));
+        }
+        if self.storage.flush_interval_secs == 0 {
+            problems.push("storage.flush_interval_secs must be at least 1".to_string());
+        }
+        if self.storage.checkpoint_interval_secs == 0 {
+            problems.push("storage.checkpoint_interval_secs must be at least 1".to_string());
+        }
+        if self.admin_token.as_deref() == Some("") {
+            problems.push("admin_token can't be empty".to_string());
+        }
+        for origin in &self.cors_origins {
+            if origin.parse::<axum::http::HeaderValue>().is_err() {
+                problems.push(format!(
+                    "cors_origins contains an invalid origin {origin:?}"
+                ));
+            }
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            problems.push(format!("log.level {:?} is invalid: {e}", self.log.level));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// Parses `args` as if the environment held only `env`. Reading the real environment would
+    /// make the tests depend on each other, as setting it is process wide.
+    fn parse(args: &[&str], env: &[(&str, &str)]) -> Args {
+        let mut command = Args::command();
+        let env_args: Vec<_> = command
+            .get_arguments()
+            .filter_map(|arg| Some((arg.get_id().clone(), arg.get_env()?.to_owned())))
+            .collect();
+        for (id, var) in env_args {
+            let value = env
+                .iter()
+                .find(|(name, _)| var == **name)
+                .map(|(_, value)| -> &'static str { Box::leak(value.to_string().into()) });
+            command = command.mut_arg(id, |arg| {
+                let arg = arg.env(None);
+                match value {
+                    Some(value) => arg.default_value(value),
+                    None => arg,
+                }
+            });
+        }
+        Args::from_arg_matches(&command.get_matches_from(args)).unwrap()
+    }
+
+    fn write_config(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("skitgubbe-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_validate_lists_every_problem() {
+        let mut config = Config::default();
+        config.queue.table_size = 0;
+        config.timeouts.heartbeat_interval_secs = 30;
+        config.storage.flush_interval_secs = 0;
+        config.admin_token = Some(String::new());
+        config.log.level = "info,=".to_string();
+
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("Config should be invalid");
+        };
+        assert_eq!(problems.len(), 5, "{problems:?}");
+        assert!(problems[0].starts_with("queue.table_size"));
+        assert!(problems[1].starts_with("timeouts.heartbeat_timeout_secs"));
+        assert!(problems[2].starts_with("storage.flush_interval_secs"));
+        assert!(problems[3].starts_with("admin_token"));
+        assert!(problems[4].starts_with("log.level"));
+
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_flags_override_env_which_overrides_the_file() {
+        let path = write_config(
+            "bind = \"127.0.0.1:4000\"\nseed = 1\n[queue]\ntable_size = 2\nmax_length = 10\n",
+        );
+        let config_arg = path.to_str().unwrap();
+        let env = [("PORT", "5000")];
+
+        let args = parse(
+            &["server", "--config", config_arg, "--table-size", "3"],
+            &env,
+        );
+        let config = Config::load(args).unwrap();
+        assert_eq!(config.bind, SocketAddr::from(([127, 0, 0, 1], 5000)));
+        assert_eq!(config.queue.table_size, 3);
+        assert_eq!(config.queue.max_length, 10);
+        assert_eq!(config.seed, Some(1));
+
+        let args = parse(&["server", "--config", config_arg, "--port", "6000"], &env);
+        let config = Config::load(args).unwrap();
+        assert_eq!(config.bind.port(), 6000);
+        assert_eq!(config.queue.table_size, 2);
+
+        let args = parse(&["server", "--config", config_arg], &[]);
+        assert_eq!(Config::load(args).unwrap().bind.port(), 4000);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_timeouts_and_rules_have_flags() {
+        let args = parse(
+            &[
+                "server",
+                "--heartbeat-interval-secs",
+                "5",
+                "--heartbeat-timeout-secs",
+                "15",
+                "--god-view-delay-secs",
+                "60",
+                "--resume-secs",
+                "30",
+                "--max-turns",
+                "100",
+                "--hand-size",
+                "5",
+                "--clear-on-four-of-a-kind",
+                "false",
+            ],
+            &[],
+        );
+        let config = Config::load(args).unwrap();
+        assert_eq!(config.timeouts.heartbeat().interval, Duration::from_secs(5));
+        assert_eq!(config.timeouts.heartbeat().timeout, Duration::from_secs(15));
+        assert_eq!(config.timeouts.god_view_delay(), Duration::from_secs(60));
+        assert_eq!(config.timeouts.resume(), Duration::from_secs(30));
+        let rules = RuleSet {
+            max_turns: 100,
+            hand_size: 5,
+            clear_on_four_of_a_kind: false,
+        };
+        assert_eq!(config.rules, rules);
+
+        let args = parse(
+            &["server", "--hand-size", "9", "--god-view-delay-secs", "0"],
+            &[],
+        );
+        let Err(ConfigError::Invalid(problems)) = Config::load(args) else {
+            panic!("Config should be invalid");
+        };
+        assert_eq!(problems.len(), 2, "{problems:?}");
+    }
+
+    #[test]
+    fn test_file_problems_are_reported() {
+        let path = write_config("[queue]\ntable_sise = 2\n");
+        let args = parse(&["server", "--config", path.to_str().unwrap()], &[]);
+        assert!(matches!(Config::load(args), Err(ConfigError::Parse(..))));
+        let _ = std::fs::remove_file(path);
+
+        let path = write_config("[queue]\ntable_size = 2\nmax_length = 1\n");
+        let args = parse(&["server", "--config", path.to_str().unwrap()], &[]);
+        let Err(ConfigError::Invalid(problems)) = Config::load(args) else {
+            panic!("Config should be invalid");
+        };
+        assert_eq!(problems.len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use skitgubbe_game::api::server_messages::GameEvent;
+
+thread_local! {
+    /// Backtrace of the last panic on this thread, taken by [`take_backtrace`]
+    static LAST_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Records a backtrace for every panic so it can be written into a crash dump after the panic
+/// has been caught. The previous hook is still called.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::force_capture().to_string();
+        LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+        default_hook(info);
+    }));
+}
+
+/// Takes the backtrace of the last panic on the current thread.
+///
+/// Must be called on the thread that caught the panic, before it panics again.
+pub fn take_backtrace() -> Option<String> {
+    LAST_BACKTRACE.with(|last| last.borrow_mut().take())
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
+/// Everything needed to reproduce a crashed game
+#[derive(Serialize)]
+pub struct CrashDump {
+    pub game_id: Uuid,
+    /// Seed the deck was shuffled with
+    pub seed: u64,
+    /// Player ids in seat order
+    pub players: Vec<Uuid>,
+    pub message: String,
+    pub backtrace: Option<String>,
+    pub events: Vec<GameEvent>,
+}
+
+impl CrashDump {
+    /// Writes the dump to `<dir>/<game_id>.json`, returning the path written to
+    pub fn write(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.json", self.game_id));
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,68 @@
+//! Multiplayer Elo, each game is scored as a match between every pair of players.
+//! See <http://sradack.blogspot.com/2008/06/elo-rating-system-multiple-players.html>
+
+pub const INITIAL_RATING: f64 = 1000.0;
+const K: f64 = 32.0;
+
+/// Returns the new ratings of the players after a game.
+///
+/// The winner beats every other player and everyone else draws against each other. With no
+/// winner every pair draws.
+pub fn update_ratings(ratings: &[f64], winner: Option<usize>) -> Vec<f64> {
+    let n = ratings.len();
+    if n < 2 {
+        return ratings.to_vec();
+    }
+
+    // scale K so a game is worth the same regardless of table size
+    let k = K / (n - 1) as f64;
+    (0..n)
+        .map(|i| {
+            let change: f64 = (0..n)
+                .filter(|&j| j != i)
+                .map(|j| {
+                    let score = match winner {
+                        Some(w) if w == i => 1.0,
+                        Some(w) if w == j => 0.0,
+                        _ => 0.5,
+                    };
+                    score - expected_score(ratings[i], ratings[j])
+                })
+                .sum();
+            ratings[i] + k * change
+        })
+        .collect()
+}
+
+/// Probability that a player rated `a` beats a player rated `b`
+fn expected_score(a: f64, b: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((b - a) / 400.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_winner_gains_rating() {
+        let new = update_ratings(&[INITIAL_RATING; 3], Some(1));
+        assert!(new[1] > INITIAL_RATING);
+        assert!(new[0] < INITIAL_RATING);
+        assert_eq!(new[0], new[2]);
+    }
+
+    #[test]
+    fn test_ratings_are_zero_sum() {
+        let ratings = [1200.0, 950.0, 1000.0, 1100.0];
+        let new = update_ratings(&ratings, Some(1));
+        let before: f64 = ratings.iter().sum();
+        let after: f64 = new.iter().sum();
+        assert!((before - after).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_equal_draw_changes_nothing() {
+        let new = update_ratings(&[INITIAL_RATING; 2], None);
+        assert_eq!(new, vec![INITIAL_RATING; 2]);
+    }
+}
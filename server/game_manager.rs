@@ -1,87 +1,975 @@
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex as SyncMutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use axum::extract::ws::Message;
-use futures::{lock::Mutex, SinkExt};
+use futures::{future::BoxFuture, lock::Mutex, FutureExt, SinkExt, StreamExt};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
 
+use skitgubbe_game::api::{player_messages::action::LobbyAction, server_messages};
 use skitgubbe_game::game;
 use skitgubbe_game::user::User;
 
+use crate::config::Config;
+use crate::crash::{self, CrashDump};
+use crate::games::{AbortReason, ActiveGame, Games};
+use crate::metrics::Metrics;
+use crate::registry::{AlreadyRegistered, Registry, UserState};
+use crate::storage::{unix_time, Checkpoint, GameOutcome, GameRecord, Storage};
+
+#[derive(Clone)]
 pub struct ServerQueue {
-    queue: Arc<Mutex<VecDeque<User>>>,
+    registry: Registry,
+    games: Games,
+    storage: Storage,
+    metrics: Metrics,
+    queue: Arc<Mutex<VecDeque<Uuid>>>,
+    /// While paused users can join the queue but no games are started
+    paused: Arc<AtomicBool>,
+    shutting_down: Arc<AtomicBool>,
+    table_size: Arc<AtomicUsize>,
+    max_queue_length: usize,
+    ready_check_deadline: Duration,
+    post_game_deadline: Duration,
+    crash_dump_dir: PathBuf,
+    rules: game::RuleSet,
+    /// Draws the seed of every game, seeded from the config to make deals reproducible
+    seeds: Arc<SyncMutex<StdRng>>,
+    /// Games restored from checkpoints that are waiting for their players, by game id
+    suspended: Arc<SyncMutex<HashMap<Uuid, SuspendedGame>>>,
+    resume_deadline: Duration,
+}
+
+/// A game restored from a checkpoint, waiting for its players to reconnect
+struct SuspendedGame {
+    checkpoint: Checkpoint,
+    /// Players that have reconnected so far
+    reconnected: Vec<Uuid>,
 }
 
-const GAME_PLAYER_SIZE: usize = 1;
+pub const MAX_TABLE_SIZE: usize = 4;
+
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub queued: usize,
+    pub paused: bool,
+    pub table_size: usize,
+}
 
 impl ServerQueue {
-    pub fn new() -> Self {
+    pub fn new(
+        registry: Registry,
+        games: Games,
+        storage: Storage,
+        metrics: Metrics,
+        config: &Config,
+    ) -> Self {
+        let seeds = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
         Self {
+            registry,
+            games,
+            storage,
+            metrics,
             queue: Arc::new(Mutex::new(VecDeque::new())),
+            paused: Arc::new(AtomicBool::new(false)),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            table_size: Arc::new(AtomicUsize::new(config.queue.table_size)),
+            max_queue_length: config.queue.max_length,
+            ready_check_deadline: config.timeouts.ready_check(),
+            post_game_deadline: config.timeouts.post_game(),
+            crash_dump_dir: config.storage.crash_dump_dir.clone(),
+            rules: config.rules.clone(),
+            seeds: Arc::new(SyncMutex::new(seeds)),
+            suspended: Arc::new(SyncMutex::new(HashMap::new())),
+            resume_deadline: config.timeouts.resume(),
         }
     }
 
-    pub async fn push_user(&mut self, user: User) {
-        println!("User: {} added to queue", user.id);
-        // if let Err(_) = user
-        //     .sender
-        //     .send(Message::Text("Waiting in queue".into()))
-        //     .await
-        // {
-        //     drop(user);
-        //     return;
-        // };
-        self.queue.lock().await.push_back(user);
-
-        let len = self.queue.lock().await.len();
-        if len >= GAME_PLAYER_SIZE {
-            // TODO: Check all players are connected
-            let users: Vec<User> = self
-                .queue
-                .lock()
-                .await
-                .split_off(len - GAME_PLAYER_SIZE)
-                .into();
-            let original_arr = Arc::clone(&self.queue);
+    #[instrument(skip_all, fields(user_id = %user.id))]
+    pub async fn push_user(&self, mut user: User) {
+        if self.queue.lock().await.len() >= self.max_queue_length {
+            warn!("Queue is full, turning user away");
+            let msg = server_messages::ServerNotification::QueueFull;
+            let _ = user.send(&serde_json::to_string(&msg).unwrap()).await;
+            return;
+        }
+        let Some(id) = self.register(user).await else {
+            return;
+        };
+        self.enqueue([id]).await;
+        self.start_games().await;
+    }
+
+    /// Registers `user`, turning them away if a player with the same id is still connected
+    async fn register(&self, user: User) -> Option<Uuid> {
+        // a player whose old socket has died can connect again
+        self.registry.prune();
+        match self.registry.register(user) {
+            Ok(id) => Some(id),
+            Err(AlreadyRegistered(mut user)) => {
+                warn!(user_id = %user.id, "Player is already connected, turning user away");
+                let msg = server_messages::ServerNotification::SignInFailed(
+                    "Already connected".to_string(),
+                );
+                let _ = user.send(&serde_json::to_string(&msg).unwrap()).await;
+                None
+            }
+        }
+    }
+
+    pub async fn status(&self) -> QueueStatus {
+        QueueStatus {
+            queued: self.queue.lock().await.len(),
+            paused: self.paused.load(Ordering::Relaxed),
+            table_size: self.table_size.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stops or restarts starting new games, games already running are unaffected
+    pub async fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        info!(
+            paused,
+            "Matchmaking {}",
+            if paused { "paused" } else { "resumed" }
+        );
+        if !paused {
+            self.start_games().await;
+        }
+    }
 
+    /// Changes the number of players per game for games started from now on
+    ///
+    /// # Errors
+    /// If `table_size` is 0 or more than [`MAX_TABLE_SIZE`]
+    pub async fn set_table_size(&self, table_size: usize) -> Result<(), String> {
+        if !(1..=MAX_TABLE_SIZE).contains(&table_size) {
+            return Err(format!("Table size must be between 1 and {MAX_TABLE_SIZE}"));
+        }
+        self.table_size.store(table_size, Ordering::Relaxed);
+        info!(table_size, "Table size changed");
+        self.start_games().await;
+        Ok(())
+    }
+
+    /// Removes and disconnects everyone waiting in the queue, returning how many were removed
+    pub async fn drain(&self) -> usize {
+        let mut queue = self.queue.lock().await;
+        let drained = queue.len();
+        for id in queue.drain(..) {
+            self.registry.kick(id);
+        }
+        info!(drained, "Drained the queue");
+        drained
+    }
+
+    /// Stops matchmaking, tells everyone the server is going away and gives running games
+    /// `grace_period` to finish before suspending them, they are resumed once the server is
+    /// restarted. Every connection is closed afterwards.
+    pub async fn shutdown(&self, grace_period: Duration) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.paused.store(true, Ordering::Relaxed);
+
+        let msg = server_messages::ServerNotification::ShuttingDown {
+            grace_secs: grace_period.as_secs(),
+        };
+        self.registry
+            .broadcast(&serde_json::to_string(&msg).unwrap())
+            .await;
+        self.drain().await;
+
+        info!(?grace_period, "Waiting for running games to finish");
+        if tokio::time::timeout(grace_period, self.games.wait_until_empty())
+            .await
+            .is_err()
+        {
+            warn!("Grace period over, suspending running games");
+            self.games.abort_all(AbortReason::Shutdown);
+            self.games.wait_until_empty().await;
+        }
+
+        self.registry.kick_all();
+    }
+
+    /// Waits for the players of every checkpointed game to reconnect. Games whose players don't
+    /// all reconnect within the resume deadline are recorded as aborted.
+    pub fn restore_checkpoints(&self) {
+        let checkpoints = self.storage.checkpoints();
+        if checkpoints.is_empty() {
+            return;
+        }
+        info!(
+            games = checkpoints.len(),
+            deadline = ?self.resume_deadline,
+            "Waiting for players of suspended games to reconnect"
+        );
+
+        let mut suspended = self.suspended.lock().unwrap();
+        for checkpoint in checkpoints {
+            let game_id = checkpoint.game_id;
+            suspended.insert(
+                game_id,
+                SuspendedGame {
+                    checkpoint,
+                    reconnected: vec![],
+                },
+            );
+
+            let server_queue = self.clone();
             tokio::spawn(async move {
-                let original_arr = original_arr;
-                let users: Vec<_> = users
-                    .into_iter()
-                    .map(|user| Arc::new(Mutex::new(user)))
-                    .collect();
-
-                // start game
-                let winner =
-                    game::SkitGubbe::new(users.iter().map(|user| Arc::clone(user)).collect())
-                        .run()
-                        .await;
-                if let Ok(Some(winner)) = winner {
-                    db_add_winner(&*users[winner].lock().await).await
+                tokio::time::sleep(server_queue.resume_deadline).await;
+                server_queue.expire_suspended(game_id).await;
+            });
+        }
+    }
+
+    /// The suspended game and player a resume token belongs to
+    pub fn resumed_seat(&self, token: Uuid) -> Option<(Uuid, Uuid)> {
+        let suspended = self.suspended.lock().unwrap();
+        suspended.iter().find_map(|(&game_id, game)| {
+            let checkpoint = &game.checkpoint;
+            let seat = checkpoint.resume_tokens.iter().position(|&t| t == token)?;
+            Some((game_id, checkpoint.players[seat]))
+        })
+    }
+
+    /// Seats a reconnected player back at their suspended game, whose id must already be the one
+    /// from [`ServerQueue::resumed_seat`]. The game carries on once everyone is back.
+    #[instrument(skip(self, user), fields(user_id = %user.id))]
+    pub async fn rejoin(&self, user: User, game_id: Uuid) {
+        let Some(id) = self.register(user).await else {
+            return;
+        };
+        let ready = {
+            let mut suspended = self.suspended.lock().unwrap();
+            match suspended.get_mut(&game_id) {
+                Some(game) => {
+                    if !game.reconnected.contains(&id) {
+                        game.reconnected.push(id);
+                    }
+                    info!(
+                        reconnected = game.reconnected.len(),
+                        players = game.checkpoint.players.len(),
+                        "Player rejoined suspended game"
+                    );
+                    if game.reconnected.len() == game.checkpoint.players.len() {
+                        suspended.remove(&game_id).map(|game| Ok(game.checkpoint))
+                    } else {
+                        None
+                    }
                 }
+                None => Some(Err(())),
+            }
+        };
+
+        match ready {
+            Some(Ok(checkpoint)) => {
+                let server_queue = self.clone();
+                tokio::spawn(async move {
+                    let users = checkpoint.players.clone();
+                    server_queue.play(users, Some(checkpoint)).await;
+                });
+            }
+            Some(Err(())) => {
+                info!("Suspended game expired, queueing player instead");
+                self.enqueue([id]).await;
+                self.start_games().await;
+            }
+            None => {}
+        }
+    }
+
+    /// Gives up on a suspended game if its players haven't all reconnected, queueing the ones
+    /// that did
+    #[instrument(skip(self))]
+    async fn expire_suspended(&self, game_id: Uuid) {
+        let Some(game) = self.suspended.lock().unwrap().remove(&game_id) else {
+            return;
+        };
+        warn!(
+            reconnected = game.reconnected.len(),
+            "Players didn't reconnect in time, game aborted"
+        );
+
+        let message = AbortReason::Disconnect.message().to_string();
+        let msg = serde_json::to_string(&server_messages::ServerNotification::GameAborted(
+            message.clone(),
+        ))
+        .unwrap();
+        for &id in &game.reconnected {
+            if let Some(user) = self.registry.user(id) {
+                let _ = user.lock().await.send(&msg).await;
+            }
+        }
+        self.metrics.game_aborted(AbortReason::Disconnect);
+
+        let checkpoint = game.checkpoint;
+        self.storage.record_game(GameRecord {
+            id: game_id,
+            players: checkpoint.players,
+            seed: checkpoint.table.seed(),
+            outcome: GameOutcome::Aborted(message),
+            started_at: checkpoint.started_at,
+            finished_at: unix_time(SystemTime::now()),
+        });
 
-                // TODO: If error check which player caused it and don't add back to queue
+        self.enqueue(game.reconnected).await;
+        self.start_games().await;
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
 
-                // add users back to queue
-                let mut users = users
-                    .into_iter()
-                    .map(|user| {
-                        Arc::into_inner(user)
-                            .expect("Someone else has a reference to this user")
-                            .into_inner()
-                    })
-                    .collect();
+    /// Adds connected users to the back of the queue
+    async fn enqueue(&self, users: impl IntoIterator<Item = Uuid>) {
+        let mut queue = self.queue.lock().await;
+        self.prune_disconnected(&mut queue);
+        for id in users {
+            if let Err(e) = self.registry.transition(id, UserState::Queued) {
+                warn!("{e}");
+                continue;
+            }
+            info!(user_id = %id, "User added to queue");
+            queue.push_back(id);
+        }
+    }
+
+    /// Removes users whose socket has closed or stopped answering the heartbeat
+    fn prune_disconnected(&self, queue: &mut VecDeque<Uuid>) {
+        self.registry.prune();
+        queue.retain(|&id| {
+            if !self.registry.is_connected(id) {
+                info!(user_id = %id, "User disconnected, removed from queue");
+                // usually already forgotten by the prune above
+                let _ = self.registry.disconnect(id);
+                return false;
+            }
+            true
+        });
+    }
 
-                original_arr.lock().await.append(&mut users);
-                todo!("trigger queue")
+    /// Takes tables from the queue while there are enough users and starts their games.
+    ///
+    /// Boxed as finished games requeue their users through this function.
+    fn start_games(&self) -> BoxFuture<'_, ()> {
+        async move {
+            let mut queue = self.queue.lock().await;
+            let table_size = self.table_size.load(Ordering::Relaxed);
+            while !self.paused.load(Ordering::Relaxed) && queue.len() >= table_size {
+                let len = queue.len();
+                let users: Vec<Uuid> = queue.split_off(len - table_size).into();
+
+                let server_queue = self.clone();
+                tokio::spawn(async move {
+                    let Some(users) = server_queue.ready_check_round(users).await else {
+                        return;
+                    };
+                    server_queue.play(users, None).await;
+                });
+            }
+        }
+        .boxed()
+    }
+
+    /// Ready checks the selected `users`, replacing anyone who fails from the queue until a full
+    /// table has acknowledged.
+    ///
+    /// Returns `None` if the queue ran out of replacements, in which case the users that did
+    /// acknowledge are put back at the end of the queue.
+    #[instrument(skip(self))]
+    async fn ready_check_round(&self, mut users: Vec<Uuid>) -> Option<Vec<Uuid>> {
+        let table_size = users.len();
+        let mut ready = Vec::with_capacity(table_size);
+        loop {
+            let results = futures::future::join_all(users.into_iter().map(|id| async move {
+                let is_ready = match self.registry.user(id) {
+                    Some(user) => {
+                        ready_check(&mut *user.lock().await, self.ready_check_deadline).await
+                    }
+                    None => false,
+                };
+                (id, is_ready)
+            }))
+            .await;
+
+            for (id, is_ready) in results {
+                if is_ready {
+                    ready.push(id);
+                } else {
+                    info!(user_id = %id, "User failed ready check");
+                    let _ = self.registry.disconnect(id);
+                }
+            }
+
+            let missing = table_size - ready.len();
+            if missing == 0 {
+                return Some(ready);
+            }
+
+            let mut queue = self.queue.lock().await;
+            self.prune_disconnected(&mut queue);
+            if queue.len() < missing {
+                queue.extend(ready);
+                return None;
+            }
+            let len = queue.len();
+            users = queue.split_off(len - missing).into();
+        }
+    }
+
+    /// Plays games with `users` until the table stops asking for rematches. The first game
+    /// carries on from `checkpoint` if one is given.
+    async fn play(&self, mut users: Vec<Uuid>, mut checkpoint: Option<Checkpoint>) {
+        loop {
+            let game_id = checkpoint
+                .as_ref()
+                .map_or_else(Uuid::new_v4, |checkpoint| checkpoint.game_id);
+            if self.is_shutting_down() || !self.play_game(game_id, &users, checkpoint.take()).await
+            {
+                return;
+            }
+            if self.is_shutting_down() {
+                return;
+            }
+
+            match self.post_game(users).await {
+                Some(rematch) => users = rematch,
+                None => return,
+            }
+        }
+    }
+
+    /// Plays a single game, from `checkpoint` if given, and records its outcome. Returns false if
+    /// the game couldn't be started.
+    #[instrument(name = "game", skip(self, checkpoint), fields(restored = checkpoint.is_some()))]
+    async fn play_game(
+        &self,
+        game_id: Uuid,
+        users: &[Uuid],
+        checkpoint: Option<Checkpoint>,
+    ) -> bool {
+        let started = self
+            .registry
+            .start_game(game_id, users)
+            .map_err(|e| e.to_string())
+            .and_then(|guard| match guard.users() {
+                Ok(table) => Ok((guard, table)),
+                Err(id) => Err(format!("User: {id} left before the game started")),
             });
+        let (guard, table) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                error!("Couldn't start game: {e}");
+                for &id in users {
+                    let _ = self.registry.transition(id, UserState::Connected);
+                }
+                let registry = &self.registry;
+                self.enqueue(
+                    users
+                        .iter()
+                        .copied()
+                        .filter(|&id| registry.is_connected(id)),
+                )
+                .await;
+                self.start_games().await;
+                return false;
+            }
+        };
+
+        // start game
+        let (mut game, started_at, resume_tokens) = match checkpoint {
+            Some(checkpoint) => {
+                info!("Game resumed");
+                let game =
+                    game::SkitGubbe::restore(table.clone(), checkpoint.table, checkpoint.events);
+                let started_at = UNIX_EPOCH + Duration::from_secs(checkpoint.started_at);
+                (game, started_at, checkpoint.resume_tokens)
+            }
+            None => {
+                info!("Game started");
+                let seed = self.seeds.lock().unwrap().next_u64();
+                let game = game::SkitGubbe::with_rules(table.clone(), seed, self.rules.clone());
+                let resume_tokens = users.iter().map(|_| Uuid::new_v4()).collect();
+                (game, SystemTime::now(), resume_tokens)
+            }
+        };
+        let seed = game.seed();
+        for (user, token) in table.iter().zip(&resume_tokens) {
+            let msg = server_messages::ServerNotification::ResumeToken {
+                game_id: game_id.to_string(),
+                token: token.to_string(),
+            };
+            let _ = user
+                .lock()
+                .await
+                .send(&serde_json::to_string(&msg).unwrap())
+                .await;
         }
+        let metrics = self.metrics.clone();
+        game.set_move_observer(Arc::new(move |stage, latency| {
+            metrics.observe_move(stage, latency)
+        }));
+        let events = game.events();
+        let shared_table = game.table();
+        let (abort, mut aborted) = watch::channel(None);
+        self.games.insert(
+            game_id,
+            ActiveGame {
+                players: users.to_vec(),
+                started_at,
+                spectators: game.spectators(),
+                abort,
+                resume_tokens,
+                table: Arc::clone(&shared_table),
+                events: Arc::clone(&events),
+            },
+        );
+        let aborted = async {
+            let reason = aborted
+                .wait_for(Option::is_some)
+                .await
+                .ok()
+                .and_then(|reason| *reason);
+            match reason {
+                Some(reason) => reason,
+                // the sender lives in `self.games` until the game is removed below
+                None => std::future::pending().await,
+            }
+        };
+        let result = tokio::select! {
+            result = AssertUnwindSafe(game.run()).catch_unwind() => Ok(result),
+            reason = aborted => Err(reason),
+        };
+        let outcome = match result {
+            Err(AbortReason::Shutdown) => {
+                // the game future has been dropped so the table can't change any more
+                self.games.save_checkpoint(game_id, &self.storage);
+                warn!("Game suspended for restart");
+                notify_table(
+                    &table,
+                    &server_messages::ServerNotification::GameAborted(
+                        AbortReason::Shutdown.message().to_string(),
+                    ),
+                )
+                .await;
+                self.metrics.game_aborted(AbortReason::Shutdown);
+                None
+            }
+            Err(reason) => {
+                warn!(reason = reason.label(), "Game aborted");
+                let message = reason.message().to_string();
+                notify_table(
+                    &table,
+                    &server_messages::ServerNotification::GameAborted(message.clone()),
+                )
+                .await;
+                self.metrics.game_aborted(reason);
+                Some(GameOutcome::Aborted(message))
+            }
+            Ok(Ok(Ok(winner))) => {
+                info!(winner = ?winner.map(|winner| users[winner]), "Game finished");
+                let turns = shared_table.lock().unwrap().turns_taken();
+                self.metrics.game_completed(turns);
+                match winner {
+                    Some(winner) => Some(GameOutcome::Won(users[winner])),
+                    None => Some(GameOutcome::Draw),
+                }
+            }
+            Ok(Ok(Err(player_id))) => {
+                warn!(%player_id, "Game aborted, player disconnected");
+                self.metrics.game_aborted(AbortReason::Disconnect);
+                Some(GameOutcome::Aborted(format!("{player_id} disconnected")))
+            }
+            Ok(Err(payload)) => {
+                let dump = CrashDump {
+                    game_id,
+                    seed,
+                    players: users.to_vec(),
+                    message: crash::panic_message(&*payload),
+                    backtrace: crash::take_backtrace(),
+                    events: events.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+                };
+                match dump.write(&self.crash_dump_dir) {
+                    Ok(path) => error!(?path, "Game crashed, dump written"),
+                    Err(e) => error!("Game crashed, couldn't write dump: {e}"),
+                }
+
+                let message = AbortReason::Crash.message().to_string();
+                notify_table(
+                    &table,
+                    &server_messages::ServerNotification::GameAborted(message.clone()),
+                )
+                .await;
+                self.metrics.game_aborted(AbortReason::Crash);
+                Some(GameOutcome::Aborted(message))
+            }
+        };
+        // removed before recording so the periodic checkpoint can't save the finished game again
+        self.games.remove(game_id);
+        if let Some(outcome) = outcome {
+            self.storage.record_game(GameRecord {
+                id: game_id,
+                players: users.to_vec(),
+                seed,
+                outcome,
+                started_at: unix_time(started_at),
+                finished_at: unix_time(SystemTime::now()),
+            });
+        }
+        drop(table);
+        drop(guard);
+        true
+    }
+
+    /// Asks every user at a finished table what they want to do next.
+    ///
+    /// Returns the table if everyone asked for a rematch, otherwise the users that asked to
+    /// requeue or for a rematch are added back to the queue.
+    #[instrument(skip(self))]
+    async fn post_game(&self, users: Vec<Uuid>) -> Option<Vec<Uuid>> {
+        let table_size = users.len();
+        let choices = futures::future::join_all(users.into_iter().map(|id| async move {
+            let choice = match self.registry.user(id) {
+                Some(user) => {
+                    post_game_choice(&mut *user.lock().await, self.post_game_deadline).await
+                }
+                None => None,
+            };
+            (id, choice)
+        }))
+        .await;
+
+        let mut requeue = vec![];
+        let mut rematch = vec![];
+        for (id, choice) in choices {
+            match choice {
+                Some(LobbyAction::Requeue) => requeue.push(id),
+                Some(LobbyAction::Rematch) => rematch.push(id),
+                Some(LobbyAction::Leave) => {
+                    info!(user_id = %id, "User left");
+                    if let Some(user) = self.registry.user(id) {
+                        let _ = user.lock().await.sender.lock().await.close().await;
+                    }
+                    let _ = self.registry.disconnect(id);
+                }
+                Some(LobbyAction::Ready) | None => {
+                    info!(user_id = %id, "User didn't choose to play again");
+                    let _ = self.registry.disconnect(id);
+                }
+            }
+        }
+
+        if rematch.len() == table_size {
+            info!("Table asked for a rematch");
+            return Some(rematch);
+        }
+
+        if requeue.is_empty() && rematch.is_empty() {
+            return None;
+        }
+        self.enqueue(requeue.into_iter().chain(rematch)).await;
+        self.start_games().await;
+        None
     }
 }
 
-async fn db_add_winner(user: &User) {
-    compute_elo();
-    todo!("compute elo & notify db of win");
+async fn notify_table(table: &[Arc<Mutex<User>>], msg: &server_messages::ServerNotification) {
+    let msg = serde_json::to_string(msg).unwrap();
+    for user in table {
+        let _ = user.lock().await.send(&msg).await;
+    }
+}
+
+/// Asks `user` to acknowledge they are ready to play within `deadline`
+async fn ready_check(user: &mut User, deadline: Duration) -> bool {
+    let msg = server_messages::ServerNotification::ReadyCheck {
+        deadline_secs: deadline.as_secs(),
+    };
+    if user
+        .send(&serde_json::to_string(&msg).unwrap())
+        .await
+        .is_err()
+    {
+        return false;
+    }
+
+    matches!(
+        next_lobby_action(user, deadline).await,
+        Some(LobbyAction::Ready)
+    )
+}
+
+/// Asks `user` whether they want to requeue, rematch or leave after a game
+async fn post_game_choice(user: &mut User, deadline: Duration) -> Option<LobbyAction> {
+    let msg = server_messages::ServerNotification::PostGame {
+        deadline_secs: deadline.as_secs(),
+    };
+    user.send(&serde_json::to_string(&msg).unwrap())
+        .await
+        .ok()?;
+
+    next_lobby_action(user, deadline).await
 }
 
-fn compute_elo() {
-    todo!("http://sradack.blogspot.com/2008/06/elo-rating-system-multiple-players.html");
+/// Waits up to `deadline` for `user` to send a [`LobbyAction`], ignoring any other messages
+async fn next_lobby_action(user: &mut User, deadline: Duration) -> Option<LobbyAction> {
+    let action = async {
+        while let Some(Ok(message)) = user.receiver.next().await {
+            let Message::Text(message) = message else {
+                continue;
+            };
+            if let Ok(action) = serde_json::from_str(&message) {
+                return Some(action);
+            }
+        }
+        None
+    };
+
+    tokio::time::timeout(deadline, action).await.ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, answer, connect};
+    use skitgubbe_game::api::player_messages::action::{PlayAction, SetupAction};
+    use skitgubbe_game::bot::reference::LowestCard;
+    use skitgubbe_game::bot::runner::{self, RunnerConfig};
+    use skitgubbe_game::bot::{Strategy, View};
+    use skitgubbe_game::deck::{Card, Suit};
+
+    /// Plays like [`LowestCard`] after first trying a card nobody has
+    #[derive(Default)]
+    struct Fumbles {
+        fumbled: bool,
+    }
+
+    impl Strategy for Fumbles {
+        fn on_setup(&mut self, view: &View) -> SetupAction {
+            LowestCard.on_setup(view)
+        }
+
+        fn on_turn(&mut self, view: &View) -> PlayAction {
+            if std::mem::replace(&mut self.fumbled, true) {
+                return LowestCard.on_turn(view);
+            }
+            let card = Card {
+                rank: 15,
+                suit: Suit::Spade,
+            };
+            PlayAction::PlaceCard { card }
+        }
+    }
+
+    fn server_queue(table_size: usize) -> ServerQueue {
+        let mut config = Config::default();
+        config.queue.table_size = table_size;
+        config.seed = Some(0);
+        let path = std::env::temp_dir().join(format!("skitgubbe-{}.json", Uuid::new_v4()));
+        let storage = Storage::open(path).unwrap();
+        let mut queue = ServerQueue::new(
+            Registry::new(),
+            Games::new(),
+            storage,
+            Metrics::new(),
+            &config,
+        );
+        queue.ready_check_deadline = Duration::from_millis(200);
+        queue.post_game_deadline = Duration::from_millis(200);
+        queue
+    }
+
+    #[tokio::test]
+    async fn test_failed_ready_check_is_replaced_from_queue() {
+        let server_queue = server_queue(2);
+        let (silent, _silent_client) = connect().await;
+        let (ready, ready_client) = connect().await;
+        let (replacement, replacement_client) = connect().await;
+        let ids = [silent.id, ready.id, replacement.id];
+        let mut ready_client = answer(ready_client, testing::ready);
+        let mut replacement_client = answer(replacement_client, testing::ready);
+
+        // the first two make a table, the third waits in the queue
+        for user in [silent, ready, replacement] {
+            server_queue.push_user(user).await;
+        }
+        // players are sent a resume token once their game has started
+        for client in [&mut ready_client, &mut replacement_client] {
+            client
+                .wait_for(|notification| {
+                    matches!(
+                        notification,
+                        server_messages::ServerNotification::ResumeToken { .. }
+                    )
+                })
+                .await;
+        }
+
+        let registry = &server_queue.registry;
+        assert_eq!(registry.state(ids[0]), None);
+        assert!(matches!(registry.state(ids[1]), Some(UserState::InGame(_))));
+        assert_eq!(registry.state(ids[1]), registry.state(ids[2]));
+        assert_eq!(server_queue.status().await.queued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_second_connection_of_a_player_is_turned_away() {
+        let server_queue = server_queue(4);
+        let (first, _first_client) = connect().await;
+        let (mut second, mut second_client) = connect().await;
+        let id = first.id;
+        second.id = id;
+        server_queue.push_user(first).await;
+        server_queue.push_user(second).await;
+
+        assert!(matches!(
+            testing::next_notification(&mut second_client).await,
+            server_messages::ServerNotification::SignInFailed(_)
+        ));
+        assert_eq!(server_queue.registry.state(id), Some(UserState::Queued));
+        assert!(server_queue.registry.is_connected(id));
+    }
+
+    #[tokio::test]
+    async fn test_post_game_choices() {
+        let server_queue = server_queue(4);
+        let mut ids = vec![];
+        for choice in [
+            LobbyAction::Rematch,
+            LobbyAction::Requeue,
+            LobbyAction::Leave,
+            LobbyAction::Ready,
+        ] {
+            let (user, client) = connect().await;
+            ids.push(server_queue.registry.register(user).ok().unwrap());
+            let choice = serde_json::to_string(&choice).unwrap();
+            answer(client, move |notification| {
+                let post_game = matches!(
+                    notification,
+                    server_messages::ServerNotification::PostGame { .. }
+                );
+                post_game.then(|| serde_json::from_str(&choice).unwrap())
+            });
+        }
+
+        // not everyone asked for a rematch, so it is requeued with the one who asked to requeue
+        assert!(server_queue.post_game(ids.clone()).await.is_none());
+        let registry = &server_queue.registry;
+        assert_eq!(registry.state(ids[0]), Some(UserState::Queued));
+        assert_eq!(registry.state(ids[1]), Some(UserState::Queued));
+        assert_eq!(registry.state(ids[2]), None);
+        // ready isn't an answer to what to do next
+        assert_eq!(registry.state(ids[3]), None);
+    }
+
+    #[tokio::test]
+    async fn test_requeued_players_are_matched_again() {
+        let server_queue = server_queue(2);
+        let mut ids = vec![];
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let (user, client) = connect().await;
+            ids.push(server_queue.registry.register(user).ok().unwrap());
+            clients.push(answer(client, |notification| {
+                let post_game = matches!(
+                    notification,
+                    server_messages::ServerNotification::PostGame { .. }
+                );
+                post_game.then_some(LobbyAction::Requeue)
+            }));
+        }
+
+        assert!(server_queue.post_game(ids).await.is_none());
+        // nobody else joins, the two of them make the next table
+        for client in &mut clients {
+            client
+                .wait_for(|notification| {
+                    matches!(
+                        notification,
+                        server_messages::ServerNotification::ReadyCheck { .. }
+                    )
+                })
+                .await;
+        }
+        assert_eq!(server_queue.status().await.queued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_rematch_keeps_the_table() {
+        let server_queue = server_queue(2);
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let (user, client) = connect().await;
+            ids.push(server_queue.registry.register(user).ok().unwrap());
+            answer(client, |notification| {
+                let post_game = matches!(
+                    notification,
+                    server_messages::ServerNotification::PostGame { .. }
+                );
+                post_game.then_some(LobbyAction::Rematch)
+            });
+        }
+        assert_eq!(server_queue.post_game(ids.clone()).await, Some(ids));
+    }
+
+    #[tokio::test]
+    async fn test_won_game_asks_players_what_next() {
+        let server_queue = server_queue(2);
+        let url = testing::serve(server_queue.clone()).await;
+        let config = RunnerConfig {
+            url,
+            games: Some(1),
+            ..RunnerConfig::default()
+        };
+        let (mut a, mut b) = (LowestCard, LowestCard);
+        let (a, b) = tokio::join!(runner::run(&mut a, &config), runner::run(&mut b, &config));
+
+        // the runners only stop once they have been asked what to do after the game
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!((a.games, b.games), (1, 1));
+        assert_eq!(a.wins + b.wins, 1);
+        let (_, games) = server_queue.storage.games(0, 10);
+        assert!(matches!(
+            games[..],
+            [GameRecord {
+                outcome: GameOutcome::Won(_),
+                ..
+            }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_accepted_play_actions() {
+        let server_queue = server_queue(2);
+        let url = testing::serve(server_queue.clone()).await;
+        let config = RunnerConfig {
+            url,
+            games: Some(1),
+            ..RunnerConfig::default()
+        };
+        let (mut a, mut b) = (Fumbles::default(), LowestCard);
+        let (ra, rb) = tokio::join!(runner::run(&mut a, &config), runner::run(&mut b, &config));
+        assert_eq!((ra.unwrap().games, rb.unwrap().games), (1, 1));
+        assert!(a.fumbled);
+
+        // neither the setup actions nor the rejected card are counted
+        let (turns, moves) = server_queue.metrics.play_counts();
+        assert!(turns > 0.0);
+        assert_eq!(turns, moves as f64);
+    }
 }
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use tokio::sync::{watch, Notify};
+use uuid::Uuid;
+
+use skitgubbe_game::api::server_messages::SpectatorView;
+use skitgubbe_game::game::{EventLog, SharedTable, Spectators};
+
+use crate::storage::{unix_time, Checkpoint, Storage};
+
+/// Why a game stopped before it was finished
+#[derive(Clone, Copy, Debug)]
+pub enum AbortReason {
+    Disconnect,
+    Crash,
+    Admin,
+    Shutdown,
+}
+
+impl AbortReason {
+    /// Used as the `reason` label of aborted games in metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            AbortReason::Disconnect => "disconnect",
+            AbortReason::Crash => "crash",
+            AbortReason::Admin => "admin",
+            AbortReason::Shutdown => "shutdown",
+        }
+    }
+
+    /// Sent to the players of the game
+    pub fn message(self) -> &'static str {
+        match self {
+            AbortReason::Disconnect => "A player disconnected",
+            AbortReason::Crash => "The game crashed",
+            AbortReason::Admin => "The game was aborted by an admin",
+            AbortReason::Shutdown => {
+                "The server is restarting, reconnect with your resume token to carry on"
+            }
+        }
+    }
+}
+
+/// A game that is currently being played
+pub struct ActiveGame {
+    /// Player ids in seat order
+    pub players: Vec<Uuid>,
+    pub started_at: SystemTime,
+    pub spectators: Spectators,
+    /// Set to stop the game early
+    pub abort: watch::Sender<Option<AbortReason>>,
+    /// Secrets the players can resume the game with after a restart, in seat order
+    pub resume_tokens: Vec<Uuid>,
+    pub table: SharedTable,
+    pub events: EventLog,
+}
+
+#[derive(Serialize)]
+pub struct GameSummary {
+    pub id: Uuid,
+    pub players: Vec<Uuid>,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+}
+
+#[derive(Serialize)]
+pub struct GameDetails {
+    #[serde(flatten)]
+    pub summary: GameSummary,
+    /// What a spectator would currently see, `None` before the game has started
+    pub view: Option<SpectatorView>,
+}
+
+impl ActiveGame {
+    fn summary(&self, id: Uuid) -> GameSummary {
+        GameSummary {
+            id,
+            players: self.players.clone(),
+            started_at: unix_time(self.started_at),
+        }
+    }
+
+    fn checkpoint(&self, id: Uuid) -> Checkpoint {
+        Checkpoint {
+            game_id: id,
+            players: self.players.clone(),
+            resume_tokens: self.resume_tokens.clone(),
+            started_at: unix_time(self.started_at),
+            table: self.table.lock().unwrap().clone(),
+            events: self.events.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Every game currently being played, by game id
+#[derive(Clone, Default)]
+pub struct Games {
+    games: Arc<Mutex<HashMap<Uuid, ActiveGame>>>,
+    /// Notified whenever the last active game is removed
+    emptied: Arc<Notify>,
+}
+
+impl Games {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, game_id: Uuid, game: ActiveGame) {
+        self.games.lock().unwrap().insert(game_id, game);
+    }
+
+    pub fn remove(&self, game_id: Uuid) {
+        let mut games = self.games.lock().unwrap();
+        games.remove(&game_id);
+        if games.is_empty() {
+            self.emptied.notify_waiters();
+        }
+    }
+
+    /// Waits until there are no active games
+    pub async fn wait_until_empty(&self) {
+        loop {
+            let emptied = self.emptied.notified();
+            if self.games.lock().unwrap().is_empty() {
+                return;
+            }
+            emptied.await;
+        }
+    }
+
+    /// All active games, oldest first
+    pub fn list(&self) -> Vec<GameSummary> {
+        let games = self.games.lock().unwrap();
+        let mut summaries: Vec<_> = games.iter().map(|(&id, game)| game.summary(id)).collect();
+        summaries.sort_by_key(|game| game.started_at);
+        summaries
+    }
+
+    pub fn details(&self, game_id: Uuid) -> Option<GameDetails> {
+        let games = self.games.lock().unwrap();
+        let game = games.get(&game_id)?;
+        Some(GameDetails {
+            summary: game.summary(game_id),
+            view: game.spectators.latest().map(|update| update.public),
+        })
+    }
+
+    /// Stops a game early, returns false if there is no such game
+    pub fn abort(&self, game_id: Uuid, reason: AbortReason) -> bool {
+        let games = self.games.lock().unwrap();
+        let Some(game) = games.get(&game_id) else {
+            return false;
+        };
+        game.abort.send_replace(Some(reason));
+        true
+    }
+
+    /// Stops every active game early
+    pub fn abort_all(&self, reason: AbortReason) {
+        for game in self.games.lock().unwrap().values() {
+            game.abort.send_replace(Some(reason));
+        }
+    }
+
+    /// Saves a checkpoint of the game, returns false if there is no such game
+    pub fn save_checkpoint(&self, game_id: Uuid, storage: &Storage) -> bool {
+        let games = self.games.lock().unwrap();
+        let Some(game) = games.get(&game_id) else {
+            return false;
+        };
+        storage.save_checkpoint(game.checkpoint(game_id));
+        true
+    }
+
+    /// Saves a checkpoint of every active game.
+    ///
+    /// Saved while holding the lock so a game that finishes meanwhile can't be resurrected by a
+    /// stale checkpoint.
+    pub fn save_checkpoints(&self, storage: &Storage) {
+        for (&id, game) in self.games.lock().unwrap().iter() {
+            storage.save_checkpoint(game.checkpoint(id));
+        }
+    }
+
+    pub fn spectators(&self, game_id: Uuid) -> Option<Spectators> {
+        let games = self.games.lock().unwrap();
+        games.get(&game_id).map(|game| game.spectators.clone())
+    }
+}
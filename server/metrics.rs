@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use skitgubbe_game::api::server_messages::Stage;
+
+use crate::games::AbortReason;
+use crate::AppState;
+
+/// Prometheus metrics describing the health of the server
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    queue_length: IntGauge,
+    active_games: IntGauge,
+    connected_users: IntGauge,
+    games_completed: IntCounter,
+    games_aborted: IntCounterVec,
+    /// Turns of the play stage, not counting setup or rejected actions. The average is
+    /// `skitgubbe_game_turns_sum / skitgubbe_game_turns_count`
+    game_turns: Histogram,
+    move_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let queue_length =
+            IntGauge::new("skitgubbe_queue_length", "Users waiting in the queue").unwrap();
+        let active_games =
+            IntGauge::new("skitgubbe_active_games", "Games currently being played").unwrap();
+        let connected_users =
+            IntGauge::new("skitgubbe_connected_users", "Users with an open connection").unwrap();
+        let games_completed = IntCounter::new(
+            "skitgubbe_games_completed_total",
+            "Games that finished with a winner or a draw",
+        )
+        .unwrap();
+        let games_aborted = IntCounterVec::new(
+            Opts::new(
+                "skitgubbe_games_aborted_total",
+                "Games that stopped before they were finished",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let game_turns = Histogram::with_opts(
+            HistogramOpts::new(
+                "skitgubbe_game_turns",
+                "Play stage turns taken in each completed game",
+            )
+            .buckets(vec![10.0, 25.0, 50.0, 75.0, 100.0, 150.0, 200.0, 300.0]),
+        )
+        .unwrap();
+        let move_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "skitgubbe_move_latency_seconds",
+                "Time between a player being asked for an action and sending one that is accepted",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+            ]),
+            &["stage"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(queue_length.clone())).unwrap();
+        registry.register(Box::new(active_games.clone())).unwrap();
+        registry
+            .register(Box::new(connected_users.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(games_completed.clone()))
+            .unwrap();
+        registry.register(Box::new(games_aborted.clone())).unwrap();
+        registry.register(Box::new(game_turns.clone())).unwrap();
+        registry.register(Box::new(move_latency.clone())).unwrap();
+
+        Self {
+            registry,
+            queue_length,
+            active_games,
+            connected_users,
+            games_completed,
+            games_aborted,
+            game_turns,
+            move_latency,
+        }
+    }
+
+    /// `turns` counts the turns of the play stage
+    pub fn game_completed(&self, turns: usize) {
+        self.games_completed.inc();
+        self.game_turns.observe(turns as f64);
+    }
+
+    pub fn game_aborted(&self, reason: AbortReason) {
+        self.games_aborted
+            .with_label_values(&[reason.label()])
+            .inc();
+    }
+
+    pub fn observe_move(&self, stage: Stage, latency: Duration) {
+        let stage = match stage {
+            Stage::Swap => "swap",
+            Stage::Play => "play",
+        };
+        self.move_latency
+            .with_label_values(&[stage])
+            .observe(latency.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+impl Metrics {
+    /// The total of every game's turns and the number of play stage moves timed
+    pub fn play_counts(&self) -> (f64, u64) {
+        let moves = self.move_latency.with_label_values(&["play"]);
+        (self.game_turns.get_sample_sum(), moves.get_sample_count())
+    }
+}
+
+/// Renders every metric in the Prometheus text format. Gauges are sampled on each scrape.
+pub async fn handler(State(state): State<AppState>) -> Response {
+    let metrics = &state.metrics;
+    metrics
+        .queue_length
+        .set(state.queue.status().await.queued as i64);
+    metrics.active_games.set(state.games.list().len() as i64);
+    metrics.connected_users.set(
+        state
+            .registry
+            .list()
+            .iter()
+            .filter(|connection| connection.connected)
+            .count() as i64,
+    );
+
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&metrics.registry.gather(), &mut buffer) {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+        .into_response()
+}
@@ -0,0 +1,427 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex as SyncMutex},
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use futures::{lock::Mutex, stream::SplitSink, SinkExt};
+use serde::Serialize;
+use tracing::info;
+use uuid::Uuid;
+
+use skitgubbe_game::user::{Connection, User};
+
+/// Where a connection currently is. A user is only ever in one of these states, a user that
+/// disconnects is forgotten.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum UserState {
+    /// Connected but not waiting for or playing a game
+    Connected,
+    Queued,
+    InGame(Uuid),
+}
+
+impl UserState {
+    /// Whether a user is allowed to move from `self` to `to`
+    fn can_transition(self, to: UserState) -> bool {
+        use UserState::*;
+        matches!(
+            (self, to),
+            (Connected, Queued)
+                | (Connected, InGame(_))
+                | (Queued, Connected)
+                | (Queued, InGame(_))
+                | (InGame(_), Connected)
+        )
+    }
+
+    /// Whether a user can be forgotten. Users in a game are only forgotten once it ends, as the
+    /// game still needs their seat.
+    fn can_disconnect(self) -> bool {
+        !matches!(self, UserState::InGame(_))
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidTransition {
+    pub user: Uuid,
+    pub from: Option<UserState>,
+    /// `None` when the user was being disconnected
+    pub to: Option<UserState>,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) => {
+                write!(f, "User: {} can't move from {from:?} to {to:?}", self.user)
+            }
+            (Some(from), None) => {
+                write!(f, "User: {} can't disconnect while {from:?}", self.user)
+            }
+            (None, _) => write!(f, "User: {} is not registered", self.user),
+        }
+    }
+}
+
+/// A connection with the same id is already registered, the new one is handed back
+pub struct AlreadyRegistered(pub User);
+
+impl fmt::Display for AlreadyRegistered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "User: {} is already connected", self.0.id)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConnectionInfo {
+    pub id: Uuid,
+    pub state: UserState,
+    pub connected: bool,
+}
+
+struct Entry {
+    user: Arc<Mutex<User>>,
+    /// Kept separately so users can be messaged while a game holds their lock
+    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    connection: Connection,
+    state: UserState,
+}
+
+/// Owns every connection on the server and tracks which state it is in.
+///
+/// The queue and games only hold user ids or short lived handles, the registry is responsible
+/// for dropping the user (closing the socket) once it disconnects.
+#[derive(Clone, Default)]
+pub struct Registry {
+    entries: Arc<SyncMutex<HashMap<Uuid, Entry>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a new connection in the [`UserState::Connected`] state
+    ///
+    /// # Errors
+    /// If a user with the same id is registered, which is left as it is
+    pub fn register(&self, user: User) -> Result<Uuid, AlreadyRegistered> {
+        let id = user.id;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(&id) {
+            return Err(AlreadyRegistered(user));
+        }
+        let entry = Entry {
+            connection: user.connection(),
+            sender: Arc::clone(&user.sender),
+            user: Arc::new(Mutex::new(user)),
+            state: UserState::Connected,
+        };
+        entries.insert(id, entry);
+        Ok(id)
+    }
+
+    pub fn user(&self, id: Uuid) -> Option<Arc<Mutex<User>>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(&id).map(|entry| Arc::clone(&entry.user))
+    }
+
+    pub fn state(&self, id: Uuid) -> Option<UserState> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(&id).map(|entry| entry.state)
+    }
+
+    /// Whether the user is registered and its socket is still alive
+    pub fn is_connected(&self, id: Uuid) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&id)
+            .is_some_and(|entry| entry.connection.is_connected())
+    }
+
+    /// Moves the user to the state `to`
+    ///
+    /// # Errors
+    /// If the user isn't registered or can't move from its current state to `to`
+    pub fn transition(&self, id: Uuid, to: UserState) -> Result<(), InvalidTransition> {
+        let mut entries = self.entries.lock().unwrap();
+        Self::transition_entry(&mut entries, id, to)
+    }
+
+    fn transition_entry(
+        entries: &mut HashMap<Uuid, Entry>,
+        id: Uuid,
+        to: UserState,
+    ) -> Result<(), InvalidTransition> {
+        let Some(entry) = entries.get_mut(&id) else {
+            return Err(InvalidTransition {
+                user: id,
+                from: None,
+                to: Some(to),
+            });
+        };
+        if !entry.state.can_transition(to) {
+            return Err(InvalidTransition {
+                user: id,
+                from: Some(entry.state),
+                to: Some(to),
+            });
+        }
+        entry.state = to;
+        Ok(())
+    }
+
+    /// Forgets the user, which closes its socket once nothing else holds it
+    ///
+    /// # Errors
+    /// If the user isn't registered or is in a game, see [`Registry::kick`] for those
+    pub fn disconnect(&self, id: Uuid) -> Result<(), InvalidTransition> {
+        let mut entries = self.entries.lock().unwrap();
+        Self::disconnect_entry(&mut entries, id)
+    }
+
+    fn disconnect_entry(
+        entries: &mut HashMap<Uuid, Entry>,
+        id: Uuid,
+    ) -> Result<(), InvalidTransition> {
+        let from = entries.get(&id).map(|entry| entry.state);
+        if !from.is_some_and(UserState::can_disconnect) {
+            return Err(InvalidTransition {
+                user: id,
+                from,
+                to: None,
+            });
+        }
+        entries.remove(&id);
+        info!(user_id = %id, "User disconnected");
+        Ok(())
+    }
+
+    /// Forcibly closes a user's connection. Users in a game are forgotten once the game notices
+    /// and finishes, otherwise they are forgotten immediately.
+    ///
+    /// Returns false if the user isn't registered
+    pub fn kick(&self, id: Uuid) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(&id) else {
+            return false;
+        };
+        entry.connection.close();
+        info!(user_id = %id, "User kicked");
+        let _ = Self::disconnect_entry(&mut entries, id);
+        true
+    }
+
+    /// Closes every connection, see [`Registry::kick`]
+    pub fn kick_all(&self) {
+        let ids: Vec<Uuid> = self.entries.lock().unwrap().keys().copied().collect();
+        for id in ids {
+            self.kick(id);
+        }
+    }
+
+    /// Sends `msg` to every connected user, including those in a game
+    pub async fn broadcast(&self, msg: &str) {
+        let senders: Vec<_> = {
+            let entries = self.entries.lock().unwrap();
+            entries
+                .values()
+                .filter(|entry| entry.connection.is_connected())
+                .map(|entry| Arc::clone(&entry.sender))
+                .collect()
+        };
+        for sender in senders {
+            let _ = sender
+                .lock()
+                .await
+                .send(Message::Text(msg.to_string()))
+                .await;
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .map(|(&id, entry)| ConnectionInfo {
+                id,
+                state: entry.state,
+                connected: entry.connection.is_connected(),
+            })
+            .collect()
+    }
+
+    /// Forgets every user that isn't playing and whose socket has closed
+    pub fn prune(&self) {
+        self.entries.lock().unwrap().retain(|id, entry| {
+            let keep = entry.connection.is_connected() || !entry.state.can_disconnect();
+            if !keep {
+                info!(user_id = %id, "User disconnected");
+            }
+            keep
+        });
+    }
+
+    /// Moves all `users` into the game `game_id` at once.
+    ///
+    /// The returned guard moves them back to [`UserState::Connected`] (or forgets them if they
+    /// disconnected) when it is dropped, including when the game panics.
+    ///
+    /// # Errors
+    /// If any of the users can't join a game, in which case none of them are moved
+    pub fn start_game(
+        &self,
+        game_id: Uuid,
+        users: &[Uuid],
+    ) -> Result<GameGuard, InvalidTransition> {
+        let mut entries = self.entries.lock().unwrap();
+        for &id in users {
+            let from = entries.get(&id).map(|entry| entry.state);
+            if !from.is_some_and(|from| from.can_transition(UserState::InGame(game_id))) {
+                return Err(InvalidTransition {
+                    user: id,
+                    from,
+                    to: Some(UserState::InGame(game_id)),
+                });
+            }
+        }
+
+        for &id in users {
+            Self::transition_entry(&mut entries, id, UserState::InGame(game_id))
+                .expect("Users were checked above");
+        }
+
+        Ok(GameGuard {
+            registry: self.clone(),
+            game_id,
+            users: users.to_vec(),
+        })
+    }
+}
+
+/// The users taking part in a game, returned to the lobby when dropped
+pub struct GameGuard {
+    registry: Registry,
+    game_id: Uuid,
+    users: Vec<Uuid>,
+}
+
+impl GameGuard {
+    /// Handles to the users in seat order
+    ///
+    /// # Errors
+    /// The id of a seated user that is no longer registered, as the table would be short a seat
+    pub fn users(&self) -> Result<Vec<Arc<Mutex<User>>>, Uuid> {
+        self.users
+            .iter()
+            .map(|&id| self.registry.user(id).ok_or(id))
+            .collect()
+    }
+}
+
+impl Drop for GameGuard {
+    fn drop(&mut self) {
+        let mut entries = self.registry.entries.lock().unwrap();
+        for &id in &self.users {
+            let Some(entry) = entries.get(&id) else {
+                continue;
+            };
+            if entry.state != UserState::InGame(self.game_id) {
+                continue;
+            }
+
+            let connected = entry.connection.is_connected();
+            let _ = Registry::transition_entry(&mut entries, id, UserState::Connected);
+            if !connected {
+                let _ = Registry::disconnect_entry(&mut entries, id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect;
+
+    #[test]
+    fn test_transitions() {
+        use UserState::*;
+        let game = InGame(Uuid::new_v4());
+        assert!(Connected.can_transition(Queued));
+        assert!(Queued.can_transition(game));
+        assert!(game.can_transition(Connected));
+        // a game always ends back in the lobby, and its players stay until then
+        assert!(!game.can_transition(Queued));
+        assert!(!game.can_transition(InGame(Uuid::new_v4())));
+        assert!(!Queued.can_transition(Queued));
+        assert!(Queued.can_disconnect());
+        assert!(!game.can_disconnect());
+    }
+
+    #[tokio::test]
+    async fn test_register_refuses_an_id_already_connected() {
+        let registry = Registry::new();
+        let (first, _first) = connect().await;
+        let (mut second, _second) = connect().await;
+        second.id = first.id;
+        let id = registry.register(first).ok().unwrap();
+        registry.transition(id, UserState::Queued).unwrap();
+
+        let Err(AlreadyRegistered(second)) = registry.register(second) else {
+            panic!("The second connection should be refused");
+        };
+        assert_eq!(second.id, id);
+        assert_eq!(registry.state(id), Some(UserState::Queued));
+        assert!(registry.is_connected(id));
+
+        // once the first has gone the id is free again
+        registry.disconnect(id).unwrap();
+        assert!(registry.register(second).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_start_game_moves_everyone_or_no_one() {
+        let registry = Registry::new();
+        let (a, _a) = connect().await;
+        let (b, _b) = connect().await;
+        let (a, b) = (
+            registry.register(a).ok().unwrap(),
+            registry.register(b).ok().unwrap(),
+        );
+        let game_id = Uuid::new_v4();
+
+        let in_game = registry.start_game(Uuid::new_v4(), &[a]).unwrap();
+        // `a` is already playing, so `b` isn't moved either
+        assert!(registry.start_game(game_id, &[b, a]).is_err());
+        assert_eq!(registry.state(b), Some(UserState::Connected));
+        drop(in_game);
+        assert_eq!(registry.state(a), Some(UserState::Connected));
+
+        let guard = registry.start_game(game_id, &[a, b]).unwrap();
+        assert_eq!(registry.state(a), Some(UserState::InGame(game_id)));
+        assert_eq!(guard.users().unwrap().len(), 2);
+
+        // a user kicked during the game is forgotten once it ends
+        registry.kick(b);
+        drop(guard);
+        assert_eq!(registry.state(a), Some(UserState::Connected));
+        assert_eq!(registry.state(b), None);
+    }
+
+    #[tokio::test]
+    async fn test_players_in_a_game_cant_be_disconnected() {
+        let registry = Registry::new();
+        let (a, _a) = connect().await;
+        let a = registry.register(a).ok().unwrap();
+        let guard = registry.start_game(Uuid::new_v4(), &[a]).unwrap();
+        assert!(registry.disconnect(a).is_err());
+        assert_eq!(guard.users().unwrap().len(), 1);
+
+        drop(guard);
+        assert!(registry.disconnect(a).is_ok());
+        assert_eq!(registry.state(a), None);
+    }
+}
@@ -0,0 +1,104 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::registry::UserState;
+use crate::storage::{GameRecord, PlayerStats};
+use crate::AppState;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// JSON endpoints for dashboards and scripts
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/games", get(list_games))
+        .route("/games/:game_id", get(game))
+        .route("/history", get(history))
+        .route("/players/:player_id", get(player))
+        .route("/leaderboard", get(leaderboard))
+}
+
+#[derive(Deserialize)]
+struct Page {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+impl Page {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+}
+
+async fn list_games(State(state): State<AppState>) -> Response {
+    Json(state.games.list()).into_response()
+}
+
+/// An active game if it is still being played, otherwise its record
+async fn game(Path(game_id): Path<Uuid>, State(state): State<AppState>) -> Response {
+    if let Some(details) = state.games.details(game_id) {
+        return Json(details).into_response();
+    }
+    match state.storage.game(game_id) {
+        Some(record) => Json(record).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct History {
+    total: usize,
+    offset: usize,
+    games: Vec<GameRecord>,
+}
+
+/// Finished games, newest first
+async fn history(Query(page): Query<Page>, State(state): State<AppState>) -> Response {
+    let offset = page.offset.unwrap_or(0);
+    let (total, games) = state.storage.games(offset, page.limit());
+    Json(History {
+        total,
+        offset,
+        games,
+    })
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct PlayerProfile {
+    id: Uuid,
+    /// Where the player currently is, `None` if they aren't connected
+    state: Option<UserState>,
+    stats: Option<PlayerStats>,
+}
+
+/// Looks players up by id, or by the name they signed in with
+async fn player(Path(player): Path<String>, State(state): State<AppState>) -> Response {
+    let Some(player_id) = player
+        .parse()
+        .ok()
+        .or_else(|| state.storage.player_id(&player))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let profile = PlayerProfile {
+        id: player_id,
+        state: state.registry.state(player_id),
+        stats: state.storage.player(player_id),
+    };
+    if profile.state.is_none() && profile.stats.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(profile).into_response()
+}
+
+async fn leaderboard(Query(page): Query<Page>, State(state): State<AppState>) -> Response {
+    Json(state.storage.leaderboard(page.limit())).into_response()
+}
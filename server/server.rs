@@ -1,80 +1,261 @@
 #![feature(async_closure)]
 
+mod admin;
+mod config;
+mod crash;
+mod elo;
 mod game_manager;
+mod games;
+mod metrics;
+mod registry;
+mod rest;
+mod spectate;
+mod storage;
+#[cfg(test)]
+mod testing;
 
 use axum::{
-    extract::{
-        ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
-    },
-    http::Method,
+    extract::{ws::WebSocket, Query, State, WebSocketUpgrade},
+    http::{HeaderValue, Method},
     response::IntoResponse,
     routing::get,
     Router,
 };
-use futures::lock::Mutex;
-use futures_util::SinkExt;
-use skitgubbe_game::user::User;
-use std::sync::Arc;
+use clap::Parser;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use skitgubbe_game::user::{Heartbeat, User};
+use std::{sync::Arc, time::Duration};
 use tokio::{self, net::TcpListener};
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
 
+use crate::config::{Args, Config, LogConfig, LogFormat};
 use crate::game_manager::ServerQueue;
+use crate::games::Games;
+use crate::metrics::Metrics;
+use crate::registry::Registry;
+use crate::storage::Storage;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub queue: ServerQueue,
+    pub registry: Registry,
+    pub games: Games,
+    pub storage: Storage,
+    pub metrics: Metrics,
+    /// Token required by admin only endpoints, if unset they are disabled
+    pub admin_token: Option<Arc<str>>,
+    pub god_view_delay: Duration,
+    pub heartbeat: Heartbeat,
+}
+
+impl AppState {
+    pub fn is_admin(&self, token: &str) -> bool {
+        // compares digests, so how long it takes says nothing about how much of the token matched
+        self.admin_token.as_deref().is_some_and(|admin_token| {
+            let (expected, given) = (Sha256::digest(admin_token), Sha256::digest(token));
+            expected
+                .iter()
+                .zip(given.iter())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        })
+    }
+}
+
+/// Logs to stdout in the configured format, filtered by the configured level
+fn init_tracing(config: &LogConfig) {
+    let filter = EnvFilter::new(&config.level);
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Pretty => subscriber.pretty().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let port;
-    {
-        let port_env = std::env::var("PORT");
-        if port_env.is_ok() {
-            port = port_env
-                .unwrap()
-                .parse::<u16>()
-                .expect("PORT env var is not a number");
-        } else {
-            // default
-            eprintln!("No PORT env var set defaulting to port 0");
-            port = 0;
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
         }
-    }
+    };
+    init_tracing(&config.log);
 
-    let address = std::net::SocketAddr::from(([0, 0, 0, 0], port));
-    let server = TcpListener::bind(address)
+    let server = TcpListener::bind(config.bind)
         .await
         .expect("Couldn't bind to address");
-    println!("Listening on: {}", server.local_addr().unwrap());
+    info!("Listening on: {}", server.local_addr().unwrap());
+
+    let admin_token = config.admin_token.as_deref().map(Arc::from);
+    if admin_token.is_none() {
+        warn!("No admin token set, admin features are disabled");
+    }
+
+    let storage = Storage::open(&config.storage.path).expect("Couldn't open storage");
+    tokio::spawn(flush_storage(
+        storage.clone(),
+        Duration::from_secs(config.storage.flush_interval_secs),
+    ));
 
-    let queue_state = Arc::new(Mutex::new(ServerQueue::new()));
+    crash::install_panic_hook();
+    let registry = Registry::new();
+    let games = Games::new();
+    let metrics = Metrics::new();
+    let state = AppState {
+        queue: ServerQueue::new(
+            registry.clone(),
+            games.clone(),
+            storage.clone(),
+            metrics.clone(),
+            &config,
+        ),
+        registry,
+        games,
+        storage,
+        metrics,
+        admin_token,
+        god_view_delay: config.timeouts.god_view_delay(),
+        heartbeat: config.timeouts.heartbeat(),
+    };
+    state.queue.restore_checkpoints();
+    tokio::spawn(checkpoint_games(
+        state.games.clone(),
+        state.storage.clone(),
+        Duration::from_secs(config.storage.checkpoint_interval_secs),
+    ));
 
+    let allow_origin = if config.cors_origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .cors_origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin).expect("Validated in config")),
+        )
+    };
     let cors = CorsLayer::new()
-        .allow_origin(Any)
+        .allow_origin(allow_origin)
         .allow_methods(vec![Method::GET]);
 
     let router = Router::new()
         .route("/queue", get(handler))
-        .with_state(queue_state)
+        .route("/spectate/:game_id", get(spectate::handler))
+        .route("/metrics", get(metrics::handler))
+        .merge(rest::router())
+        .merge(admin::router(state.clone()))
+        .with_state(state.clone())
         .layer(cors);
 
-    axum::serve(server, router).await.unwrap();
+    axum::serve(server, router)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // the listener is closed at this point so no new users can connect
+    info!("Shutting down");
+    state.queue.shutdown(config.timeouts.shutdown_grace()).await;
+    if let Err(e) = state.storage.flush() {
+        error!("Couldn't flush storage: {e}");
+    }
+    info!("Shut down");
+}
+
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Couldn't listen for Ctrl-C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Periodically writes finished games to disk
+async fn flush_storage(storage: Storage, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage.flush() {
+            error!("Couldn't flush storage: {e}");
+        }
+    }
+}
+
+/// Periodically saves running games so they can be resumed after a crash
+async fn checkpoint_games(games: Games, storage: Storage, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        games.save_checkpoints(&storage);
+    }
+}
+
+#[derive(Deserialize)]
+struct QueueParams {
+    /// Token from a [`ServerNotification::ResumeToken`], rejoins a game suspended by a restart
+    resume: Option<Uuid>,
+    /// Signs in as the player called `name`, see [`Storage::sign_in`]
+    name: Option<String>,
+    token: Option<String>,
 }
 
 async fn handler(
     ws: WebSocketUpgrade,
-    State(state): State<Arc<Mutex<ServerQueue>>>,
+    Query(params): Query<QueueParams>,
+    State(state): State<AppState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, params))
 }
 
 use skitgubbe_game::api::server_messages::ServerNotification;
 
-async fn handle_socket(socket: WebSocket, state: Arc<Mutex<ServerQueue>>) {
-    let mut user = User::new(socket);
+async fn handle_socket(socket: WebSocket, state: AppState, params: QueueParams) {
+    let queue = state.queue;
+    let mut user = User::new(socket, state.heartbeat);
+    let resumed = params.resume.and_then(|token| queue.resumed_seat(token));
+    if let Some((_, player_id)) = resumed {
+        user.id = player_id;
+    } else if let Some(name) = params.name {
+        let token = params.token.unwrap_or_default();
+        match state.storage.sign_in(&name, &token) {
+            Ok(id) => user.id = id,
+            Err(e) => {
+                warn!(name, "Sign in failed: {e}");
+                let msg = ServerNotification::SignInFailed(e.to_string());
+                let _ = user.send(&serde_json::to_string(&msg).unwrap()).await;
+                return;
+            }
+        }
+    }
 
     let server_id_msg = ServerNotification::Id(user.id.to_string());
     let _ = user
-        .sender
-        .send(Message::Text(serde_json::to_string(&server_id_msg).unwrap()))
+        .send(&serde_json::to_string(&server_id_msg).unwrap())
         .await;
 
-    state.lock().await.push_user(user).await;
+    match resumed {
+        Some((game_id, _)) => queue.rejoin(user, game_id).await,
+        None => queue.push_user(user).await,
+    }
 }
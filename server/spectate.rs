@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Path, State, WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
+use uuid::Uuid;
+
+use skitgubbe_game::game::{SpectatorUpdate, Spectators};
+
+use crate::admin::bearer_token;
+use crate::AppState;
+
+/// Streams the public view of a game. With the header `Authorization: Bearer <ADMIN_TOKEN>` the
+/// full game is streamed instead, after a delay.
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    Path(game_id): Path<Uuid>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(spectators) = state.games.spectators(game_id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let god_view_delay = match bearer_token(&headers) {
+        Some(token) if state.is_admin(token) => Some(state.god_view_delay),
+        Some(_) => return StatusCode::UNAUTHORIZED.into_response(),
+        None => None,
+    };
+
+    ws.on_upgrade(move |socket| async move {
+        match god_view_delay {
+            Some(delay) => stream_god_view(socket, spectators, delay).await,
+            None => stream_public_view(socket, spectators).await,
+        }
+    })
+}
+
+/// Streams the public view of the game until it finishes or the spectator disconnects
+async fn stream_public_view(mut socket: WebSocket, spectators: Spectators) {
+    let (latest, mut receiver) = spectators.subscribe();
+    drop(spectators);
+
+    if let Some(update) = latest {
+        if send_json(&mut socket, &update.public).await.is_err() {
+            return;
+        }
+    }
+
+    while let Some(update) = next_update(&mut receiver).await {
+        if send_json(&mut socket, &update.public).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.close().await;
+}
+
+/// Streams the full game `delay` behind real time so it can't be used to help a player
+async fn stream_god_view(mut socket: WebSocket, spectators: Spectators, delay: Duration) {
+    let (latest, mut receiver) = spectators.subscribe();
+    drop(spectators);
+
+    let (delayed_sender, mut delayed) = mpsc::unbounded_channel();
+    if let Some(update) = latest {
+        let _ = delayed_sender.send((Instant::now(), update));
+    }
+    let buffer = tokio::spawn(async move {
+        while let Some(update) = next_update(&mut receiver).await {
+            if delayed_sender.send((Instant::now(), update)).is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some((received_at, update)) = delayed.recv().await {
+        tokio::time::sleep_until(received_at + delay).await;
+        if send_json(&mut socket, &update.god).await.is_err() {
+            break;
+        }
+    }
+    buffer.abort();
+    let _ = socket.close().await;
+}
+
+/// Waits for the next update, skipping any the spectator was too slow to receive.
+/// Returns `None` once the game has finished.
+async fn next_update(
+    receiver: &mut broadcast::Receiver<SpectatorUpdate>,
+) -> Option<SpectatorUpdate> {
+    loop {
+        match receiver.recv().await {
+            Ok(update) => return Some(update),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+async fn send_json(
+    socket: &mut WebSocket,
+    value: &impl serde::Serialize,
+) -> Result<(), axum::Error> {
+    socket
+        .send(Message::Text(serde_json::to_string(value).unwrap()))
+        .await
+}
@@ -0,0 +1,391 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use skitgubbe_game::api::server_messages::GameEvent;
+use skitgubbe_game::game::Table;
+
+use crate::elo;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum GameOutcome {
+    Won(Uuid),
+    Draw,
+    /// The game stopped early, eg. a player disconnected or the game crashed
+    Aborted(String),
+}
+
+/// A finished game
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameRecord {
+    pub id: Uuid,
+    /// Player ids in seat order
+    pub players: Vec<Uuid>,
+    pub seed: u64,
+    pub outcome: GameOutcome,
+    /// Unix timestamps in seconds
+    pub started_at: u64,
+    pub finished_at: u64,
+}
+
+/// Everything needed to carry on a game that was running when the server stopped
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Checkpoint {
+    pub game_id: Uuid,
+    /// Player ids in seat order
+    pub players: Vec<Uuid>,
+    /// Secrets the players reconnect with to take back their seat, in seat order
+    pub resume_tokens: Vec<Uuid>,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+    pub table: Table,
+    pub events: Vec<GameEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerStats {
+    pub id: Uuid,
+    /// The name the player signed in with, `None` for players who didn't
+    #[serde(default)]
+    pub name: Option<String>,
+    pub games_played: u32,
+    pub wins: u32,
+    pub draws: u32,
+    pub aborted: u32,
+    pub rating: f64,
+}
+
+impl PlayerStats {
+    fn new(id: Uuid) -> Self {
+        Self {
+            id,
+            name: None,
+            games_played: 0,
+            wins: 0,
+            draws: 0,
+            aborted: 0,
+            rating: elo::INITIAL_RATING,
+        }
+    }
+}
+
+/// A name claimed by a player, who proves it is theirs with the token they claimed it with
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Account {
+    id: Uuid,
+    /// Hex encoded SHA-256 of the token
+    token_hash: String,
+}
+
+/// Why a player couldn't sign in with a name
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignInError {
+    /// Names are 1 to [`MAX_NAME_LEN`] characters without control characters
+    InvalidName,
+    MissingToken,
+    /// The name was claimed with a different token
+    WrongToken,
+}
+
+impl std::fmt::Display for SignInError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignInError::InvalidName => write!(
+                f,
+                "Names must be 1 to {MAX_NAME_LEN} characters without control characters"
+            ),
+            SignInError::MissingToken => write!(f, "A name must be given with a token"),
+            SignInError::WrongToken => write!(f, "The name is taken"),
+        }
+    }
+}
+
+pub const MAX_NAME_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Data {
+    /// Oldest first
+    games: Vec<GameRecord>,
+    players: HashMap<Uuid, PlayerStats>,
+    /// Games that haven't finished yet, by game id
+    checkpoints: HashMap<Uuid, Checkpoint>,
+    /// By name
+    accounts: HashMap<String, Account>,
+}
+
+/// Finished games, player stats, the names players sign in with and checkpoints of running
+/// games, kept in memory and written to a JSON file
+#[derive(Clone)]
+pub struct Storage {
+    data: Arc<Mutex<Data>>,
+    /// Whether there are changes that haven't been flushed
+    dirty: Arc<AtomicBool>,
+    path: PathBuf,
+}
+
+impl Storage {
+    /// Loads the storage file at `path`, starting empty if it doesn't exist
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let data = match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Data::default(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            data: Arc::new(Mutex::new(data)),
+            dirty: Arc::new(AtomicBool::new(false)),
+            path,
+        })
+    }
+
+    /// Adds a finished game, updating the stats and ratings of its players. Any checkpoint of
+    /// the game is removed.
+    pub fn record_game(&self, record: GameRecord) {
+        let mut data = self.data.lock().unwrap();
+        data.checkpoints.remove(&record.id);
+        for &id in &record.players {
+            data.players
+                .entry(id)
+                .or_insert_with(|| PlayerStats::new(id));
+        }
+
+        match record.outcome {
+            GameOutcome::Aborted(_) => {
+                for id in &record.players {
+                    data.players.get_mut(id).unwrap().aborted += 1;
+                }
+            }
+            GameOutcome::Won(_) | GameOutcome::Draw => {
+                let winner = match record.outcome {
+                    GameOutcome::Won(winner) => record.players.iter().position(|&id| id == winner),
+                    _ => None,
+                };
+                let ratings: Vec<f64> = record
+                    .players
+                    .iter()
+                    .map(|id| data.players[id].rating)
+                    .collect();
+                let ratings = elo::update_ratings(&ratings, winner);
+
+                for (i, (id, rating)) in record.players.iter().zip(ratings).enumerate() {
+                    let stats = data.players.get_mut(id).unwrap();
+                    stats.games_played += 1;
+                    stats.rating = rating;
+                    match winner {
+                        Some(w) if w == i => stats.wins += 1,
+                        Some(_) => {}
+                        None => stats.draws += 1,
+                    }
+                }
+            }
+        }
+
+        data.games.push(record);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Total number of finished games and a page of them, newest first
+    pub fn games(&self, offset: usize, limit: usize) -> (usize, Vec<GameRecord>) {
+        let data = self.data.lock().unwrap();
+        let page = data
+            .games
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        (data.games.len(), page)
+    }
+
+    pub fn game(&self, id: Uuid) -> Option<GameRecord> {
+        let data = self.data.lock().unwrap();
+        data.games.iter().rev().find(|game| game.id == id).cloned()
+    }
+
+    /// The id of the player called `name`. The first player to sign in with a name claims it
+    /// for their token, after that only the same token gets the same id.
+    ///
+    /// # Errors
+    /// If the name isn't valid, no token is given or the name was claimed with another token
+    pub fn sign_in(&self, name: &str, token: &str) -> Result<Uuid, SignInError> {
+        let name = name.trim();
+        if name.is_empty()
+            || name.chars().count() > MAX_NAME_LEN
+            || name.chars().any(char::is_control)
+        {
+            return Err(SignInError::InvalidName);
+        }
+        if token.is_empty() {
+            return Err(SignInError::MissingToken);
+        }
+
+        let token_hash = hex_sha256(token);
+        let mut data = self.data.lock().unwrap();
+        if let Some(account) = data.accounts.get(name) {
+            return if account.token_hash == token_hash {
+                Ok(account.id)
+            } else {
+                Err(SignInError::WrongToken)
+            };
+        }
+
+        let id = Uuid::new_v4();
+        data.accounts
+            .insert(name.to_string(), Account { id, token_hash });
+        data.players.insert(
+            id,
+            PlayerStats {
+                name: Some(name.to_string()),
+                ..PlayerStats::new(id)
+            },
+        );
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(id)
+    }
+
+    /// The id of the player who signed in as `name`
+    pub fn player_id(&self, name: &str) -> Option<Uuid> {
+        let data = self.data.lock().unwrap();
+        data.accounts.get(name.trim()).map(|account| account.id)
+    }
+
+    pub fn player(&self, id: Uuid) -> Option<PlayerStats> {
+        self.data.lock().unwrap().players.get(&id).cloned()
+    }
+
+    /// The `limit` highest rated players that have finished a game
+    pub fn leaderboard(&self, limit: usize) -> Vec<PlayerStats> {
+        let data = self.data.lock().unwrap();
+        let mut players: Vec<_> = data
+            .players
+            .values()
+            .filter(|player| player.games_played > 0)
+            .cloned()
+            .collect();
+        players.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        players.truncate(limit);
+        players
+    }
+
+    /// Saves the latest state of a running game, replacing any earlier checkpoint of it
+    pub fn save_checkpoint(&self, checkpoint: Checkpoint) {
+        let mut data = self.data.lock().unwrap();
+        data.checkpoints.insert(checkpoint.game_id, checkpoint);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Every game that was running when its checkpoint was last saved
+    pub fn checkpoints(&self) -> Vec<Checkpoint> {
+        let data = self.data.lock().unwrap();
+        data.checkpoints.values().cloned().collect()
+    }
+
+    /// Writes everything to the storage file if anything changed since the last flush
+    pub fn flush(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let s = serde_json::to_string(&*self.data.lock().unwrap())?;
+        write_atomic(&self.path, s.as_bytes()).inspect_err(|_| {
+            self.dirty.store(true, Ordering::Relaxed);
+        })
+    }
+}
+
+/// Writes to a temporary file first so a crash never leaves a half written file behind
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, path)
+}
+
+fn hex_sha256(s: &str) -> String {
+    Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Seconds since the unix epoch
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> Storage {
+        let path = std::env::temp_dir().join(format!("skitgubbe-storage-{}.json", Uuid::new_v4()));
+        Storage::open(path).unwrap()
+    }
+
+    #[test]
+    fn test_sign_in_keeps_the_id_of_a_name() {
+        let storage = storage();
+        let id = storage.sign_in("alice", "secret").unwrap();
+        assert_eq!(storage.sign_in(" alice ", "secret"), Ok(id));
+        assert_eq!(
+            storage.sign_in("alice", "guess"),
+            Err(SignInError::WrongToken)
+        );
+        assert_ne!(storage.sign_in("bob", "secret").unwrap(), id);
+        assert_eq!(storage.player_id("alice"), Some(id));
+        assert_eq!(storage.player(id).unwrap().name.as_deref(), Some("alice"));
+
+        assert_eq!(storage.sign_in("", "secret"), Err(SignInError::InvalidName));
+        assert_eq!(
+            storage.sign_in("a\nb", "secret"),
+            Err(SignInError::InvalidName)
+        );
+        assert_eq!(
+            storage.sign_in(&"a".repeat(33), "secret"),
+            Err(SignInError::InvalidName)
+        );
+        assert_eq!(storage.sign_in("carol", ""), Err(SignInError::MissingToken));
+    }
+
+    #[test]
+    fn test_names_and_stats_survive_a_restart() {
+        let storage = storage();
+        let alice = storage.sign_in("alice", "secret").unwrap();
+        let bob = storage.sign_in("bob", "hunter2").unwrap();
+        storage.record_game(GameRecord {
+            id: Uuid::new_v4(),
+            players: vec![alice, bob],
+            seed: 0,
+            outcome: GameOutcome::Won(alice),
+            started_at: 0,
+            finished_at: 0,
+        });
+        storage.flush().unwrap();
+
+        let reopened = Storage::open(&storage.path).unwrap();
+        assert_eq!(reopened.sign_in("alice", "secret"), Ok(alice));
+        let stats = reopened.player(alice).unwrap();
+        assert_eq!((stats.games_played, stats.wins), (1, 1));
+        assert_eq!(reopened.leaderboard(1)[0].name.as_deref(), Some("alice"));
+        let _ = std::fs::remove_file(&storage.path);
+    }
+}
@@ -0,0 +1,132 @@
+//! Connections for tests, the server's end as a [`User`] and the other end as a client
+
+use std::time::Duration;
+
+use axum::{extract::WebSocketUpgrade, routing::get, Router};
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite;
+
+use skitgubbe_game::api::player_messages::action::LobbyAction;
+use skitgubbe_game::api::server_messages::ServerNotification;
+use skitgubbe_game::user::{testing, Heartbeat, User};
+
+use crate::game_manager::ServerQueue;
+
+pub use skitgubbe_game::user::testing::Client;
+
+/// How long a test waits for a message before failing
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Slow enough that it never gets in the way of a test
+pub const HEARTBEAT: Heartbeat = Heartbeat {
+    interval: Duration::from_secs(10),
+    timeout: Duration::from_secs(30),
+};
+
+/// A user whose socket is connected to the returned client over localhost
+pub async fn connect() -> (User, Client) {
+    testing::connect(HEARTBEAT).await
+}
+
+/// The next notification the client is sent, skipping other messages
+///
+/// # Panics
+/// If none arrives within a few seconds or the socket closes
+pub async fn next_notification(client: &mut Client) -> ServerNotification {
+    let next = async {
+        while let Some(Ok(message)) = client.next().await {
+            let tungstenite::Message::Text(message) = message else {
+                continue;
+            };
+            if let Ok(notification) = serde_json::from_str(&message) {
+                return notification;
+            }
+        }
+        panic!("The socket closed before a notification arrived");
+    };
+    tokio::time::timeout(WAIT, next)
+        .await
+        .expect("Timed out waiting for a notification")
+}
+
+/// Notifications a client was sent, see [`answer`]
+pub struct Notifications(mpsc::UnboundedReceiver<ServerNotification>);
+
+impl Notifications {
+    /// Waits for a notification `wanted` accepts, skipping the others
+    ///
+    /// # Panics
+    /// If none arrives within a few seconds or the socket closes
+    pub async fn wait_for(
+        &mut self,
+        wanted: impl Fn(&ServerNotification) -> bool,
+    ) -> ServerNotification {
+        let next = async {
+            while let Some(notification) = self.0.next().await {
+                if wanted(&notification) {
+                    return notification;
+                }
+            }
+            panic!("The socket closed before the notification arrived");
+        };
+        tokio::time::timeout(WAIT, next)
+            .await
+            .expect("Timed out waiting for a notification")
+    }
+}
+
+/// Replies to every notification the client is sent with `answer`, until the socket closes
+pub fn answer(
+    mut client: Client,
+    answer: impl Fn(&ServerNotification) -> Option<LobbyAction> + Send + 'static,
+) -> Notifications {
+    let (send, receive) = mpsc::unbounded();
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = client.next().await {
+            let tungstenite::Message::Text(message) = message else {
+                continue;
+            };
+            let Ok(notification) = serde_json::from_str(&message) else {
+                continue;
+            };
+            let reply = answer(&notification);
+            let _ = send.unbounded_send(notification);
+            if let Some(action) = reply {
+                let reply = serde_json::to_string(&action).unwrap();
+                if client
+                    .send(tungstenite::Message::Text(reply))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    });
+    Notifications(receive)
+}
+
+/// Acknowledges ready checks
+pub fn ready(notification: &ServerNotification) -> Option<LobbyAction> {
+    matches!(notification, ServerNotification::ReadyCheck { .. }).then_some(LobbyAction::Ready)
+}
+
+/// Serves `queue` at the returned url, the way the server's `/queue` endpoint does
+pub async fn serve(queue: ServerQueue) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = Router::new().route(
+        "/",
+        get(move |ws: WebSocketUpgrade| async move {
+            ws.on_upgrade(move |socket| async move {
+                let mut user = User::new(socket, HEARTBEAT);
+                let id = ServerNotification::Id(user.id.to_string());
+                let _ = user.send(&serde_json::to_string(&id).unwrap()).await;
+                queue.push_user(user).await;
+            })
+        }),
+    );
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    format!("ws://{addr}")
+}
@@ -0,0 +1,526 @@
+//! Plays bots against each other without a server, or connects a bot to one.
+//!
+//! Bots can be built in strategies or executables that speak the
+//! [subprocess protocol](skitgubbe_game::bot::subprocess), so bots written in any language can
+//! be tried out locally and then put in the server's queue.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use skitgubbe_game::{
+    bot::{
+        ismcts::{Ismcts, IsmctsConfig},
+        local,
+        reference::{Heuristic, LowestCard, Random},
+        runner::{self, RunnerConfig},
+        script::ScriptBot,
+        subprocess::{Limits, Subprocess},
+        wasm::{WasmBot, WasmLimits},
+        Strategy,
+    },
+    game::RuleSet,
+    simulation::{self, head_to_head_score, SimulationConfig},
+    stats::{win_rate_interval, Decision, Interval, Sprt, Z_95},
+    tournament::{self, Format, Participant, TournamentConfig},
+};
+
+/// Plays bots against each other in-process, or puts one in a server's queue
+#[derive(Parser, Debug)]
+#[command(version, after_help = BOTS)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+const BOTS: &str = "Bots are random, lowest, heuristic or ismcts. Anything else is run as a \
+command that speaks the subprocess protocol, eg. \"python3 bot.py\", except for .wasm files which \
+are loaded as WebAssembly bots and .rhai files which are played as scripts. Subprocess and \
+WebAssembly bots forfeit if they go over their limits.";
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Play games between bots in-process on every core and show how they did
+    ///
+    /// A new bot is made for every deal, so the results depend only on the seed.
+    Play(PlayArgs),
+    /// Play a bot on the server, eg. at ws://localhost:3000/queue
+    Queue(QueueArgs),
+    /// Play deals from both seats until it is clear whether the candidate is better than the
+    /// baseline
+    ///
+    /// Exits with 0 if the candidate is better, 1 if not and 2 if it couldn't tell.
+    Sprt(SprtArgs),
+    /// Play a round-robin, swiss or knockout tournament and show the standings
+    Tournament(TournamentArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct PlayArgs {
+    /// Deals to play
+    #[arg(long, default_value_t = 1)]
+    games: usize,
+    /// Seed of the first deal, random if unset
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Games played at the same time, by default one per core
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Plays every deal from each seating of the bots and compares them on the deals they won
+    #[arg(long)]
+    duplicate: bool,
+    #[command(flatten)]
+    limits: LimitArgs,
+    /// 2 to 4 bots, see below
+    #[arg(required = true, num_args = 2..=4)]
+    bots: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct QueueArgs {
+    /// Games to play before leaving, keeps requeueing if unset
+    #[arg(long)]
+    games: Option<usize>,
+    /// Seed of built in bots, random if unset
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Signs in as this player, which keeps the bot's stats and rating between runs. The
+    /// first run with a name claims it for the token.
+    #[arg(long, requires = "token")]
+    name: Option<String>,
+    #[arg(long, requires = "name")]
+    token: Option<String>,
+    #[command(flatten)]
+    limits: LimitArgs,
+    /// The server's queue endpoint
+    url: String,
+    bot: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct SprtArgs {
+    /// How many percent better the candidate must be
+    #[arg(long, default_value_t = 5.0)]
+    better: f64,
+    /// Chance of accepting a candidate that isn't better
+    #[arg(long, default_value_t = 0.05)]
+    alpha: f64,
+    /// Chance of rejecting a candidate that is better
+    #[arg(long, default_value_t = 0.05)]
+    beta: f64,
+    /// Stops after this many deals even if it couldn't tell
+    #[arg(long)]
+    games: Option<usize>,
+    /// Seed of the first deal, random if unset
+    #[arg(long)]
+    seed: Option<u64>,
+    #[command(flatten)]
+    limits: LimitArgs,
+    /// The bot being tested
+    candidate: String,
+    /// The bot it has to beat
+    baseline: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct TournamentArgs {
+    #[arg(long, value_enum, default_value_t = FormatArg::RoundRobin)]
+    format: FormatArg,
+    /// Rounds of a swiss tournament, by default enough for a clear winner
+    #[arg(long)]
+    rounds: Option<usize>,
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(2..=4))]
+    table_size: u8,
+    /// Games every table plays from each seat, or deals from every seating with --duplicate
+    #[arg(long, default_value_t = 1)]
+    games: usize,
+    /// Random if unset
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Tables played at the same time, by default one per core
+    #[arg(long)]
+    threads: Option<usize>,
+    /// JSON file the tournament is stored in, rewritten after every round
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Plays every deal from each seating of the table
+    #[arg(long)]
+    duplicate: bool,
+    #[command(flatten)]
+    limits: LimitArgs,
+    /// At least 2 bots, see below
+    #[arg(required = true, num_args = 2..)]
+    bots: Vec<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FormatArg {
+    RoundRobin,
+    Swiss,
+    Knockout,
+}
+
+/// What subprocess and WebAssembly bots may use
+#[derive(clap::Args, Clone, Debug)]
+struct LimitArgs {
+    /// CPU time for the whole run
+    #[arg(long, value_name = "SECS")]
+    cpu_time: Option<u64>,
+    /// Memory the bot may use
+    #[arg(long, value_name = "MB")]
+    memory: Option<u64>,
+    /// Time to answer each request
+    #[arg(long, value_name = "MS")]
+    move_time: Option<u64>,
+    /// Longest line the bot may send
+    #[arg(long, value_name = "BYTES")]
+    max_output: Option<usize>,
+    /// Instructions a WebAssembly bot may run per move
+    #[arg(long)]
+    fuel: Option<u64>,
+}
+
+impl LimitArgs {
+    fn limits(&self) -> Limits {
+        Limits {
+            cpu_time: self.cpu_time.map(Duration::from_secs),
+            memory: self.memory.map(|mb| mb * 1024 * 1024),
+            move_time: self.move_time.map(Duration::from_millis),
+            max_output: self.max_output,
+        }
+    }
+
+    fn wasm_limits(&self) -> WasmLimits {
+        let mut limits = WasmLimits::default();
+        if let Some(fuel) = self.fuel {
+            limits.fuel = fuel;
+        }
+        if let Some(mb) = self.memory {
+            limits.memory = mb as usize * 1024 * 1024;
+        }
+        limits
+    }
+}
+
+/// Creates the bot named by `spec`, built in strategies use `seed`
+fn bot(spec: &str, seed: u64, limits: &LimitArgs) -> Result<Box<dyn Strategy>, String> {
+    Ok(match spec {
+        "random" => Box::new(Random::new(seed)),
+        "lowest" => Box::new(LowestCard),
+        "heuristic" => Box::new(Heuristic),
+        "ismcts" => Box::new(Ismcts::new(IsmctsConfig {
+            seed,
+            ..IsmctsConfig::default()
+        })),
+        path if path.ends_with(".wasm") => {
+            let bot = WasmBot::load(path, limits.wasm_limits());
+            Box::new(bot.map_err(|e| e.to_string())?)
+        }
+        path if path.ends_with(".rhai") => {
+            Box::new(ScriptBot::load(path).map_err(|e| e.to_string())?)
+        }
+        command => {
+            let bot = Subprocess::from_command_line(command, limits.limits());
+            Box::new(bot.map_err(|e| e.to_string())?)
+        }
+    })
+}
+
+fn play(args: PlayArgs) -> ExitCode {
+    let mut config = SimulationConfig {
+        deals: args.games,
+        duplicate: args.duplicate,
+        seed: args.seed.unwrap_or_else(rand::random),
+        ..SimulationConfig::default()
+    };
+    if let Some(threads) = args.threads {
+        config.threads = threads;
+    }
+    // a new bot is made for every deal, so find out now if one can't be
+    for spec in &args.bots {
+        if let Err(e) = bot(spec, config.seed, &args.limits) {
+            eprintln!("{spec}: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let stats = simulation::run(&participants(&args.bots, &args.limits), &config);
+    println!(
+        "{} games from seed {}, {} draws, with 95% confidence intervals",
+        stats.games, config.seed, stats.draws
+    );
+    for (spec, bot) in args.bots.iter().zip(&stats.bots) {
+        let interval = win_rate_interval(bot.wins, stats.games, Z_95);
+        let duplicate = if args.duplicate {
+            format!(", {:.1} of {} deals won", bot.deals_won, stats.deals)
+        } else {
+            String::new()
+        };
+        println!(
+            "{spec}: {} wins ({}){duplicate}, {} forfeits, {} rejected actions",
+            bot.wins,
+            percent(bot.wins as f64 / stats.games as f64, interval),
+            bot.forfeits,
+            bot.rejections
+        );
+        if let Some((deal, reason)) = &bot.first_forfeit {
+            println!("    first forfeited deal {deal}: {reason}");
+        }
+    }
+    for (a, scores) in stats.head_to_head.iter().enumerate() {
+        for (b, scores) in scores.iter().enumerate().skip(a + 1) {
+            println!(
+                "{} scores {} against {}",
+                args.bots[a],
+                percent(scores.mean(), scores.interval(Z_95)),
+                args.bots[b]
+            );
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn percent(value: f64, interval: Interval) -> String {
+    format!(
+        "{:.1}%, {:.1}% to {:.1}%",
+        value * 100.0,
+        interval.low * 100.0,
+        interval.high * 100.0
+    )
+}
+
+fn sprt(args: SprtArgs) -> ExitCode {
+    let (candidate, baseline) = (&args.candidate, &args.baseline);
+    let mut test = Sprt::new(args.better / 100.0, args.alpha, args.beta);
+    let seed = args.seed.unwrap_or_else(rand::random);
+
+    let mut bots = vec![];
+    for (seat, spec) in [candidate, baseline].into_iter().enumerate() {
+        match bot(spec, seed.wrapping_add(seat as u64), &args.limits) {
+            Ok(bot) => bots.push(bot),
+            Err(e) => {
+                eprintln!("{spec}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let mut deals = 0;
+    while test.decision().is_none() && args.games.is_none_or(|games| deals < games) {
+        let mut strategies: Vec<&mut dyn Strategy> = bots
+            .iter_mut()
+            .map(|bot| bot.as_mut() as &mut dyn Strategy)
+            .collect();
+        // each deal is played from both seats, which cancels out most of its luck
+        let games = local::play_duplicate(
+            &mut strategies,
+            seed.wrapping_add(deals as u64),
+            RuleSet::default(),
+        );
+        let score: f64 = games
+            .iter()
+            .map(|game| {
+                let positions = game.result.positions();
+                let seat = game.seating.iter().position(|&bot| bot == 0).unwrap();
+                head_to_head_score(positions[seat], positions[1 - seat])
+            })
+            .sum();
+        test.add(score / games.len() as f64);
+        deals += 1;
+        if deals % 100 == 0 {
+            let (lower, upper) = test.bounds();
+            eprintln!(
+                "{deals} deals, llr {:.2} ({lower:.2}, {upper:.2})",
+                test.llr()
+            );
+        }
+    }
+
+    let scores = test.scores();
+    let (lower, upper) = test.bounds();
+    println!("{deals} deals played from both seats from seed {seed}");
+    println!(
+        "{candidate} scores {} against {baseline}, 95% confidence interval",
+        percent(scores.mean(), scores.interval(Z_95))
+    );
+    println!("llr {:.2}, bounds ({lower:.2}, {upper:.2})", test.llr());
+    let better = args.better;
+    match test.decision() {
+        Some(Decision::Accept) => {
+            println!("Accepted: {candidate} is at least {better}% better");
+            ExitCode::SUCCESS
+        }
+        Some(Decision::Reject) => {
+            println!("Rejected: {candidate} is not {better}% better");
+            ExitCode::FAILURE
+        }
+        None => {
+            println!("Inconclusive after {deals} deals");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn queue(args: QueueArgs) -> ExitCode {
+    let spec = &args.bot;
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let mut bot = match bot(spec, seed, &args.limits) {
+        Ok(bot) => bot,
+        Err(e) => {
+            eprintln!("{spec}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = RunnerConfig {
+        url: args.url,
+        games: args.games,
+        name: args.name,
+        token: args.token,
+        ..RunnerConfig::default()
+    };
+
+    let runtime = tokio::runtime::Runtime::new().expect("Should be able to start a runtime");
+    match runtime.block_on(runner::run(&mut bot, &config)) {
+        Ok(summary) => {
+            println!("{summary:?}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The bots named on the command line, made fresh for every game
+fn participants(specs: &[String], limits: &LimitArgs) -> Vec<Participant> {
+    let limits = Arc::new(limits.clone());
+    specs
+        .iter()
+        .enumerate()
+        .map(|(i, spec)| {
+            // the same bot can be entered more than once
+            let entered = specs[..i].iter().filter(|s| *s == spec).count();
+            let name = match entered {
+                0 => spec.clone(),
+                n => format!("{spec} #{}", n + 1),
+            };
+            let (spec, limits) = (spec.clone(), Arc::clone(&limits));
+            Participant::new(name, move |seed| bot(&spec, seed, &limits))
+        })
+        .collect()
+}
+
+fn run_tournament(args: TournamentArgs) -> ExitCode {
+    let players = args.bots.len();
+    let format = match args.format {
+        FormatArg::RoundRobin => Format::RoundRobin,
+        FormatArg::Swiss => Format::Swiss {
+            // enough rounds for a clear winner
+            rounds: args
+                .rounds
+                .unwrap_or(players.next_power_of_two().trailing_zeros() as usize),
+        },
+        FormatArg::Knockout => Format::Knockout,
+    };
+    let mut config = TournamentConfig {
+        format,
+        table_size: args.table_size.into(),
+        games_per_seat: args.games,
+        duplicate: args.duplicate,
+        seed: args.seed.unwrap_or_else(rand::random),
+        storage_path: args.output.clone(),
+        ..TournamentConfig::default()
+    };
+    if let Some(threads) = args.threads {
+        config.threads = threads;
+    }
+
+    let participants = participants(&args.bots, &args.limits);
+    let tournament = match tournament::run(&participants, &config) {
+        Ok(tournament) => tournament,
+        Err(e) => {
+            eprintln!("Couldn't save the tournament: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!(
+        "{} games in {} rounds from seed {}",
+        tournament.games.len(),
+        tournament.rounds_played,
+        config.seed
+    );
+    println!(
+        "     {:<30} {:>6} {:>8} {:>6} {:>9} {:>8}",
+        "bot", "games", "points", "wins", "forfeits", "avg pos"
+    );
+    for (rank, standing) in tournament.ranking().iter().enumerate() {
+        println!(
+            "{:>3}. {:<30} {:>6} {:>8.1} {:>6} {:>9} {:>8.2}",
+            rank + 1,
+            standing.name,
+            standing.games,
+            standing.points,
+            standing.wins,
+            standing.forfeits,
+            standing.average_position()
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    // clap exits with 2 on bad arguments, which sprt uses for an inconclusive test
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+
+    match cli.command {
+        Command::Play(args) => play(args),
+        Command::Queue(args) => queue(args),
+        Command::Sprt(args) => sprt(args),
+        Command::Tournament(args) => run_tournament(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_unknown_flags_are_rejected() {
+        let cli = Cli::try_parse_from(["sim", "play", "--games", "3", "lowest", "random"]).unwrap();
+        let Command::Play(args) = cli.command else {
+            panic!("Should be the play command");
+        };
+        assert_eq!(args.games, 3);
+        assert_eq!(args.bots, ["lowest", "random"]);
+
+        assert!(Cli::try_parse_from(["sim", "play", "--gmaes", "3", "lowest", "random"]).is_err());
+        assert!(Cli::try_parse_from(["sim", "play", "lowest"]).is_err());
+        assert!(Cli::try_parse_from(["sim", "tournament", "--table-size", "5", "a", "b"]).is_err());
+        assert!(
+            Cli::try_parse_from(["sim", "queue", "--name", "bot", "ws://x", "lowest"]).is_err()
+        );
+    }
+}
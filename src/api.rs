@@ -4,14 +4,14 @@ pub mod player_messages {
 
         use crate::deck::Card;
 
-        #[derive(Deserialize, Serialize)]
+        #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
         pub enum SetupAction {
             ExchangeCard { hand: Vec<Card>, bottom: usize },
             CompoundCard { hand: Vec<Card>, bottom: usize },
             FinishExchange,
         }
 
-        #[derive(Deserialize, Serialize)]
+        #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
         pub enum PlayAction {
             /// Player places a card or cards
             /// If player has < 3 cards then will automatically pick up a card if possible
@@ -28,21 +28,82 @@ pub mod player_messages {
             /// Player picks up the stack
             PickupStack,
         }
+
+        /// Actions taken outside of a game
+        #[derive(Deserialize, Serialize)]
+        pub enum LobbyAction {
+            /// Acknowledges a [`ServerNotification::ReadyCheck`]
+            ///
+            /// [`ServerNotification::ReadyCheck`]: crate::api::server_messages::ServerNotification::ReadyCheck
+            Ready,
+            /// Joins the back of the queue for another game
+            Requeue,
+            /// Asks to play again with the same table. If anyone at the table doesn't also ask for
+            /// a rematch then the player is requeued instead.
+            Rematch,
+            /// Disconnects from the server
+            Leave,
+        }
     }
 }
 
 pub mod server_messages {
     use serde::{Deserialize, Serialize};
 
+    use crate::api::player_messages::action::{PlayAction, SetupAction};
     use crate::deck::Card;
 
     #[derive(Serialize, Deserialize)]
     pub enum ServerNotification {
         GameStart(Vec<String>),
         Id(String),
+        /// Sent to players selected for a game. Must be answered with
+        /// [`LobbyAction::Ready`](crate::api::player_messages::action::LobbyAction::Ready)
+        /// within the deadline or the player is dropped from the queue.
+        ReadyCheck {
+            deadline_secs: u64,
+        },
+        /// Sent after a game has finished. Must be answered with a
+        /// [`LobbyAction`](crate::api::player_messages::action::LobbyAction) other than `Ready`
+        /// within the deadline or the player is disconnected.
+        PostGame {
+            deadline_secs: u64,
+        },
+        /// The game was stopped early, players are returned to the lobby
+        GameAborted(String),
+        /// The queue is full, the connection is closed after this message
+        QueueFull,
+        /// The name or token given to `/queue` was rejected, or a player with the same name is
+        /// already connected. The connection is closed after this message.
+        SignInFailed(String),
+        /// The server is shutting down. Running games have `grace_secs` to finish before they are
+        /// suspended, then every connection is closed.
+        ShuttingDown {
+            grace_secs: u64,
+        },
+        /// Sent at the start of a game. If the game is suspended by a restart, reconnecting to
+        /// `/queue?resume=<token>` takes the player back to their seat once the server is up.
+        ResumeToken {
+            game_id: String,
+            token: String,
+        },
+        /// The last action was rejected for the given reason. The table is sent again after this
+        /// and the player is asked for another action.
+        ActionRejected(String),
+        /// The game finished, `winner` is `None` if it was a draw
+        GameOver {
+            winner: Option<String>,
+        },
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    /// An action a player took during a game
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum GameEvent {
+        Setup { player: String, action: SetupAction },
+        Play { player: String, action: PlayAction },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
     #[serde(tag = "Stage")]
     pub enum Stage {
         Swap,
@@ -51,7 +112,7 @@ pub mod server_messages {
 
     pub type BottomCards = Vec<Vec<Card>>;
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     #[serde(tag = "state")]
     pub struct Cards {
         pub hand: Vec<Card>,
@@ -59,7 +120,7 @@ pub mod server_messages {
     }
 
     /// A struct representing the game state
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     pub struct GameState {
         /// The ID of the player who turn it is
         pub turn: String,
@@ -71,4 +132,43 @@ pub mod server_messages {
         /// Other players visible cards
         pub other_players: Vec<(String, BottomCards)>,
     }
+
+    /// What a spectator can see of a player
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PublicPlayer {
+        pub id: String,
+        pub hand_size: usize,
+        pub visible_cards: BottomCards,
+        pub hidden_cards: usize,
+    }
+
+    /// A view of a game without any hidden information, sent to spectators
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SpectatorView {
+        /// The ID of the player who turn it is, empty during the swap stage
+        pub turn: String,
+        pub stage: Stage,
+        pub stack: Vec<Card>,
+        pub deck_size: usize,
+        pub players: Vec<PublicPlayer>,
+        /// The last action taken by any player
+        pub last_action: Option<GameEvent>,
+    }
+
+    /// All of a player's cards
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct FullPlayerCards {
+        pub id: String,
+        pub hand: Vec<Card>,
+        pub visible_cards: BottomCards,
+        pub hidden_cards: Vec<Option<Card>>,
+    }
+
+    /// A view of a game including every player's cards and the deck, only sent to admins
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct GodView {
+        pub view: SpectatorView,
+        pub players: Vec<FullPlayerCards>,
+        pub deck: Vec<Card>,
+    }
 }
@@ -0,0 +1,332 @@
+//! Information set Monte Carlo tree search.
+//!
+//! Every iteration deals the cards we can't see at random, consistent with what has happened in
+//! the game so far, and then searches that deal with the rules engine. Moves that are only
+//! possible in some deals are picked by how often they were available, so the tree ends up
+//! shared between all the deals.
+
+use std::time::{Duration, Instant};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use super::reference::{value, Heuristic};
+use super::tracker::CardTracker;
+use super::{Event, Strategy, View};
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+use crate::deck::CardIndex;
+use crate::game::{Outcome, RuleSet, Table};
+
+/// How often a simulated player makes a random move instead of playing its lowest card
+const ROLLOUT_RANDOMNESS: f64 = 0.1;
+
+/// How long the search may take
+#[derive(Clone, Debug)]
+pub struct IsmctsConfig {
+    /// Iterations per move
+    pub iterations: usize,
+    /// Stops searching after this long even if not every iteration has run
+    pub time_limit: Option<Duration>,
+    /// Trades off trying moves that look good against trying the others more
+    pub exploration: f64,
+    /// Simulated games are scored by the cards each seat has left after this many turns
+    pub max_rollout_turns: usize,
+    /// The rules the game is played with, as the server doesn't send them
+    pub rules: RuleSet,
+    pub seed: u64,
+}
+
+impl Default for IsmctsConfig {
+    fn default() -> Self {
+        Self {
+            iterations: 1000,
+            time_limit: None,
+            exploration: 0.7,
+            max_rollout_turns: 30,
+            rules: RuleSet::default(),
+            seed: 0,
+        }
+    }
+}
+
+/// Searches every move of the play stage, swaps like [`Heuristic`]
+pub struct Ismcts {
+    config: IsmctsConfig,
+    rng: StdRng,
+    tracker: CardTracker,
+}
+
+impl Ismcts {
+    pub fn new(config: IsmctsConfig) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            tracker: CardTracker::new(config.rules.clone()),
+            config,
+        }
+    }
+
+    /// `None` if the tracker hasn't seen the play stage start
+    fn search(&mut self) -> Option<PlayAction> {
+        let started = Instant::now();
+        let mut tree = vec![Node::root()];
+
+        for _ in 0..self.config.iterations {
+            if self
+                .config
+                .time_limit
+                .is_some_and(|limit| started.elapsed() >= limit)
+            {
+                break;
+            }
+            let table = self.tracker.determinize(&mut self.rng)?;
+            self.iterate(&mut tree, table);
+        }
+
+        // the most visited move is the most reliable, the root's moves are the same in every deal
+        tree[0]
+            .children
+            .iter()
+            .max_by_key(|&&child| tree[child].visits)
+            .and_then(|&child| tree[child].action)
+            .map(Move::action)
+    }
+
+    /// Walks down the tree with the moves possible in `table`, adds one new move and plays the
+    /// game on from there
+    fn iterate(&mut self, tree: &mut Vec<Node>, mut table: Table) {
+        let mut path = vec![0];
+        let mut outcome = Outcome::Ongoing;
+
+        while outcome == Outcome::Ongoing {
+            let node = *path.last().unwrap();
+            let seat = table.turn();
+            let legal = moves(&table, seat);
+            let untried: Vec<Move> = legal
+                .iter()
+                .copied()
+                .filter(|&action| {
+                    !tree[node]
+                        .children
+                        .iter()
+                        .any(|&child| tree[child].action == Some(action))
+                })
+                .collect();
+
+            if let Some(&action) = untried.choose(&mut self.rng) {
+                let child = tree.len();
+                tree.push(Node::new(action, seat));
+                tree[node].children.push(child);
+                path.push(child);
+                outcome = table
+                    .play_action(seat, &action.action())
+                    .expect("Move should be legal");
+                break;
+            }
+
+            let available: Vec<usize> = tree[node]
+                .children
+                .iter()
+                .copied()
+                .filter(|&child| legal.iter().any(|&a| tree[child].action == Some(a)))
+                .collect();
+            for &child in &available {
+                tree[child].available += 1;
+            }
+            let exploration = self.config.exploration;
+            let child = *available
+                .iter()
+                .max_by(|&&a, &&b| {
+                    tree[a]
+                        .ucb(exploration)
+                        .total_cmp(&tree[b].ucb(exploration))
+                })
+                .expect("There is always a legal move");
+            path.push(child);
+            let action = tree[child].action.unwrap();
+            outcome = table
+                .play_action(seat, &action.action())
+                .expect("Move should be legal");
+        }
+
+        let rewards = self.rollout(&mut table, outcome);
+        for node in path {
+            let node = &mut tree[node];
+            node.visits += 1;
+            if let Some(seat) = node.seat {
+                node.reward += rewards[seat];
+            }
+        }
+    }
+
+    /// Plays the game on until it ends or runs too long, returning what each seat scored
+    fn rollout(&mut self, table: &mut Table, mut outcome: Outcome) -> Vec<f64> {
+        let mut turns = 0;
+        while outcome == Outcome::Ongoing && turns < self.config.max_rollout_turns {
+            let seat = table.turn();
+            // simulated players mostly get rid of their lowest card, 2s and 10s last
+            let action = if self.rng.gen_bool(ROLLOUT_RANDOMNESS) {
+                *moves(table, seat).choose(&mut self.rng).unwrap()
+            } else {
+                table
+                    .placeable_cards(seat)
+                    .iter()
+                    .min_by_key(|card| value(card.rank()))
+                    .map_or(Move::Pickup, Move::Place)
+            };
+            outcome = table
+                .play_action(seat, &action.action())
+                .expect("Move should be legal");
+            turns += 1;
+        }
+
+        let seats = table.seats();
+        match outcome {
+            Outcome::Won(winner) => (0..seats).map(|seat| f64::from(seat == winner)).collect(),
+            Outcome::Draw => vec![1.0 / seats as f64; seats],
+            // the fewer cards a seat has left compared to the others the better it is doing
+            Outcome::Ongoing => {
+                let inverse: Vec<f64> = (0..seats)
+                    .map(|seat| 1.0 / table.player(seat).cards_left() as f64)
+                    .collect();
+                let total: f64 = inverse.iter().sum();
+                inverse.iter().map(|share| share / total).collect()
+            }
+        }
+    }
+}
+
+impl Strategy for Ismcts {
+    fn on_setup(&mut self, view: &View) -> SetupAction {
+        Heuristic.on_setup(view)
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        let mut legal = view.legal_plays();
+        if legal.len() == 1 {
+            return legal.remove(0);
+        }
+        self.search().unwrap_or_else(|| Heuristic.on_turn(view))
+    }
+
+    fn on_event(&mut self, event: &Event) {
+        self.tracker.observe(event);
+    }
+}
+
+/// A move in the tree, cheaper to copy and compare than a [`PlayAction`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Move {
+    Place(CardIndex),
+    Pickup,
+}
+
+impl Move {
+    fn action(self) -> PlayAction {
+        match self {
+            Move::Place(card) => PlayAction::PlaceCard { card: card.card() },
+            Move::Pickup => PlayAction::PickupStack,
+        }
+    }
+}
+
+/// The legal moves worth searching, picking up an empty stack only passes the turn so it is left
+/// out unless there is nothing else to do
+fn moves(table: &Table, seat: usize) -> Vec<Move> {
+    let placeable = table.placeable_cards(seat);
+    let mut legal: Vec<Move> = placeable.iter().map(Move::Place).collect();
+    if !table.playing_stack().is_empty() || legal.is_empty() {
+        legal.push(Move::Pickup);
+    }
+    legal
+}
+
+struct Node {
+    /// The move that led here, `None` for the root
+    action: Option<Move>,
+    /// Seat that made the move
+    seat: Option<usize>,
+    children: Vec<usize>,
+    visits: usize,
+    /// Times the move could have been made when its parent was visited
+    available: usize,
+    reward: f64,
+}
+
+impl Node {
+    fn root() -> Self {
+        Self {
+            action: None,
+            seat: None,
+            children: vec![],
+            visits: 0,
+            available: 0,
+            reward: 0.0,
+        }
+    }
+
+    fn new(action: Move, seat: usize) -> Self {
+        Self {
+            action: Some(action),
+            seat: Some(seat),
+            // it was available when it was added
+            available: 1,
+            ..Self::root()
+        }
+    }
+
+    fn ucb(&self, exploration: f64) -> f64 {
+        let visits = self.visits as f64;
+        self.reward / visits + exploration * ((self.available as f64).ln() / visits).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::local::{play_duplicate, play_game};
+    use crate::bot::reference::Random;
+    use crate::simulation::head_to_head_score;
+
+    #[test]
+    fn test_ismcts_only_makes_legal_moves() {
+        for seed in 0..2 {
+            let mut ismcts = Ismcts::new(IsmctsConfig {
+                iterations: 30,
+                seed,
+                ..IsmctsConfig::default()
+            });
+            let mut random = Random::new(seed);
+            let result = play_game(&mut [&mut ismcts, &mut random], seed, RuleSet::default());
+            assert_eq!(result.rejections, [0, 0], "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_ismcts_beats_random() {
+        const DEALS: u64 = 8;
+        // games that reach the limit are scored by the cards left
+        let rules = RuleSet {
+            max_turns: 200,
+            ..RuleSet::default()
+        };
+        let mut score = 0.0;
+        let mut games = 0;
+        for seed in 0..DEALS {
+            let mut ismcts = Ismcts::new(IsmctsConfig {
+                iterations: 50,
+                rules: rules.clone(),
+                seed,
+                ..IsmctsConfig::default()
+            });
+            let mut random = Random::new(seed);
+            for game in play_duplicate(&mut [&mut ismcts, &mut random], seed, rules.clone()) {
+                let positions = game.result.positions();
+                let ismcts_seat = game.seating.iter().position(|&bot| bot == 0).unwrap();
+                score += head_to_head_score(positions[ismcts_seat], positions[1 - ismcts_seat]);
+                games += 1;
+            }
+        }
+        // each deal is played from both seats so an even match would score half
+        let score = score / games as f64;
+        assert!(score > 0.7, "ISMCTS scored {score} over {games} games");
+    }
+}
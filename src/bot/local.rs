@@ -0,0 +1,307 @@
+//! Plays strategies against each other in-process, without a server
+
+use super::{Event, Strategy, View};
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+use crate::api::server_messages::{GameEvent, Stage};
+use crate::game::{Outcome, RuleSet, Table};
+
+/// Rejected actions in a row before a seat falls back to finishing the swap or picking up
+const MAX_RETRIES: usize = 3;
+
+/// How a game between strategies went
+#[derive(Clone, Debug)]
+pub struct GameResult {
+    pub outcome: Outcome,
+    /// Turns taken during the play stage
+    pub turns: usize,
+    /// Cards each seat had left when the game ended
+    pub cards_left: Vec<usize>,
+    /// Actions rejected by the rules, by seat
+    pub rejections: Vec<usize>,
+    /// Every action taken, including rejected ones, as the server logs them. Together with the
+    /// seed the game can be played back.
+    pub events: Vec<GameEvent>,
+    /// The seat that forfeited, which ended the game
+    pub forfeit: Option<Forfeit>,
+}
+
+impl GameResult {
+    /// Finishing positions in seat order, 1 is first. The winner is first, whoever forfeited
+    /// is last and the rest are ordered by the cards they had left. Tied seats share the
+    /// average of their positions.
+    pub fn positions(&self) -> Vec<f64> {
+        let seats = self.cards_left.len();
+        let key = |seat: usize| {
+            if self
+                .forfeit
+                .as_ref()
+                .is_some_and(|forfeit| forfeit.seat == seat)
+            {
+                usize::MAX
+            } else if self.outcome == Outcome::Won(seat) {
+                0
+            } else {
+                self.cards_left[seat] + 1
+            }
+        };
+        (0..seats)
+            .map(|seat| {
+                let ahead = (0..seats).filter(|&other| key(other) < key(seat)).count();
+                let tied = (0..seats).filter(|&other| key(other) == key(seat)).count();
+                // the positions from `ahead + 1` to `ahead + tied` averaged
+                ahead as f64 + (tied as f64 + 1.0) / 2.0
+            })
+            .collect()
+    }
+}
+
+/// One of the games of [`play_duplicate`]
+#[derive(Clone, Debug)]
+pub struct DuplicateGame {
+    /// The index of the strategy at each seat
+    pub seating: Vec<usize>,
+    pub result: GameResult,
+}
+
+/// A seat whose strategy gave up, see [`Strategy::forfeit`]. With two seats the other seat wins,
+/// with more it is a draw between the rest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Forfeit {
+    pub seat: usize,
+    pub reason: String,
+}
+
+/// Id a seat is known by in the views strategies are given
+pub fn seat_id(seat: usize) -> String {
+    seat.to_string()
+}
+
+/// Deals a game from `seed` and plays it to the end, `strategies` are seated in order
+pub fn play_game(strategies: &mut [&mut dyn Strategy], seed: u64, rules: RuleSet) -> GameResult {
+    play_table(strategies, Table::deal(strategies.len(), seed, rules))
+}
+
+/// Deals a game from `seed` and plays it from every seating of `strategies`, so each strategy
+/// gets a go with every hand. Comparing how strategies did with the same cards takes most of the
+/// luck of the deal out of the results.
+pub fn play_duplicate(
+    strategies: &mut [&mut dyn Strategy],
+    seed: u64,
+    rules: RuleSet,
+) -> Vec<DuplicateGame> {
+    let mut games = vec![];
+    for seating in permutations(strategies.len()) {
+        let mut unseated: Vec<Option<&mut &mut dyn Strategy>> =
+            strategies.iter_mut().map(Some).collect();
+        let mut seated: Vec<&mut dyn Strategy> = seating
+            .iter()
+            .map(|&i| &mut **unseated[i].take().expect("Seatings are permutations") as _)
+            .collect();
+        let result = play_game(&mut seated, seed, rules.clone());
+        games.push(DuplicateGame { seating, result });
+    }
+    games
+}
+
+/// Every ordering of `0..n`
+pub fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![vec![]];
+    }
+    let mut orderings = vec![];
+    for shorter in permutations(n - 1) {
+        for i in 0..n {
+            let mut ordering = shorter.clone();
+            ordering.insert(i, n - 1);
+            orderings.push(ordering);
+        }
+    }
+    orderings
+}
+
+/// Plays `table` to the end from wherever it is, `strategies` are seated in order
+pub fn play_table(strategies: &mut [&mut dyn Strategy], mut table: Table) -> GameResult {
+    assert_eq!(
+        strategies.len(),
+        table.seats(),
+        "Every seat at the table must have a strategy"
+    );
+    let ids: Vec<String> = (0..table.seats()).map(seat_id).collect();
+    let mut rejections = vec![0; table.seats()];
+    let mut events = vec![];
+    let mut forfeit = None;
+
+    for (strategy, id) in strategies.iter_mut().zip(&ids) {
+        let players = ids.clone();
+        let id = id.clone();
+        strategy.on_event(&Event::GameStart { players, id });
+    }
+
+    if table.stage() == Stage::Swap {
+        broadcast(strategies, &Event::Stage(Stage::Swap));
+        'swap: for seat in 0..table.seats() {
+            let mut retries = 0;
+            while !table.has_finished_setup(seat) {
+                let state = table.view_for(seat, &ids);
+                let strategy = &mut strategies[seat];
+                strategy.on_event(&Event::State(state.clone()));
+                let action = if retries > MAX_RETRIES {
+                    SetupAction::FinishExchange
+                } else {
+                    strategy.on_setup(&View::new(ids[seat].clone(), state))
+                };
+                if let Some(reason) = strategy.forfeit() {
+                    forfeit = Some(Forfeit { seat, reason });
+                    break 'swap;
+                }
+                events.push(GameEvent::Setup {
+                    player: ids[seat].clone(),
+                    action: action.clone(),
+                });
+
+                match table.setup_action(seat, action) {
+                    Ok(()) => retries = 0,
+                    Err(reason) => {
+                        retries += 1;
+                        rejections[seat] += 1;
+                        let reason = reason.to_string();
+                        strategy.on_event(&Event::Rejected { reason });
+                    }
+                }
+            }
+        }
+    }
+
+    if forfeit.is_none() {
+        broadcast(strategies, &Event::Stage(Stage::Play));
+    }
+    let mut retries = 0;
+    let outcome = loop {
+        if let Some(Forfeit { seat, .. }) = forfeit {
+            break match table.seats() {
+                2 => Outcome::Won(1 - seat),
+                _ => Outcome::Draw,
+            };
+        }
+        let seat = table.turn();
+        // like the server, only the seat whose action was rejected is shown the table again
+        if retries == 0 {
+            for (other, strategy) in strategies.iter_mut().enumerate() {
+                strategy.on_event(&Event::State(table.view_for(other, &ids)));
+            }
+        } else {
+            strategies[seat].on_event(&Event::State(table.view_for(seat, &ids)));
+        }
+
+        let action = if retries > MAX_RETRIES {
+            PlayAction::PickupStack
+        } else {
+            let view = View::new(ids[seat].clone(), table.view_for(seat, &ids));
+            strategies[seat].on_turn(&view)
+        };
+        if let Some(reason) = strategies[seat].forfeit() {
+            forfeit = Some(Forfeit { seat, reason });
+            continue;
+        }
+        events.push(GameEvent::Play {
+            player: ids[seat].clone(),
+            action: action.clone(),
+        });
+
+        match table.play_action(seat, &action) {
+            Ok(Outcome::Ongoing) => retries = 0,
+            Ok(outcome) => break outcome,
+            Err(reason) => {
+                retries += 1;
+                rejections[seat] += 1;
+                let reason = reason.to_string();
+                strategies[seat].on_event(&Event::Rejected { reason });
+            }
+        }
+    };
+
+    let winner = match outcome {
+        Outcome::Won(seat) => Some(ids[seat].clone()),
+        _ => None,
+    };
+    broadcast(strategies, &Event::GameOver { winner });
+
+    GameResult {
+        outcome,
+        turns: table.turns_taken(),
+        cards_left: (0..table.seats())
+            .map(|seat| table.player(seat).cards_left())
+            .collect(),
+        rejections,
+        events,
+        forfeit,
+    }
+}
+
+fn broadcast(strategies: &mut [&mut dyn Strategy], event: &Event) {
+    for strategy in strategies.iter_mut() {
+        strategy.on_event(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::reference::{Heuristic, LowestCard, Random};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_reference_bots_only_make_legal_moves() {
+        for seed in 0..20 {
+            let mut random = Random::new(seed);
+            let mut lowest = LowestCard;
+            let mut heuristic = Heuristic;
+            let result = play_game(
+                &mut [&mut random, &mut lowest, &mut heuristic],
+                seed,
+                RuleSet::default(),
+            );
+            assert_eq!(result.rejections, [0, 0, 0], "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_positions_share_ties() {
+        let result = GameResult {
+            outcome: Outcome::Won(2),
+            turns: 0,
+            cards_left: vec![5, 3, 0, 5],
+            rejections: vec![0; 4],
+            events: vec![],
+            forfeit: None,
+        };
+        assert_eq!(result.positions(), [3.5, 2.0, 1.0, 3.5]);
+    }
+
+    #[test]
+    fn test_duplicate_deals_the_same_cards_to_every_seating() {
+        let (mut a, mut b, mut c) = (LowestCard, LowestCard, LowestCard);
+        let games = play_duplicate(&mut [&mut a, &mut b, &mut c], 5, RuleSet::default());
+        assert_eq!(games.len(), 6);
+        // the same strategy everywhere plays the same game whoever sits where
+        for game in &games {
+            assert_eq!(game.result.outcome, games[0].result.outcome);
+            assert_eq!(game.result.turns, games[0].result.turns);
+        }
+        // every seating is different, and with 3! of them that is all of them
+        let seatings: HashSet<_> = games.iter().map(|game| game.seating.clone()).collect();
+        assert_eq!(seatings.len(), (1..=3).product::<usize>());
+    }
+
+    #[test]
+    fn test_same_seed_plays_the_same_game() {
+        let play = || {
+            let mut a = Random::new(7);
+            let mut b = Random::new(8);
+            play_game(&mut [&mut a, &mut b], 3, RuleSet::default())
+        };
+        let (first, second) = (play(), play());
+        assert_eq!(first.outcome, second.outcome);
+        assert_eq!(first.turns, second.turns);
+    }
+}
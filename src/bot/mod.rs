@@ -0,0 +1,243 @@
+//! Building blocks for writing bots.
+//!
+//! A bot is a [`Strategy`] that picks actions from a [`View`] of the game. The [`runner`] plays
+//! a strategy against the server, taking care of the connection and the lobby, while [`local`]
+//! plays strategies against each other in-process. [`reference`] has some simple strategies to
+//! compare new ones with and [`ismcts`] a strong one that searches the game tree. Strategies can
+//! follow where the cards are with a [`tracker::CardTracker`]. Bots written in other languages
+//! can be run as a [`subprocess`], or compiled to WebAssembly and run in a [`wasm`] sandbox, and
+//! quick experiments can be written as a [`script`].
+
+pub mod ismcts;
+pub mod local;
+pub mod reference;
+pub mod runner;
+pub mod script;
+pub mod subprocess;
+pub mod tracker;
+pub mod wasm;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+use crate::api::server_messages::{BottomCards, GameState, Stage};
+use crate::deck::Card;
+use crate::game::{can_place_on, can_play_hidden};
+
+/// Decides what a bot does. Only `on_setup` and `on_turn` need to be implemented.
+pub trait Strategy {
+    /// Called with every state during the swap stage until the strategy returns
+    /// [`SetupAction::FinishExchange`]
+    fn on_setup(&mut self, view: &View) -> SetupAction;
+
+    /// Called whenever it is our turn during the play stage
+    fn on_turn(&mut self, view: &View) -> PlayAction;
+
+    /// Called with everything else that happens, before `on_setup` or `on_turn` are asked for
+    /// an action
+    fn on_event(&mut self, _event: &Event) {}
+
+    /// Why the strategy can't carry on playing, if it can't. Checked after every action it is
+    /// asked for, the game is then forfeited.
+    fn forfeit(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<S: Strategy + ?Sized> Strategy for Box<S> {
+    fn on_setup(&mut self, view: &View) -> SetupAction {
+        (**self).on_setup(view)
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        (**self).on_turn(view)
+    }
+
+    fn on_event(&mut self, event: &Event) {
+        (**self).on_event(event)
+    }
+
+    fn forfeit(&self) -> Option<String> {
+        (**self).forfeit()
+    }
+}
+
+/// Something that happened that a strategy may want to know about
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Event {
+    /// A game started with these players in seat order, we are `id`
+    GameStart {
+        players: Vec<String>,
+        id: String,
+    },
+    Stage(Stage),
+    /// The table as we can see it, sent after every action
+    State(GameState),
+    /// The last action we sent was rejected, we will be asked again
+    Rejected {
+        reason: String,
+    },
+    /// The game finished, `winner` is `None` if it was a draw
+    GameOver {
+        winner: Option<String>,
+    },
+    /// The game was stopped before it finished
+    GameAborted(String),
+}
+
+/// What a bot can see when it is asked for an action
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct View {
+    /// Our player id
+    pub id: String,
+    pub state: GameState,
+}
+
+impl View {
+    pub fn new(id: impl Into<String>, state: GameState) -> Self {
+        Self {
+            id: id.into(),
+            state,
+        }
+    }
+
+    pub fn hand(&self) -> &[Card] {
+        &self.state.cards.hand
+    }
+
+    /// Our visible cards, or the hidden ones once the visible cards have been played
+    pub fn bottom_cards(&self) -> &BottomCards {
+        &self.state.cards.bottom_cards
+    }
+
+    pub fn top_of_stack(&self) -> Option<&Card> {
+        self.state.stack.last()
+    }
+
+    pub fn is_our_turn(&self) -> bool {
+        self.state.stage == Stage::Play && self.state.turn == self.id
+    }
+
+    /// Whether our bottom cards are the hidden cards, which happens once the visible cards have
+    /// been played
+    pub fn playing_hidden_cards(&self) -> bool {
+        self.hand().is_empty()
+            && self
+                .state
+                .other_players
+                .iter()
+                .find(|(id, _)| *id == self.id)
+                .is_some_and(|(_, visible)| visible.is_empty())
+    }
+
+    /// Cards we could place on the stack right now
+    pub fn placeable_cards(&self) -> Vec<Card> {
+        let cards: Vec<Card> = if !self.hand().is_empty() {
+            self.hand().to_vec()
+        } else if self.playing_hidden_cards() {
+            let hidden: Vec<&Card> = self.bottom_cards().iter().flatten().collect();
+            hidden
+                .iter()
+                .filter(|card| can_play_hidden(card, hidden.len()))
+                .map(|&card| card.clone())
+                .collect()
+        } else {
+            self.bottom_cards().iter().flatten().cloned().collect()
+        };
+
+        cards
+            .into_iter()
+            .filter(|card| can_place_on(self.top_of_stack(), card))
+            .collect()
+    }
+
+    /// Every action we could take, empty if it isn't our turn
+    pub fn legal_plays(&self) -> Vec<PlayAction> {
+        if !self.is_our_turn() {
+            return vec![];
+        }
+
+        let mut actions: Vec<PlayAction> = self
+            .placeable_cards()
+            .into_iter()
+            .map(|card| PlayAction::PlaceCard { card })
+            .collect();
+        actions.push(PlayAction::PickupStack);
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::server_messages::Cards;
+    use crate::deck::Suit;
+
+    fn card(rank: u8) -> Card {
+        Card {
+            rank,
+            suit: Suit::Spade,
+        }
+    }
+
+    fn view(hand: Vec<Card>, bottom_cards: BottomCards, visible: BottomCards) -> View {
+        View::new(
+            "me",
+            GameState {
+                turn: "me".to_string(),
+                stage: Stage::Play,
+                cards: Cards { hand, bottom_cards },
+                stack: vec![card(8)],
+                other_players: vec![("me".to_string(), visible)],
+            },
+        )
+    }
+
+    #[test]
+    fn test_legal_plays_from_hand() {
+        let view = view(vec![card(5), card(9), card(10)], vec![], vec![]);
+        assert_eq!(
+            view.legal_plays(),
+            [
+                PlayAction::PlaceCard { card: card(9) },
+                PlayAction::PlaceCard { card: card(10) },
+                PlayAction::PickupStack,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_legal_plays_from_hidden_cards() {
+        let hidden = vec![vec![card(2)], vec![], vec![card(13)]];
+        let view = view(vec![], hidden, vec![]);
+        assert!(view.playing_hidden_cards());
+        assert_eq!(
+            view.legal_plays(),
+            [
+                PlayAction::PlaceCard { card: card(2) },
+                PlayAction::PlaceCard { card: card(13) },
+                PlayAction::PickupStack,
+            ]
+        );
+
+        // the last hidden card can't be an ace
+        let view = View {
+            state: GameState {
+                cards: Cards {
+                    hand: vec![],
+                    bottom_cards: vec![vec![], vec![card(14)], vec![]],
+                },
+                ..view.state
+            },
+            ..view
+        };
+        assert_eq!(view.legal_plays(), [PlayAction::PickupStack]);
+    }
+
+    #[test]
+    fn test_no_legal_plays_on_other_turns() {
+        let mut view = view(vec![card(9)], vec![], vec![]);
+        view.state.turn = "someone else".to_string();
+        assert!(view.legal_plays().is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+//! Simple strategies to measure new bots against
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use super::{Strategy, View};
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+use crate::deck::{Card, Rank};
+
+/// Plays a uniformly random legal move and never swaps cards
+pub struct Random {
+    rng: StdRng,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Strategy for Random {
+    fn on_setup(&mut self, _view: &View) -> SetupAction {
+        SetupAction::FinishExchange
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        view.legal_plays()
+            .choose(&mut self.rng)
+            .cloned()
+            .unwrap_or(PlayAction::PickupStack)
+    }
+}
+
+/// Always plays its lowest legal card and never swaps cards
+pub struct LowestCard;
+
+impl Strategy for LowestCard {
+    fn on_setup(&mut self, _view: &View) -> SetupAction {
+        SetupAction::FinishExchange
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        match view.placeable_cards().into_iter().min() {
+            Some(card) => PlayAction::PlaceCard { card },
+            None => PlayAction::PickupStack,
+        }
+    }
+}
+
+/// Swaps its strongest cards to the bottom, saves 2s and 10s for when they are needed and gets
+/// rid of ranks it holds several of first
+pub struct Heuristic;
+
+/// How useful a card is to keep, 2s and 10s can be played on anything so they are worth the most
+pub(super) fn value(rank: Rank) -> u8 {
+    match rank {
+        2 => 15,
+        10 => 16,
+        rank => rank,
+    }
+}
+
+impl Strategy for Heuristic {
+    fn on_setup(&mut self, view: &View) -> SetupAction {
+        let cards = &view.state.cards;
+        let Some(best) = cards.hand.iter().max_by_key(|card| value(card.rank)) else {
+            return SetupAction::FinishExchange;
+        };
+        let worst = cards
+            .bottom_cards
+            .iter()
+            .enumerate()
+            .filter_map(|(i, stack)| Some((i, stack.first()?)))
+            .min_by_key(|(_, card)| value(card.rank));
+
+        match worst {
+            Some((bottom, worst)) if value(best.rank) > value(worst.rank) => {
+                let hand = cards
+                    .hand
+                    .iter()
+                    .filter(|card| card.rank == best.rank)
+                    .cloned()
+                    .collect();
+                SetupAction::ExchangeCard { hand, bottom }
+            }
+            _ => SetupAction::FinishExchange,
+        }
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        let placeable = view.placeable_cards();
+        let copies = |card: &Card| view.hand().iter().filter(|c| c.rank == card.rank).count();
+        // a 2 goes on anything, so it is wasted on an empty stack
+        let saved =
+            |card: &Card| card.rank == 10 || (card.rank == 2 && view.state.stack.is_empty());
+
+        let ordinary = placeable
+            .iter()
+            .filter(|card| !saved(card))
+            // low cards first, ranks we hold several of count as a bit lower
+            .min_by_key(|card| (card.rank as usize).saturating_sub(copies(card) * 2));
+        let card = ordinary
+            // clear the stack with a 10 rather than picking it up
+            .or_else(|| placeable.iter().find(|card| card.rank == 10))
+            .or_else(|| placeable.iter().min());
+
+        match card {
+            Some(card) => PlayAction::PlaceCard { card: card.clone() },
+            None => PlayAction::PickupStack,
+        }
+    }
+}
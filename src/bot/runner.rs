@@ -0,0 +1,305 @@
+//! Plays a [`Strategy`] on the server over a websocket
+
+use std::fmt;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+
+use super::{Event, Strategy, View};
+use crate::api::player_messages::action::{LobbyAction, PlayAction, SetupAction};
+use crate::api::server_messages::{GameState, ServerNotification, Stage};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Clone, Debug)]
+pub struct RunnerConfig {
+    /// The server's queue, eg. `ws://localhost:3000/queue`
+    pub url: String,
+    /// Games to play before leaving, keeps requeueing if `None`
+    pub games: Option<usize>,
+    /// Actions the server may reject in a row before the runner falls back to one that is
+    /// always accepted, finishing the swap or picking up the stack
+    pub max_retries: usize,
+    /// Rejoins the game that was suspended by a server restart, see
+    /// [`ServerNotification::ResumeToken`]
+    pub resume_token: Option<String>,
+    /// Signs in as the player called `name`, so stats and rating carry over between
+    /// connections. The first bot to use a name claims it for `token`.
+    pub name: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Default for RunnerConfig {
+    fn default() -> Self {
+        Self {
+            url: "ws://localhost:3000/queue".to_string(),
+            games: None,
+            max_retries: 3,
+            resume_token: None,
+            name: None,
+            token: None,
+        }
+    }
+}
+
+/// How the games played by the runner went
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub games: usize,
+    pub wins: usize,
+    pub draws: usize,
+    pub aborted: usize,
+}
+
+#[derive(Debug)]
+pub enum RunnerError {
+    Connect(tungstenite::Error),
+    Socket(tungstenite::Error),
+    /// The server turned us away
+    QueueFull,
+    /// The server rejected our name or token
+    SignInFailed(String),
+    /// The strategy gave up, see [`Strategy::forfeit`]. The runner leaves the game by closing
+    /// the connection.
+    Forfeited(String),
+}
+
+impl fmt::Display for RunnerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunnerError::Connect(e) => write!(f, "Couldn't connect to the server: {e}"),
+            RunnerError::Socket(e) => write!(f, "Connection to the server failed: {e}"),
+            RunnerError::QueueFull => write!(f, "The server's queue is full"),
+            RunnerError::SignInFailed(reason) => write!(f, "Couldn't sign in: {reason}"),
+            RunnerError::Forfeited(reason) => write!(f, "The bot forfeited: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for RunnerError {}
+
+/// A message from the server
+enum Incoming {
+    Notification(ServerNotification),
+    Stage(Stage),
+    State(GameState),
+    /// Anything else, which a newer server may send
+    Unknown(String),
+}
+
+impl Incoming {
+    fn decode(text: String) -> Self {
+        if let Ok(notification) = serde_json::from_str(&text) {
+            return Incoming::Notification(notification);
+        }
+        if let Ok(stage) = serde_json::from_str(&text) {
+            return Incoming::Stage(stage);
+        }
+        if let Ok(state) = serde_json::from_str(&text) {
+            return Incoming::State(state);
+        }
+        Incoming::Unknown(text)
+    }
+}
+
+/// Joins the queue and plays games with `strategy` until `config.games` have been played or the
+/// server closes the connection.
+///
+/// # Errors
+/// If the connection fails or the server turns us away
+pub async fn run(
+    strategy: &mut impl Strategy,
+    config: &RunnerConfig,
+) -> Result<Summary, RunnerError> {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    let params = [
+        ("resume", &config.resume_token),
+        ("name", &config.name),
+        ("token", &config.token),
+    ];
+    for (key, value) in params {
+        if let Some(value) = value {
+            query.append_pair(key, value);
+        }
+    }
+    let url = match query.finish() {
+        query if query.is_empty() => config.url.clone(),
+        query => format!("{}?{query}", config.url),
+    };
+    let (mut socket, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(RunnerError::Connect)?;
+
+    let mut id = String::new();
+    let mut summary = Summary::default();
+    // rejections of our last action in a row
+    let mut retries = 0;
+    let mut rejected = false;
+
+    while let Some(text) = next_text(&mut socket).await? {
+        match Incoming::decode(text) {
+            Incoming::Notification(notification) => match notification {
+                ServerNotification::Id(our_id) => {
+                    info!(id = our_id, "Connected");
+                    id = our_id;
+                }
+                ServerNotification::ReadyCheck { .. } => {
+                    send(&mut socket, &LobbyAction::Ready).await?;
+                }
+                ServerNotification::ResumeToken { game_id, token } => {
+                    info!(game_id, token, "Resume token");
+                }
+                ServerNotification::GameStart(players) => {
+                    retries = 0;
+                    rejected = false;
+                    let id = id.clone();
+                    strategy.on_event(&Event::GameStart { players, id });
+                }
+                ServerNotification::GameAborted(reason) => {
+                    warn!(reason, "Game aborted");
+                    summary.games += 1;
+                    summary.aborted += 1;
+                    strategy.on_event(&Event::GameAborted(reason));
+                }
+                ServerNotification::PostGame { .. } => {
+                    let more = config.games.is_none_or(|games| summary.games < games);
+                    let action = if more {
+                        LobbyAction::Requeue
+                    } else {
+                        LobbyAction::Leave
+                    };
+                    send(&mut socket, &action).await?;
+                    if !more {
+                        break;
+                    }
+                }
+                ServerNotification::QueueFull => return Err(RunnerError::QueueFull),
+                ServerNotification::SignInFailed(reason) => {
+                    return Err(RunnerError::SignInFailed(reason))
+                }
+                ServerNotification::ShuttingDown { grace_secs } => {
+                    warn!(grace_secs, "Server is shutting down");
+                }
+                ServerNotification::ActionRejected(reason) => {
+                    debug!(reason, "Action rejected");
+                    retries += 1;
+                    rejected = true;
+                    strategy.on_event(&Event::Rejected { reason });
+                }
+                ServerNotification::GameOver { winner } => {
+                    info!(?winner, "Game over");
+                    summary.games += 1;
+                    match &winner {
+                        Some(winner) if *winner == id => summary.wins += 1,
+                        Some(_) => {}
+                        None => summary.draws += 1,
+                    }
+                    strategy.on_event(&Event::GameOver { winner });
+                }
+            },
+            Incoming::Stage(stage) => strategy.on_event(&Event::Stage(stage)),
+            Incoming::State(state) => {
+                if !rejected {
+                    retries = 0;
+                }
+                rejected = false;
+                strategy.on_event(&Event::State(state.clone()));
+
+                let view = View::new(id.clone(), state);
+                let give_up = retries > config.max_retries;
+                match view.state.stage {
+                    Stage::Swap => {
+                        let action = if give_up {
+                            SetupAction::FinishExchange
+                        } else {
+                            strategy.on_setup(&view)
+                        };
+                        forfeit(&mut socket, strategy).await?;
+                        debug!(?action, "Setup action");
+                        send(&mut socket, &action).await?;
+                    }
+                    Stage::Play if view.is_our_turn() => {
+                        let action = if give_up {
+                            PlayAction::PickupStack
+                        } else {
+                            strategy.on_turn(&view)
+                        };
+                        forfeit(&mut socket, strategy).await?;
+                        debug!(?action, "Play action");
+                        send(&mut socket, &action).await?;
+                    }
+                    Stage::Play => {}
+                }
+            }
+            Incoming::Unknown(text) => warn!(text, "Unknown message"),
+        }
+    }
+
+    Ok(summary)
+}
+
+async fn send(socket: &mut Socket, msg: &impl Serialize) -> Result<(), RunnerError> {
+    socket
+        .send(Message::Text(serde_json::to_string(msg).unwrap()))
+        .await
+        .map_err(RunnerError::Socket)
+}
+
+/// Leaves the game if the strategy has given up
+async fn forfeit(socket: &mut Socket, strategy: &impl Strategy) -> Result<(), RunnerError> {
+    let Some(reason) = strategy.forfeit() else {
+        return Ok(());
+    };
+    warn!(reason, "Forfeiting");
+    let _ = socket.close(None).await;
+    Err(RunnerError::Forfeited(reason))
+}
+
+/// Waits for the next text message, `None` once the connection is closed
+async fn next_text(socket: &mut Socket) -> Result<Option<String>, RunnerError> {
+    while let Some(message) = socket.next().await {
+        match message {
+            Ok(Message::Text(text)) => return Ok(Some(text)),
+            Ok(Message::Close(_)) => return Ok(None),
+            Ok(_) => continue,
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(RunnerError::Socket(e)),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifications_are_decoded() {
+        let decode = |notification| Incoming::decode(serde_json::to_string(&notification).unwrap());
+        assert!(matches!(
+            decode(ServerNotification::GameOver {
+                winner: Some("abc".to_string())
+            }),
+            Incoming::Notification(ServerNotification::GameOver { winner: Some(winner) })
+                if winner == "abc"
+        ));
+        assert!(matches!(
+            decode(ServerNotification::GameOver { winner: None }),
+            Incoming::Notification(ServerNotification::GameOver { winner: None })
+        ));
+        assert!(matches!(
+            decode(ServerNotification::ActionRejected("Not your turn".to_string())),
+            Incoming::Notification(ServerNotification::ActionRejected(reason))
+                if reason == "Not your turn"
+        ));
+        assert!(matches!(
+            Incoming::decode("Game finished".to_string()),
+            Incoming::Unknown(_)
+        ));
+    }
+}
@@ -0,0 +1,307 @@
+//! Plays a [Rhai](https://rhai.rs) script as a [`Strategy`].
+//!
+//! The script defines `play(view)`, returning the action to take on our turn, and optionally
+//! `setup(view)`, returning a swap or `finish()`. It is reloaded whenever the file changes, so
+//! a bot can be tweaked while it is playing. The view has:
+//!
+//! - `id`, our player id
+//! - `hand`, `stack` and `bottom`, arrays of cards with `rank` and `suit`. `bottom` is our
+//!   visible cards, or the hidden ones once they have been played.
+//! - `top`, the top of the stack or `()`
+//! - `legal`, every action we could take
+//! - `opponents`, a map from each other player's id to their visible cards
+//!
+//! Actions are made with `place(card)`, `pickup()`, `swap(hand_cards, bottom_index)` and
+//! `finish()`, and returning a card places it. See `client/lowest_card.rhai` for an example.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use tracing::{info, warn};
+
+use super::{Strategy, View};
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+use crate::deck::Card;
+
+/// Operations a script may run per call before it is stopped, so a loop can't hang the game
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// A script being played as a [`Strategy`]. If it fails to compile or run the error is logged
+/// and finishing the swap or picking up the stack is played instead, until it is fixed.
+pub struct ScriptBot {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    /// The modification time and length of the file when it was last read. It is only read
+    /// again once either changes.
+    stamp: Option<(SystemTime, u64)>,
+    /// What the file held when it was last read. A touched file that is still the same isn't
+    /// compiled again.
+    source: String,
+}
+
+impl ScriptBot {
+    /// Compiles the script at `path`
+    ///
+    /// # Errors
+    /// If the script can't be read or doesn't compile
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, Box<EvalAltResult>> {
+        let path = path.into();
+        let engine = engine();
+        let stamp = stamp(&path);
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+        let ast = engine.compile(&source)?;
+        Ok(Self {
+            path,
+            engine,
+            ast,
+            stamp,
+            source,
+        })
+    }
+
+    /// Loads the script again if the file has changed, keeping the old one if it doesn't compile
+    fn reload(&mut self) {
+        let stamp = stamp(&self.path);
+        if stamp.is_some() && stamp == self.stamp {
+            return;
+        }
+        let source = match std::fs::read_to_string(&self.path) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!(path = %self.path.display(), "Couldn't read script: {e}");
+                return;
+            }
+        };
+        self.stamp = stamp;
+        if source == self.source {
+            return;
+        }
+        let compiled = self.engine.compile(&source);
+        // a broken script is only reported once, not on every call until it is fixed
+        self.source = source;
+        match compiled {
+            Ok(ast) => {
+                info!(path = %self.path.display(), "Reloaded script");
+                self.ast = ast;
+            }
+            Err(e) => warn!(path = %self.path.display(), "Couldn't reload script: {e}"),
+        }
+    }
+
+    fn has_fn(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name)
+    }
+
+    fn call(&mut self, name: &str, view: &View) -> Option<Dynamic> {
+        let result = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, name, (view.clone(),));
+        match result {
+            Ok(result) => Some(result),
+            Err(e) => {
+                warn!(path = %self.path.display(), "{name} failed: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// The modification time and length of the file at `path`, `None` if they can't be read
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn cards(cards: &[Card]) -> Array {
+    cards.iter().cloned().map(Dynamic::from).collect()
+}
+
+/// An engine with the view, cards and actions registered
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    engine
+        .register_type_with_name::<Card>("Card")
+        .register_get("rank", |card: &mut Card| i64::from(card.rank))
+        .register_get("suit", |card: &mut Card| format!("{:?}", card.suit))
+        .register_fn("==", |a: &mut Card, b: Card| *a == b)
+        .register_fn("!=", |a: &mut Card, b: Card| *a != b)
+        .register_fn("to_string", |card: &mut Card| {
+            format!("{} of {:?}", card.rank, card.suit)
+        });
+
+    engine
+        .register_type_with_name::<PlayAction>("PlayAction")
+        .register_fn("place", |card: Card| PlayAction::PlaceCard { card })
+        .register_fn("pickup", || PlayAction::PickupStack)
+        .register_get("card", |action: &mut PlayAction| match action {
+            PlayAction::PlaceCard { card } => Dynamic::from(card.clone()),
+            PlayAction::PickupStack => Dynamic::UNIT,
+        })
+        .register_fn("to_string", |action: &mut PlayAction| format!("{action:?}"));
+
+    engine
+        .register_type_with_name::<SetupAction>("SetupAction")
+        .register_fn("finish", || SetupAction::FinishExchange)
+        .register_fn("swap", |hand: Array, bottom: i64| {
+            let hand = hand.into_iter().filter_map(Dynamic::try_cast).collect();
+            let bottom = usize::try_from(bottom).unwrap_or(usize::MAX);
+            SetupAction::ExchangeCard { hand, bottom }
+        })
+        .register_fn("to_string", |action: &mut SetupAction| {
+            format!("{action:?}")
+        });
+
+    engine
+        .register_type_with_name::<View>("View")
+        .register_get("id", |view: &mut View| view.id.clone())
+        .register_get("hand", |view: &mut View| cards(view.hand()))
+        .register_get("stack", |view: &mut View| cards(&view.state.stack))
+        .register_get("bottom", |view: &mut View| {
+            let bottom: Vec<Card> = view.bottom_cards().iter().flatten().cloned().collect();
+            cards(&bottom)
+        })
+        .register_get("top", |view: &mut View| {
+            view.top_of_stack()
+                .map_or(Dynamic::UNIT, |card| Dynamic::from(card.clone()))
+        })
+        .register_get("legal", |view: &mut View| {
+            let legal = view.legal_plays();
+            legal.into_iter().map(Dynamic::from).collect::<Array>()
+        })
+        .register_get("opponents", |view: &mut View| {
+            let mut opponents = Map::new();
+            for (id, visible) in &view.state.other_players {
+                if *id != view.id {
+                    let visible: Vec<Card> = visible.iter().flatten().cloned().collect();
+                    opponents.insert(id.into(), Dynamic::from(cards(&visible)));
+                }
+            }
+            opponents
+        });
+
+    engine
+}
+
+impl Strategy for ScriptBot {
+    fn on_setup(&mut self, view: &View) -> SetupAction {
+        // setup may have just been added or removed
+        self.reload();
+        if !self.has_fn("setup") {
+            return SetupAction::FinishExchange;
+        }
+        self.call("setup", view)
+            .and_then(Dynamic::try_cast)
+            .unwrap_or(SetupAction::FinishExchange)
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        self.reload();
+        let Some(action) = self.call("play", view) else {
+            return PlayAction::PickupStack;
+        };
+        if action.is::<Card>() {
+            let card = action.cast();
+            return PlayAction::PlaceCard { card };
+        }
+        action.try_cast().unwrap_or_else(|| {
+            warn!(path = %self.path.display(), "play didn't return an action");
+            PlayAction::PickupStack
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::local::play_game;
+    use crate::bot::reference::Heuristic;
+    use crate::game::RuleSet;
+
+    fn script_file(name: &str, source: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.rhai", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_example_script_only_makes_legal_moves() {
+        let path = script_file("lowest", include_str!("../../client/lowest_card.rhai"));
+        let mut bot = ScriptBot::load(&path).unwrap();
+        for seed in 0..5 {
+            let mut heuristic = Heuristic;
+            let result = play_game(&mut [&mut bot, &mut heuristic], seed, RuleSet::default());
+            assert_eq!(result.rejections, [0, 0], "seed {seed}");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn view() -> View {
+        View::new(
+            "me",
+            serde_json::from_str(
+                r#"{"turn":"me","stage":{"Stage":"Play"},"stack":[],"other_players":[],
+                "cards":{"state":"Cards","hand":[{"rank":5,"suit":"Heart"}],"bottom_cards":[]}}"#,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_script_is_reloaded() {
+        let path = script_file("reload", "fn play(view) { pickup() }");
+        let mut bot = ScriptBot::load(&path).unwrap();
+        let view = view();
+        assert_eq!(bot.on_turn(&view), PlayAction::PickupStack);
+
+        // rewritten straight away, so only the length may have changed
+        std::fs::write(&path, "fn play(view) { view.hand[0] }").unwrap();
+        let card = view.hand()[0].clone();
+        assert_eq!(bot.on_turn(&view), PlayAction::PlaceCard { card });
+
+        // a script that doesn't compile leaves the last one playing
+        std::fs::write(&path, "fn play(view) {").unwrap();
+        assert!(matches!(bot.on_turn(&view), PlayAction::PlaceCard { .. }));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_is_only_read_again_once_it_changes() {
+        let path = script_file("stamp", "fn play(view) { pickup()     }");
+        let mut bot = ScriptBot::load(&path).unwrap();
+        let view = view();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        // the same length and modification time aren't seen as a change
+        std::fs::write(&path, "fn play(view) { view.hand[0] }").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        assert_eq!(bot.on_turn(&view), PlayAction::PickupStack);
+
+        file.set_modified(modified + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_ne!(bot.on_turn(&view), PlayAction::PickupStack);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_setup_added_by_a_reload_is_called() {
+        let path = script_file("setup", "fn play(view) { pickup() }");
+        let mut bot = ScriptBot::load(&path).unwrap();
+        let view = view();
+        assert_eq!(bot.on_setup(&view), SetupAction::FinishExchange);
+
+        let source = "fn setup(view) { swap([], 0) }\nfn play(view) { pickup() }";
+        std::fs::write(&path, source).unwrap();
+        let swap = SetupAction::ExchangeCard {
+            hand: vec![],
+            bottom: 0,
+        };
+        assert_eq!(bot.on_setup(&view), swap);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,588 @@
+//! Runs a bot executable as a [`Strategy`], talking to it with JSON over stdin and stdout.
+//!
+//! Every message is a single line of JSON. The runner starts by sending
+//! [`Request::Hello`] and the bot answers with a [`Greeting`]. After that the runner sends a
+//! [`Request`] for everything that happens in a game:
+//!
+//! - `{"Event": ...}` tells the bot about an [`Event`], no answer is expected
+//! - `{"Setup": {"id": ..., "state": ...}}` asks for a [`SetupAction`]
+//! - `{"Turn": {"id": ..., "state": ...}}` asks for a [`PlayAction`]
+//! - `"Quit"` is sent before the runner stops the bot
+//!
+//! Actions are answered with the same JSON the server accepts over the websocket, eg.
+//! `"FinishExchange"` or `{"PlaceCard":{"card":{"rank":5,"suit":"Heart"}}}`. Anything the bot
+//! wants to log should go to stderr, which is passed through. A bot that can't go on may send a
+//! [`Failure`] instead of an answer, eg. `"OutOfMemory"`.
+//!
+//! A bot can be given [`Limits`] on the time and memory it uses. A bot that goes over one, crashes
+//! or otherwise stops playing forfeits the game it is in.
+//!
+//! As it is a [`Strategy`] a subprocess bot can play in-process with [`local`](super::local)
+//! or on the server with [`runner`](super::runner).
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use super::{Event, Strategy, View};
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+
+/// Bumped whenever a message changes in a way old bots wouldn't understand
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How long a bot has to exit after being told to quit before it is killed
+const QUIT_POLLS: u32 = 10;
+const QUIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A line sent to the bot
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Request {
+    /// The first line sent, answered with a [`Greeting`]
+    Hello {
+        protocol: u32,
+    },
+    Event(Event),
+    /// Answered with a [`SetupAction`]
+    Setup(View),
+    /// Answered with a [`PlayAction`]
+    Turn(View),
+    Quit,
+}
+
+/// The bot's answer to [`Request::Hello`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Greeting {
+    pub name: String,
+}
+
+/// Sent by the bot in place of an answer when it can't go on
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// An allocation failed, usually because of [`Limits::memory`]
+    OutOfMemory,
+}
+
+/// What a bot may use. The CPU time and memory are enforced by the OS and only on unix.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// CPU time for the whole life of the bot. It is sent `SIGXCPU` once it runs out, and killed
+    /// a second later if it is still running, which is seen as a crash.
+    pub cpu_time: Option<Duration>,
+    /// Bytes of memory the bot may map, allocating more fails. The bot has only run out if it
+    /// says so with [`Failure::OutOfMemory`], as a runtime that aborts on a failed allocation
+    /// can't be told apart from any other crash.
+    pub memory: Option<u64>,
+    /// How long the bot has to answer each request
+    pub move_time: Option<Duration>,
+    /// Longest line the bot may send, in bytes
+    pub max_output: Option<usize>,
+}
+
+/// The limit a bot went over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    CpuTime,
+    /// Only detected when the bot reports it, see [`Limits::memory`]
+    Memory,
+    MoveTime,
+    Output,
+}
+
+#[derive(Debug)]
+pub enum SubprocessError {
+    Spawn(io::Error),
+    Io(io::Error),
+    /// The bot closed its stdout, usually because it exited
+    Exited,
+    /// The bot was killed by this signal, other than for going over [`Limits::cpu_time`]
+    Crashed(i32),
+    /// The bot sent a line that isn't the answer that was asked for
+    BadReply(String),
+    LimitExceeded(Limit),
+}
+
+impl fmt::Display for SubprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubprocessError::Spawn(e) => write!(f, "Couldn't start the bot: {e}"),
+            SubprocessError::Io(e) => write!(f, "Couldn't talk to the bot: {e}"),
+            SubprocessError::Exited => write!(f, "The bot exited"),
+            SubprocessError::Crashed(signal) => match signal_name(*signal) {
+                Some(name) => write!(f, "The bot crashed with {name}"),
+                None => write!(f, "The bot was killed by signal {signal}"),
+            },
+            SubprocessError::BadReply(line) => write!(f, "The bot sent an invalid reply: {line}"),
+            SubprocessError::LimitExceeded(Limit::CpuTime) => {
+                write!(f, "The bot ran out of CPU time")
+            }
+            SubprocessError::LimitExceeded(Limit::Memory) => {
+                write!(f, "The bot ran out of memory")
+            }
+            SubprocessError::LimitExceeded(Limit::MoveTime) => {
+                write!(f, "The bot took too long to answer")
+            }
+            SubprocessError::LimitExceeded(Limit::Output) => {
+                write!(f, "The bot sent a line that is too long")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubprocessError {}
+
+/// A bot executable being played as a [`Strategy`].
+///
+/// If the bot exits, sends something invalid or goes over its [`Limits`] it forfeits the game.
+/// It is no longer asked for anything, and the actions that are always accepted are played for
+/// it in case the game carries on: finishing the swap and picking up the stack.
+pub struct Subprocess {
+    name: String,
+    limits: Limits,
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines read from the bot's stdout by a separate thread, so waiting for them can time out
+    lines: Receiver<Result<String, SubprocessError>>,
+    /// The first thing that went wrong
+    error: Option<SubprocessError>,
+}
+
+impl Subprocess {
+    /// Starts the bot and waits for it to greet us
+    ///
+    /// # Errors
+    /// If the bot can't be started or doesn't answer the hello
+    pub fn spawn(command: &mut Command, limits: Limits) -> Result<Self, SubprocessError> {
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        #[cfg(unix)]
+        os::set_rlimits(command, &limits);
+        let mut child = command.spawn().map_err(SubprocessError::Spawn)?;
+        let stdin = child.stdin.take().expect("stdin should be piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout should be piped"));
+
+        let (sender, lines) = mpsc::sync_channel(1);
+        let max_output = limits.max_output;
+        std::thread::spawn(move || read_lines(stdout, max_output, |line| sender.send(line)));
+
+        let mut bot = Self {
+            name: String::new(),
+            limits,
+            child,
+            stdin,
+            lines,
+            error: None,
+        };
+        let greeting: Greeting = bot.ask(&Request::Hello {
+            protocol: PROTOCOL_VERSION,
+        })?;
+        bot.name = greeting.name;
+        Ok(bot)
+    }
+
+    /// Starts a bot from a command line, eg. `python3 bot.py --fast`. Arguments are split on
+    /// whitespace.
+    ///
+    /// # Errors
+    /// If the command line is empty, or as [`Subprocess::spawn`]
+    pub fn from_command_line(line: &str, limits: Limits) -> Result<Self, SubprocessError> {
+        let mut parts = line.split_whitespace();
+        let Some(program) = parts.next() else {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "The command is empty");
+            return Err(SubprocessError::Spawn(e));
+        };
+        Self::spawn(Command::new(program).args(parts), limits)
+    }
+
+    /// The name the bot greeted us with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// What went wrong with the bot, if anything
+    pub fn error(&self) -> Option<&SubprocessError> {
+        self.error.as_ref()
+    }
+
+    fn send(&mut self, request: &Request) -> Result<(), SubprocessError> {
+        let line = serde_json::to_string(request).unwrap();
+        writeln!(self.stdin, "{line}")
+            .and_then(|()| self.stdin.flush())
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::BrokenPipe => SubprocessError::Exited,
+                _ => SubprocessError::Io(e),
+            })
+    }
+
+    fn ask<T: DeserializeOwned>(&mut self, request: &Request) -> Result<T, SubprocessError> {
+        self.send(request)?;
+        let line = match self.limits.move_time {
+            Some(limit) => self.lines.recv_timeout(limit).map_err(|e| match e {
+                RecvTimeoutError::Timeout => SubprocessError::LimitExceeded(Limit::MoveTime),
+                RecvTimeoutError::Disconnected => SubprocessError::Exited,
+            }),
+            None => self.lines.recv().map_err(|_| SubprocessError::Exited),
+        };
+        let line = line.and_then(|line| line)?;
+        if let Ok(answer) = serde_json::from_str(line.trim()) {
+            return Ok(answer);
+        }
+        match serde_json::from_str(line.trim()) {
+            Ok(Failure::OutOfMemory) => Err(SubprocessError::LimitExceeded(Limit::Memory)),
+            Err(_) => Err(SubprocessError::BadReply(line)),
+        }
+    }
+
+    /// Remembers what went wrong, the bot won't be asked for anything else
+    fn fail(&mut self, e: SubprocessError) {
+        // the bot exiting may be it being killed
+        let e = match e {
+            SubprocessError::Exited => match self.wait().and_then(killed_by) {
+                Some(signal) if self.limits.cpu_time.is_some() && out_of_cpu_time(signal) => {
+                    SubprocessError::LimitExceeded(Limit::CpuTime)
+                }
+                Some(signal) => SubprocessError::Crashed(signal),
+                None => SubprocessError::Exited,
+            },
+            e => e,
+        };
+        warn!(bot = self.name, "{e}");
+        self.error = Some(e);
+        // it may be stuck in a loop, and won't be asked for anything else anyway
+        let _ = self.child.kill();
+    }
+
+    /// Waits a moment for the bot to exit
+    fn wait(&mut self) -> Option<ExitStatus> {
+        for _ in 0..QUIT_POLLS {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Some(status);
+            }
+            std::thread::sleep(QUIT_POLL_INTERVAL);
+        }
+        None
+    }
+
+    /// Asks the bot for an action, falling back to `fallback` once it has failed
+    fn action<T: DeserializeOwned>(&mut self, request: &Request, fallback: T) -> T {
+        if self.error.is_some() {
+            return fallback;
+        }
+        match self.ask(request) {
+            Ok(action) => action,
+            Err(e) => {
+                self.fail(e);
+                fallback
+            }
+        }
+    }
+}
+
+/// Reads `stdout` a line at a time and passes them on until the bot exits, goes over
+/// `max_output` or `send` fails
+fn read_lines<E>(
+    mut stdout: impl BufRead,
+    max_output: Option<usize>,
+    mut send: impl FnMut(Result<String, SubprocessError>) -> Result<(), E>,
+) {
+    let limit = max_output.map_or(u64::MAX, |max| max as u64 + 1);
+    loop {
+        let mut line = vec![];
+        let read = match (&mut stdout).take(limit).read_until(b'\n', &mut line) {
+            Ok(0) => Err(SubprocessError::Exited),
+            Ok(read) if read as u64 == limit && !line.ends_with(b"\n") => {
+                Err(SubprocessError::LimitExceeded(Limit::Output))
+            }
+            Ok(_) => String::from_utf8(line).map_err(|e| SubprocessError::BadReply(e.to_string())),
+            Err(e) => Err(SubprocessError::Io(e)),
+        };
+        let stop = read.is_err();
+        if send(read).is_err() || stop {
+            return;
+        }
+    }
+}
+
+/// The signal the bot was killed by, if it was
+#[cfg(unix)]
+fn killed_by(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn killed_by(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Whether `signal` is the one sent when the bot's CPU time runs out
+#[cfg(unix)]
+fn out_of_cpu_time(signal: i32) -> bool {
+    signal == libc::SIGXCPU
+}
+
+#[cfg(not(unix))]
+fn out_of_cpu_time(_signal: i32) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    match signal {
+        libc::SIGKILL => Some("SIGKILL"),
+        libc::SIGSEGV => Some("SIGSEGV"),
+        libc::SIGABRT => Some("SIGABRT"),
+        libc::SIGBUS => Some("SIGBUS"),
+        libc::SIGXCPU => Some("SIGXCPU"),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn signal_name(_signal: i32) -> Option<&'static str> {
+    None
+}
+
+#[cfg(unix)]
+mod os {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    use super::Limits;
+
+    /// Sets the limits the OS enforces on the bot once it has been started
+    pub(super) fn set_rlimits(command: &mut Command, limits: &Limits) {
+        let cpu = limits
+            .cpu_time
+            .map(|time| time.as_secs_f64().ceil() as libc::rlim_t);
+        let memory = limits.memory.map(|bytes| bytes as libc::rlim_t);
+        if cpu.is_none() && memory.is_none() {
+            return;
+        }
+        // SAFETY: only setrlimit is called between fork and exec, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(secs) = cpu {
+                    // a hard limit above the soft one lets the bot be told before it is killed
+                    let limit = rlimit(secs.max(1), secs.max(1) + 1);
+                    check(libc::setrlimit(libc::RLIMIT_CPU, &limit))?;
+                }
+                if let Some(bytes) = memory {
+                    check(libc::setrlimit(libc::RLIMIT_AS, &rlimit(bytes, bytes)))?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    fn rlimit(soft: libc::rlim_t, hard: libc::rlim_t) -> libc::rlimit {
+        libc::rlimit {
+            rlim_cur: soft,
+            rlim_max: hard,
+        }
+    }
+
+    fn check(result: libc::c_int) -> std::io::Result<()> {
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+}
+
+impl Strategy for Subprocess {
+    fn on_setup(&mut self, view: &View) -> SetupAction {
+        let request = Request::Setup(view.clone());
+        self.action(&request, SetupAction::FinishExchange)
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        let request = Request::Turn(view.clone());
+        self.action(&request, PlayAction::PickupStack)
+    }
+
+    fn forfeit(&self) -> Option<String> {
+        self.error.as_ref().map(ToString::to_string)
+    }
+
+    fn on_event(&mut self, event: &Event) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.send(&Request::Event(event.clone())) {
+            self.fail(e);
+        }
+    }
+}
+
+impl Drop for Subprocess {
+    fn drop(&mut self) {
+        let _ = self.send(&Request::Quit);
+        // give the bot a moment to exit by itself
+        if self.wait().is_some() {
+            return;
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::bot::local::play_game;
+    use crate::bot::reference::LowestCard;
+    use crate::game::{Outcome, RuleSet};
+
+    /// A bot that never swaps and always picks up the stack
+    const SHELL_BOT: &str = r#"
+        read hello
+        echo '{"name":"picker"}'
+        while read line; do
+            case "$line" in
+                '{"Setup"'*) echo '"FinishExchange"' ;;
+                '{"Turn"'*) echo '"PickupStack"' ;;
+                '"Quit"') exit ;;
+            esac
+        done
+    "#;
+
+    #[test]
+    fn test_subprocess_bot_plays_a_game() {
+        let mut command = Command::new("sh");
+        command.args(["-c", SHELL_BOT]);
+        let mut bot = Subprocess::spawn(&mut command, Limits::default()).unwrap();
+        assert_eq!(bot.name(), "picker");
+
+        let mut lowest = LowestCard;
+        let result = play_game(&mut [&mut bot, &mut lowest], 1, RuleSet::default());
+        assert_eq!(result.rejections, [0, 0]);
+        assert!(bot.error().is_none());
+    }
+
+    fn forfeit(script: &str, limits: Limits) -> SubprocessError {
+        let script = format!("read hello; echo '{{\"name\":\"bad\"}}'; {script}");
+        let mut bot = Subprocess::spawn(Command::new("sh").args(["-c", &script]), limits).unwrap();
+
+        let mut lowest = LowestCard;
+        let result = play_game(&mut [&mut bot, &mut lowest], 1, RuleSet::default());
+        assert_eq!(result.outcome, Outcome::Won(1));
+        assert_eq!(result.forfeit.unwrap().seat, 0);
+        bot.error.take().unwrap()
+    }
+
+    #[test]
+    fn test_bad_replies_forfeit() {
+        let e = forfeit("echo nonsense; cat > /dev/null", Limits::default());
+        assert!(matches!(e, SubprocessError::BadReply(_)));
+    }
+
+    #[test]
+    fn test_going_over_limits_forfeits() {
+        let limits = Limits {
+            move_time: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let e = forfeit("sleep 10", limits);
+        assert!(matches!(e, SubprocessError::LimitExceeded(Limit::MoveTime)));
+
+        let limits = Limits {
+            cpu_time: Some(Duration::from_secs(1)),
+            ..Limits::default()
+        };
+        let e = forfeit("while :; do :; done", limits);
+        assert!(matches!(e, SubprocessError::LimitExceeded(Limit::CpuTime)));
+    }
+
+    #[test]
+    fn test_crashes_are_reported_with_the_signal() {
+        let e = forfeit("kill -SEGV $$", Limits::default());
+        assert!(matches!(e, SubprocessError::Crashed(libc::SIGSEGV)));
+        assert_eq!(e.to_string(), "The bot crashed with SIGSEGV");
+
+        // aborting isn't taken to be running out of memory
+        let limits = Limits {
+            memory: Some(512 << 20),
+            cpu_time: Some(Duration::from_secs(10)),
+            ..Limits::default()
+        };
+        let e = forfeit("kill -ABRT $$", limits);
+        assert!(matches!(e, SubprocessError::Crashed(libc::SIGABRT)));
+    }
+
+    /// Set for the test binary when it is run as [`memory_hog_bot`]
+    const MEMORY_HOG: &str = "SKITGUBBE_MEMORY_HOG";
+
+    /// Allocates until it runs out and reports it on fd 3, when this test binary is started as a
+    /// bot by `test_running_out_of_memory_forfeits`. Does nothing otherwise.
+    #[test]
+    #[ignore = "only run as a bot by test_running_out_of_memory_forfeits"]
+    fn memory_hog_bot() {
+        use std::os::fd::FromRawFd;
+
+        if std::env::var_os(MEMORY_HOG).is_none() {
+            return;
+        }
+        let mut hoard = Vec::with_capacity(1024);
+        loop {
+            let mut chunk = Vec::new();
+            if chunk.try_reserve_exact(16 << 20).is_err() {
+                // SAFETY: the script starting the bot opens fd 3 and nothing else uses it
+                let mut bot_stdout = unsafe { std::fs::File::from_raw_fd(3) };
+                writeln!(bot_stdout, "\"OutOfMemory\"").unwrap();
+                return;
+            }
+            chunk.resize(16 << 20, 1u8);
+            hoard.push(chunk);
+        }
+    }
+
+    #[test]
+    fn test_running_out_of_memory_forfeits() {
+        let limits = Limits {
+            memory: Some(512 << 20),
+            ..Limits::default()
+        };
+        // the shell greets for it, as the test harness writes to stdout. Our end of stdout is
+        // kept open on fd 3 so the bot is only seen to exit once it has died.
+        let script = r#"read hello; echo '{"name":"hog"}'
+            exec "$0" bot::subprocess::tests::memory_hog_bot --exact --ignored 3>&1 > /dev/null 2>&1"#;
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", script])
+            .arg(std::env::current_exe().unwrap())
+            .env(MEMORY_HOG, "1");
+        let mut bot = Subprocess::spawn(&mut command, limits).unwrap();
+
+        let mut lowest = LowestCard;
+        let result = play_game(&mut [&mut bot, &mut lowest], 1, RuleSet::default());
+        assert_eq!(result.forfeit.unwrap().seat, 0);
+        assert!(matches!(
+            bot.error,
+            Some(SubprocessError::LimitExceeded(Limit::Memory))
+        ));
+    }
+
+    #[test]
+    fn test_long_lines_are_cut_off() {
+        let mut lines = vec![];
+        let stdout = "short\nmuch too long\n".as_bytes();
+        read_lines(stdout, Some(6), |line| {
+            lines.push(line);
+            Ok::<(), ()>(())
+        });
+        assert!(matches!(&lines[0], Ok(line) if line == "short\n"));
+        assert!(matches!(
+            lines[1],
+            Err(SubprocessError::LimitExceeded(Limit::Output))
+        ));
+        assert_eq!(lines.len(), 2);
+    }
+}
@@ -0,0 +1,605 @@
+//! Works out where the cards are from what a player is shown during a game
+
+use rand::RngCore;
+
+use super::Event;
+use crate::api::server_messages::{GameState, Stage};
+use crate::deck::{Card, CardIndex, CardSet, Deck};
+use crate::game::{PlayerCards, RuleSet, Table};
+
+/// Where a card can be. Seats are the index of the player in
+/// [`GameState::other_players`](crate::api::server_messages::GameState::other_players).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Hand(usize),
+    Visible(usize),
+    Hidden(usize),
+    Stack,
+    Deck,
+    /// Cleared from the stack by a 10 or four of a kind
+    Burned,
+}
+
+/// What we know about a seat's cards
+#[derive(Clone, Debug, Default)]
+struct SeatCards {
+    hand_len: usize,
+    hidden_left: usize,
+    /// Cards in their hand that we saw them pick up from the stack
+    known_hand: Vec<Card>,
+}
+
+/// Follows a game from the events a [`Strategy`](super::Strategy) is given, working out what
+/// every player played, picked up and drew.
+///
+/// Cards we have seen are known exactly, including the ones an opponent picked up from the
+/// stack until they play them. Every other card could be in any of the places we can't see,
+/// see [`CardTracker::locate`].
+///
+/// The server doesn't tell players how many cards the others hold, so the tracker counts them
+/// from the start of the play stage. The counts are exact unless someone compounded cards, as
+/// we can't see how many they drew afterwards.
+#[derive(Clone, Debug)]
+pub struct CardTracker {
+    rules: RuleSet,
+    id: String,
+    seats: Vec<SeatCards>,
+    deck_len: usize,
+    burned: Vec<Card>,
+    /// Cards that were cleared from the stack without us seeing what they were
+    burned_unknown: usize,
+    last: Option<GameState>,
+}
+
+impl CardTracker {
+    /// `rules` must be the ones the game is played with, as the server doesn't send them
+    pub fn new(rules: RuleSet) -> Self {
+        Self {
+            rules,
+            id: String::new(),
+            seats: vec![],
+            deck_len: 0,
+            burned: vec![],
+            burned_unknown: 0,
+            last: None,
+        }
+    }
+
+    /// Should be called with every event, from [`Strategy::on_event`](super::Strategy::on_event)
+    pub fn observe(&mut self, event: &Event) {
+        match event {
+            Event::GameStart { id, .. } => {
+                *self = Self::new(self.rules.clone());
+                self.id = id.clone();
+            }
+            Event::State(state) if state.stage == Stage::Play => self.update(state),
+            _ => {}
+        }
+    }
+
+    /// Our seat, `None` until the play stage
+    pub fn seat(&self) -> Option<usize> {
+        let state = self.last.as_ref()?;
+        state
+            .other_players
+            .iter()
+            .position(|(id, _)| *id == self.id)
+    }
+
+    /// Number of players, 0 until the play stage
+    pub fn seats(&self) -> usize {
+        self.seats.len()
+    }
+
+    /// Cards in `seat`'s hand. Like the other per seat counts it is `None` until the play stage,
+    /// or if there is no such seat.
+    pub fn hand_len(&self, seat: usize) -> Option<usize> {
+        self.seats.get(seat).map(|info| info.hand_len)
+    }
+
+    /// Hidden cards `seat` has not played yet
+    pub fn hidden_left(&self, seat: usize) -> Option<usize> {
+        self.seats.get(seat).map(|info| info.hidden_left)
+    }
+
+    /// Cards we know are in `seat`'s hand, all of them for our own seat
+    pub fn known_hand(&self, seat: usize) -> Option<&[Card]> {
+        let info = self.seats.get(seat)?;
+        match &self.last {
+            Some(state) if Some(seat) == self.seat() => Some(&state.cards.hand),
+            _ => Some(&info.known_hand),
+        }
+    }
+
+    /// Cards left to draw
+    pub fn deck_len(&self) -> usize {
+        self.deck_len
+    }
+
+    /// Cards we saw being cleared from the stack
+    pub fn burned(&self) -> &[Card] {
+        &self.burned
+    }
+
+    /// Every card whose location we don't know, lowest first
+    pub fn unseen(&self) -> Vec<Card> {
+        (!self.known_cards()).to_cards()
+    }
+
+    fn known_cards(&self) -> CardSet {
+        self.known_locations()
+            .iter()
+            .map(|(card, _)| card)
+            .collect()
+    }
+
+    /// Where `card` could be and how likely each place is. A card we have seen is in one place
+    /// for certain, any other card is as likely to be in any of the places we can't see.
+    pub fn locate(&self, card: &Card) -> Vec<(Location, f64)> {
+        if let Some((_, location)) = self
+            .known_locations()
+            .into_iter()
+            .find(|(known, _)| known == card)
+        {
+            return vec![(location, 1.0)];
+        }
+
+        let slots = self.unseen_slots();
+        let total: usize = slots.iter().map(|(_, n)| n).sum();
+        slots
+            .into_iter()
+            .filter(|&(_, n)| n > 0)
+            .map(|(location, n)| (location, n as f64 / total as f64))
+            .collect()
+    }
+
+    /// Deals the cards we can't see at random, keeping the ones we know about where they are.
+    /// `None` until the play stage.
+    pub fn determinize(&self, rng: &mut impl RngCore) -> Option<Table> {
+        let state = self.last.as_ref()?;
+        let us = self.seat()?;
+        let known = self.known_cards();
+        let mut unseen = Deck::from_seed(rng.next_u64())
+            .cards
+            .into_iter()
+            .filter(|card| !known.contains(CardIndex::from(card)));
+        let mut deal = |n: usize| -> Vec<Card> { unseen.by_ref().take(n).collect() };
+
+        let players = state
+            .other_players
+            .iter()
+            .enumerate()
+            .map(|(seat, (_, visible))| {
+                let info = &self.seats[seat];
+                let (hand, hidden) = if seat == us {
+                    let hidden = if visible.is_empty() {
+                        state.cards.bottom_cards.iter().flatten().cloned().collect()
+                    } else {
+                        deal(info.hidden_left)
+                    };
+                    (state.cards.hand.clone(), hidden)
+                } else {
+                    let mut hand = info.known_hand.clone();
+                    hand.extend(deal(info.hand_len.saturating_sub(hand.len())));
+                    hand.sort();
+                    (hand, deal(info.hidden_left))
+                };
+                let hidden = core::array::from_fn(|i| hidden.get(i).cloned());
+                PlayerCards::new(hand, visible.clone(), hidden)
+            })
+            .collect();
+        // whatever is left over was burned without us seeing it
+        let deck = Deck {
+            cards: deal(self.deck_len),
+        };
+
+        let turn = state
+            .other_players
+            .iter()
+            .position(|(id, _)| *id == state.turn)
+            .unwrap_or(us);
+        Some(Table::in_play(
+            players,
+            deck,
+            state.stack.clone(),
+            turn,
+            self.rules.clone(),
+        ))
+    }
+
+    /// Every card we know the location of
+    fn known_locations(&self) -> Vec<(Card, Location)> {
+        let Some(state) = &self.last else {
+            return vec![];
+        };
+        let us = self.seat();
+        let mut known: Vec<(Card, Location)> = vec![];
+        let mut add = |cards: &mut dyn Iterator<Item = &Card>, location: Location| {
+            known.extend(cards.map(|card| (card.clone(), location)));
+        };
+
+        for (seat, (_, visible)) in state.other_players.iter().enumerate() {
+            add(&mut visible.iter().flatten(), Location::Visible(seat));
+            if Some(seat) == us {
+                add(&mut state.cards.hand.iter(), Location::Hand(seat));
+                // our hidden cards are shown to us once the visible ones have been played
+                if visible.is_empty() {
+                    let hidden = &mut state.cards.bottom_cards.iter().flatten();
+                    add(hidden, Location::Hidden(seat));
+                }
+            } else if let Some(info) = self.seats.get(seat) {
+                add(&mut info.known_hand.iter(), Location::Hand(seat));
+            }
+        }
+        add(&mut state.stack.iter(), Location::Stack);
+        add(&mut self.burned.iter(), Location::Burned);
+        known
+    }
+
+    /// The places unseen cards can be and how many of them each holds
+    fn unseen_slots(&self) -> Vec<(Location, usize)> {
+        let Some(state) = &self.last else {
+            return vec![];
+        };
+        let us = self.seat();
+        let mut slots = vec![];
+        for (seat, info) in self.seats.iter().enumerate() {
+            if Some(seat) == us {
+                let hidden_shown = state.other_players[seat].1.is_empty();
+                if !hidden_shown {
+                    slots.push((Location::Hidden(seat), info.hidden_left));
+                }
+            } else {
+                let unknown_hand = info.hand_len.saturating_sub(info.known_hand.len());
+                slots.push((Location::Hand(seat), unknown_hand));
+                slots.push((Location::Hidden(seat), info.hidden_left));
+            }
+        }
+        slots.push((Location::Deck, self.deck_len));
+        slots.push((Location::Burned, self.burned_unknown));
+        slots
+    }
+
+    fn update(&mut self, state: &GameState) {
+        let Some(last) = self.last.replace(state.clone()) else {
+            self.start(state);
+            return;
+        };
+        // we are sent the state again when our action is rejected. Others can't be told apart
+        // from playing a 10 from their hand on an empty stack, which changes nothing we can see.
+        if last == *state && last.turn == self.id {
+            return;
+        }
+        let Some(seat) = last
+            .other_players
+            .iter()
+            .position(|(id, _)| *id == last.turn)
+        else {
+            return;
+        };
+
+        let visible_before: Vec<&Card> = last.other_players[seat].1.iter().flatten().collect();
+        let visible_after: Vec<&Card> = match state.other_players.get(seat) {
+            Some((_, visible)) => visible.iter().flatten().collect(),
+            None => vec![],
+        };
+        let from_visible = visible_before
+            .iter()
+            .find(|card| !visible_after.contains(card))
+            .map(|&card| card.clone());
+        let ours = |state: &GameState| -> Vec<Card> {
+            let bottom = state.cards.bottom_cards.iter().flatten();
+            state.cards.hand.iter().chain(bottom).cloned().collect()
+        };
+
+        if state.stack.len() == last.stack.len() + 1 && state.stack.starts_with(&last.stack) {
+            let card = state.stack.last().cloned();
+            self.played(
+                seat,
+                card,
+                from_visible.is_some(),
+                visible_before.is_empty(),
+            );
+        } else if state.turn == last.turn {
+            // a 10 or four of a kind cleared the stack, we only know which card it was if it
+            // came from the visible cards or from us
+            let card = match from_visible.clone() {
+                Some(card) => Some(card),
+                None if last.turn == self.id => {
+                    let after = ours(state);
+                    ours(&last).into_iter().find(|card| !after.contains(card))
+                }
+                None => {
+                    self.forget_clearing_cards(seat, &last.stack);
+                    None
+                }
+            };
+            self.burned.extend(last.stack.iter().cloned());
+            match &card {
+                Some(card) => self.burned.push(card.clone()),
+                None => self.burned_unknown += 1,
+            }
+            self.played(
+                seat,
+                card,
+                from_visible.is_some(),
+                visible_before.is_empty(),
+            );
+        } else {
+            let seat = &mut self.seats[seat];
+            seat.hand_len += last.stack.len();
+            seat.known_hand.extend(last.stack.iter().cloned());
+        }
+    }
+
+    /// Counts how many cards everyone holds from the first state of the play stage. Swapping
+    /// cards keeps the number of cards in hand and visible the same, which is assumed as we can't
+    /// tell how many cards were drawn after compounding.
+    fn start(&mut self, state: &GameState) {
+        let hand_size = self.rules.hand_size;
+        self.seats = state
+            .other_players
+            .iter()
+            .map(|(id, visible)| {
+                let visible: usize = visible.iter().map(Vec::len).sum();
+                let hand_len = if *id == self.id {
+                    state.cards.hand.len()
+                } else {
+                    (hand_size + 3).saturating_sub(visible)
+                };
+                SeatCards {
+                    hand_len,
+                    hidden_left: 3,
+                    known_hand: vec![],
+                }
+            })
+            .collect();
+
+        let visible: usize = state
+            .other_players
+            .iter()
+            .map(|(_, visible)| visible.iter().map(Vec::len).sum::<usize>())
+            .sum();
+        let held: usize = self
+            .seats
+            .iter()
+            .map(|seat| seat.hand_len + seat.hidden_left)
+            .sum();
+        self.deck_len = 52usize.saturating_sub(held + visible + state.stack.len());
+    }
+
+    /// Forgets the cards we knew `seat` had that could have cleared `stack` when we didn't see
+    /// which card did: any 10, and the last card of the rank on top if there are three of it
+    fn forget_clearing_cards(&mut self, seat: usize, stack: &[Card]) {
+        let top = stack.last().map(|card| card.rank);
+        let three_on_top = self.rules.clear_on_four_of_a_kind
+            && stack.len() >= 3
+            && stack[stack.len() - 3..]
+                .iter()
+                .all(|card| Some(card.rank) == top);
+        self.seats[seat]
+            .known_hand
+            .retain(|card| card.rank != 10 && !(three_on_top && Some(card.rank) == top));
+    }
+
+    /// Takes a card played by `seat` out of their cards and refills their hand from the deck
+    fn played(&mut self, seat: usize, card: Option<Card>, from_visible: bool, no_visible: bool) {
+        let seat = &mut self.seats[seat];
+        // cards are played from the hand first, then the visible cards, then the hidden ones
+        if no_visible && seat.hand_len == 0 {
+            seat.hidden_left = seat.hidden_left.saturating_sub(1);
+        } else if !from_visible {
+            seat.hand_len = seat.hand_len.saturating_sub(1);
+            if let Some(index) =
+                card.and_then(|card| seat.known_hand.iter().position(|c| *c == card))
+            {
+                seat.known_hand.remove(index);
+            }
+        }
+
+        let hand_size = self.rules.hand_size;
+        if seat.hand_len < hand_size {
+            let drawn = self.deck_len.min(hand_size - seat.hand_len);
+            seat.hand_len += drawn;
+            self.deck_len -= drawn;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::api::player_messages::action::{PlayAction, SetupAction};
+    use crate::api::server_messages::GameEvent;
+    use crate::bot::local::{play_game, seat_id};
+    use crate::bot::reference::{Heuristic, LowestCard, Random};
+    use crate::game::Outcome;
+
+    /// Where `card` really is
+    fn location(table: &Table, card: &Card) -> Location {
+        for seat in 0..table.seats() {
+            let player = table.player(seat);
+            if player.hand.contains(card.into()) {
+                return Location::Hand(seat);
+            }
+            if player.visible_cards().iter().flatten().any(|c| c == card) {
+                return Location::Visible(seat);
+            }
+            if player.hidden_cards().iter().flatten().any(|c| c == card) {
+                return Location::Hidden(seat);
+            }
+        }
+        if table.playing_stack().contains(card) {
+            Location::Stack
+        } else if table.deck().cards.contains(card) {
+            Location::Deck
+        } else {
+            Location::Burned
+        }
+    }
+
+    /// Plays back a recorded game showing `seat` what the server would have, and checks what
+    /// the tracker knows against the table before every turn
+    fn replay(seats: usize, seed: u64, events: &[GameEvent], seat: usize) {
+        let ids: Vec<String> = (0..seats).map(seat_id).collect();
+        let seat_of = |player: &String| ids.iter().position(|id| id == player).unwrap();
+        let mut table = Table::deal(seats, seed, RuleSet::default());
+        let mut tracker = CardTracker::new(RuleSet::default());
+        tracker.observe(&Event::GameStart {
+            players: ids.clone(),
+            id: ids[seat].clone(),
+        });
+
+        let mut shown = false;
+        for event in events {
+            match event {
+                GameEvent::Setup { player, action } => {
+                    let _ = table.setup_action(seat_of(player), action.clone());
+                }
+                GameEvent::Play { player, action } => {
+                    if !shown {
+                        tracker.observe(&Event::State(table.view_for(seat, &ids)));
+                        check(&tracker, &table, seed);
+                        shown = true;
+                    }
+                    match table.play_action(seat_of(player), action) {
+                        Ok(Outcome::Ongoing) => shown = false,
+                        Ok(_) => return,
+                        Err(_) if seat_of(player) == seat => {
+                            tracker.observe(&Event::State(table.view_for(seat, &ids)));
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn check(tracker: &CardTracker, table: &Table, seed: u64) {
+        assert_eq!(tracker.deck_len(), table.deck().cards.len(), "seed {seed}");
+        for seat in 0..table.seats() {
+            let player = table.player(seat);
+            assert_eq!(
+                tracker.hand_len(seat),
+                Some(player.hand.len()),
+                "seed {seed}"
+            );
+            let hidden = player.hidden_cards().iter().flatten().count();
+            assert_eq!(tracker.hidden_left(seat), Some(hidden), "seed {seed}");
+            assert!(
+                tracker
+                    .known_hand(seat)
+                    .unwrap()
+                    .iter()
+                    .all(|card| player.hand.contains(card.into())),
+                "seed {seed}"
+            );
+        }
+
+        for card in Deck::from_seed(0).cards {
+            let odds = tracker.locate(&card);
+            let total: f64 = odds.iter().map(|(_, chance)| chance).sum();
+            assert!((total - 1.0).abs() < 1e-9, "seed {seed} {card:?} {odds:?}");
+            let real = location(table, &card);
+            assert!(
+                odds.iter().any(|&(location, _)| location == real),
+                "seed {seed} {card:?} is in {real:?} not {odds:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tracker_follows_replays() {
+        for seed in 0..10 {
+            let mut random = Random::new(seed);
+            let mut lowest = LowestCard;
+            let mut heuristic = Heuristic;
+            let result = play_game(
+                &mut [&mut random, &mut lowest, &mut heuristic],
+                seed,
+                RuleSet::default(),
+            );
+            for seat in 0..3 {
+                replay(3, seed, &result.events, seat);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seat_counts_are_none_before_the_play_stage() {
+        let ids: Vec<String> = (0..2).map(seat_id).collect();
+        let table = Table::deal(2, 3, RuleSet::default());
+        let mut tracker = CardTracker::new(RuleSet::default());
+        tracker.observe(&Event::GameStart {
+            players: ids.clone(),
+            id: ids[0].clone(),
+        });
+        tracker.observe(&Event::State(table.view_for(0, &ids)));
+        assert_eq!(tracker.seat(), None);
+        assert_eq!(tracker.hand_len(0), None);
+        assert_eq!(tracker.hidden_left(1), None);
+        assert_eq!(tracker.known_hand(0), None);
+    }
+
+    #[test]
+    fn test_picked_up_cards_are_known() {
+        let ids: Vec<String> = (0..2).map(seat_id).collect();
+        let mut table = Table::deal(2, 3, RuleSet::default());
+        table.setup_action(0, SetupAction::FinishExchange).unwrap();
+        table.setup_action(1, SetupAction::FinishExchange).unwrap();
+        let mut tracker = CardTracker::new(RuleSet::default());
+        tracker.observe(&Event::GameStart {
+            players: ids.clone(),
+            id: ids[1].clone(),
+        });
+        tracker.observe(&Event::State(table.view_for(1, &ids)));
+
+        let card = table.player(0).hand.lowest().unwrap().card();
+        let action = PlayAction::PlaceCard { card: card.clone() };
+        table.play_action(0, &action).unwrap();
+        tracker.observe(&Event::State(table.view_for(1, &ids)));
+        assert_eq!(tracker.locate(&card), [(Location::Stack, 1.0)]);
+        if table.turn() == 0 {
+            // a 10 was played, it's burned and they go again
+            return;
+        }
+
+        table.play_action(1, &PlayAction::PickupStack).unwrap();
+        tracker.observe(&Event::State(table.view_for(1, &ids)));
+        assert_eq!(tracker.locate(&card), [(Location::Hand(1), 1.0)]);
+    }
+
+    #[test]
+    fn test_determinized_table_keeps_what_we_can_see() {
+        let mut table = Table::deal(3, 5, RuleSet::default());
+        for seat in 0..3 {
+            table
+                .setup_action(seat, SetupAction::FinishExchange)
+                .unwrap();
+        }
+        let ids: Vec<String> = (0..3).map(seat_id).collect();
+        let mut tracker = CardTracker::new(RuleSet::default());
+        tracker.observe(&Event::GameStart {
+            players: ids.clone(),
+            id: seat_id(0),
+        });
+        tracker.observe(&Event::State(table.view_for(0, &ids)));
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let sampled = tracker.determinize(&mut rng).unwrap();
+        assert_eq!(sampled.player(0).hand, table.player(0).hand);
+        assert_eq!(sampled.deck().cards.len(), table.deck().cards.len());
+        for seat in 0..3 {
+            assert_eq!(
+                sampled.player(seat).hand.len(),
+                table.player(seat).hand.len()
+            );
+            assert_eq!(
+                sampled.player(seat).visible_cards(),
+                table.player(seat).visible_cards()
+            );
+        }
+    }
+}
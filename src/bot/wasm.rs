@@ -0,0 +1,310 @@
+//! Runs a WebAssembly module as a [`Strategy`].
+//!
+//! A bot can be handed in as a single `.wasm` file. It is given no imports, so it can't do
+//! anything but compute, and its instructions are metered with fuel so every bot gets the same
+//! amount of work per move however fast the machine is. The module exports:
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, space for the host to write `len` bytes of JSON to
+//! - `setup(ptr: i32, len: i32) -> i64`, given a [`View`] as JSON returns a [`SetupAction`]
+//! - `play(ptr: i32, len: i32) -> i64`, given a [`View`] as JSON returns a [`PlayAction`]
+//! - optionally `event(ptr: i32, len: i32)`, given every [`Event`] as JSON
+//!
+//! Actions are the same JSON the server accepts, written to the module's memory and returned
+//! as `ptr << 32 | len`, at most 64 KiB long. A module that traps, runs out of fuel or returns
+//! something invalid forfeits the game.
+
+use std::fmt;
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+use wasmi::{
+    Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TrapCode, TypedFunc,
+};
+
+use super::{Event, Strategy, View};
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+
+/// Longest reply a module may return, actions are a few dozen bytes
+const MAX_REPLY: usize = 64 * 1024;
+
+/// What a module may use
+#[derive(Clone, Debug)]
+pub struct WasmLimits {
+    /// Fuel for each call into the module, about one per instruction
+    pub fuel: u64,
+    /// Bytes of memory the module may grow to
+    pub memory: usize,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        Self {
+            fuel: 100_000_000,
+            memory: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WasmError {
+    Io(std::io::Error),
+    /// The module isn't valid or couldn't be started
+    Load(wasmi::Error),
+    MissingExport(&'static str),
+    Trap(wasmi::Error),
+    OutOfFuel,
+    /// The module returned something that isn't the answer that was asked for
+    BadReply(String),
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmError::Io(e) => write!(f, "Couldn't read the module: {e}"),
+            WasmError::Load(e) => write!(f, "Couldn't load the module: {e}"),
+            WasmError::MissingExport(name) => write!(f, "The module doesn't export {name}"),
+            WasmError::Trap(e) => write!(f, "The module trapped: {e}"),
+            WasmError::OutOfFuel => write!(f, "The module ran out of fuel"),
+            WasmError::BadReply(reply) => {
+                write!(f, "The module returned an invalid reply: {reply}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WasmError {}
+
+impl From<wasmi::Error> for WasmError {
+    fn from(e: wasmi::Error) -> Self {
+        if e.as_trap_code() == Some(TrapCode::OutOfFuel) {
+            WasmError::OutOfFuel
+        } else {
+            WasmError::Trap(e)
+        }
+    }
+}
+
+type Call = TypedFunc<(i32, i32), i64>;
+
+/// A WebAssembly module being played as a [`Strategy`].
+///
+/// Like a [`Subprocess`](super::subprocess::Subprocess), once it has failed it is no longer
+/// called and finishing the swap and picking up the stack are played for it instead.
+pub struct WasmBot {
+    fuel: u64,
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    setup: Call,
+    play: Call,
+    event: Option<TypedFunc<(i32, i32), ()>>,
+    /// The first thing that went wrong
+    error: Option<WasmError>,
+}
+
+impl WasmBot {
+    /// Loads a `.wasm` file, or the text format
+    ///
+    /// # Errors
+    /// As [`WasmBot::new`], or if the file can't be read
+    pub fn load(path: impl AsRef<Path>, limits: WasmLimits) -> Result<Self, WasmError> {
+        let wasm = std::fs::read(path).map_err(WasmError::Io)?;
+        Self::new(&wasm, limits)
+    }
+
+    /// Compiles and starts a module
+    ///
+    /// # Errors
+    /// If the module is invalid, runs out of fuel starting or lacks one of the exports
+    pub fn new(wasm: &[u8], limits: WasmLimits) -> Result<Self, WasmError> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(WasmError::Load)?;
+
+        let store_limits = StoreLimitsBuilder::new().memory_size(limits.memory).build();
+        let mut store = Store::new(&engine, store_limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(limits.fuel).map_err(WasmError::Load)?;
+        let instance = Linker::new(&engine)
+            .instantiate_and_start(&mut store, &module)
+            .map_err(WasmError::Load)?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or(WasmError::MissingExport("memory"))?;
+        Ok(Self {
+            fuel: limits.fuel,
+            memory,
+            alloc: export(&store, &instance, "alloc")?,
+            setup: export(&store, &instance, "setup")?,
+            play: export(&store, &instance, "play")?,
+            event: export(&store, &instance, "event").ok(),
+            store,
+            error: None,
+        })
+    }
+
+    /// What went wrong with the module, if anything
+    pub fn error(&self) -> Option<&WasmError> {
+        self.error.as_ref()
+    }
+
+    /// Writes `message` as JSON to the module's memory, returning where it is
+    fn write(&mut self, message: &impl Serialize) -> Result<(i32, i32), WasmError> {
+        let json = serde_json::to_string(message).unwrap();
+        let len = i32::try_from(json.len()).expect("Messages are small");
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, json.as_bytes())
+            .map_err(|_| WasmError::BadReply(format!("alloc returned {ptr}")))?;
+        Ok((ptr, len))
+    }
+
+    fn ask<T: DeserializeOwned>(&mut self, call: Call, view: &View) -> Result<T, WasmError> {
+        self.store.set_fuel(self.fuel)?;
+        let (ptr, len) = self.write(view)?;
+        let reply = call.call(&mut self.store, (ptr, len))? as u64;
+
+        // the module picks both, so check them before trusting either
+        let (ptr, len) = ((reply >> 32) as usize, (reply & 0xffff_ffff) as usize);
+        if len > MAX_REPLY {
+            let e = format!("The reply is {len} bytes, longer than {MAX_REPLY}");
+            return Err(WasmError::BadReply(e));
+        }
+        let memory = self.memory.data(&self.store);
+        let Some(bytes) = memory.get(ptr..ptr.saturating_add(len)) else {
+            return Err(WasmError::BadReply(format!("{reply:#x} is out of bounds")));
+        };
+        let reply = String::from_utf8_lossy(bytes);
+        serde_json::from_str(&reply).map_err(|_| WasmError::BadReply(reply.into_owned()))
+    }
+
+    /// Asks the module for an action, falling back to `fallback` once it has failed
+    fn action<T: DeserializeOwned>(&mut self, call: Call, view: &View, fallback: T) -> T {
+        if self.error.is_some() {
+            return fallback;
+        }
+        match self.ask(call, view) {
+            Ok(action) => action,
+            Err(e) => {
+                self.fail(e);
+                fallback
+            }
+        }
+    }
+
+    fn fail(&mut self, e: WasmError) {
+        warn!("{e}");
+        self.error = Some(e);
+    }
+}
+
+fn export<Params, Results>(
+    store: &Store<StoreLimits>,
+    instance: &Instance,
+    name: &'static str,
+) -> Result<TypedFunc<Params, Results>, WasmError>
+where
+    Params: wasmi::WasmParams,
+    Results: wasmi::WasmResults,
+{
+    instance
+        .get_typed_func(store, name)
+        .map_err(|_| WasmError::MissingExport(name))
+}
+
+impl Strategy for WasmBot {
+    fn on_setup(&mut self, view: &View) -> SetupAction {
+        self.action(self.setup, view, SetupAction::FinishExchange)
+    }
+
+    fn on_turn(&mut self, view: &View) -> PlayAction {
+        self.action(self.play, view, PlayAction::PickupStack)
+    }
+
+    fn on_event(&mut self, event: &Event) {
+        let Some(call) = self.event else {
+            return;
+        };
+        if self.error.is_some() {
+            return;
+        }
+        let result = self
+            .store
+            .set_fuel(self.fuel)
+            .map_err(WasmError::from)
+            .and_then(|()| self.write(event))
+            .and_then(|args| Ok(call.call(&mut self.store, args)?));
+        if let Err(e) = result {
+            self.fail(e);
+        }
+    }
+
+    fn forfeit(&self) -> Option<String> {
+        self.error.as_ref().map(ToString::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::local::play_game;
+    use crate::bot::reference::LowestCard;
+    use crate::game::{Outcome, RuleSet};
+
+    /// Never swaps and picks up the stack, unless `play` is replaced
+    const PICKER: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\"FinishExchange\"")
+            (data (i32.const 32) "\"PickupStack\"")
+            (func (export "alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "setup") (param i32 i32) (result i64) i64.const 16)
+            (func (export "play") (param i32 i32) (result i64)
+                ;; 32 << 32 | 13
+                i64.const 137438953485))
+    "#;
+
+    #[test]
+    fn test_wasm_bot_plays_a_game() {
+        let mut bot = WasmBot::new(PICKER.as_bytes(), WasmLimits::default()).unwrap();
+        let mut lowest = LowestCard;
+        let result = play_game(&mut [&mut bot, &mut lowest], 1, RuleSet::default());
+        assert_eq!(result.rejections, [0, 0]);
+        assert!(result.forfeit.is_none());
+    }
+
+    #[test]
+    fn test_running_out_of_fuel_forfeits() {
+        let looping = PICKER.replace("i64.const 137438953485", "(loop (br 0)) i64.const 0");
+        let limits = WasmLimits {
+            fuel: 10_000,
+            ..WasmLimits::default()
+        };
+        let mut bot = WasmBot::new(looping.as_bytes(), limits).unwrap();
+        let mut lowest = LowestCard;
+        let result = play_game(&mut [&mut bot, &mut lowest], 1, RuleSet::default());
+        assert_eq!(result.outcome, Outcome::Won(1));
+        assert_eq!(result.forfeit.unwrap().seat, 0);
+        assert!(matches!(bot.error(), Some(WasmError::OutOfFuel)));
+    }
+
+    #[test]
+    fn test_replies_outside_memory_forfeit() {
+        // too long, starting past the single page of 64 KiB, and both
+        let too_long = u32::MAX.to_string();
+        let past_memory = (65536u64 << 32 | 1).to_string();
+        for reply in [too_long, past_memory, "-1".to_string()] {
+            let wasm = PICKER.replace("i64.const 137438953485", &format!("i64.const {reply}"));
+            let mut bot = WasmBot::new(wasm.as_bytes(), WasmLimits::default()).unwrap();
+            let mut lowest = LowestCard;
+            let result = play_game(&mut [&mut bot, &mut lowest], 1, RuleSet::default());
+            assert_eq!(result.forfeit.unwrap().seat, 0, "reply {reply}");
+            assert!(matches!(bot.error(), Some(WasmError::BadReply(_))));
+        }
+    }
+}
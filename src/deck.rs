@@ -1,5 +1,8 @@
-use rand::seq::SliceRandom;
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{BitAnd, BitOr, Not, Sub};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
@@ -28,12 +31,281 @@ pub struct Card {
     pub suit: Suit,
 }
 
+impl Suit {
+    fn index(&self) -> u8 {
+        match self {
+            Suit::Club => 0,
+            Suit::Diamond => 1,
+            Suit::Heart => 2,
+            Suit::Spade => 3,
+        }
+    }
+
+    fn from_index(index: u8) -> Self {
+        match index {
+            0 => Suit::Club,
+            1 => Suit::Diamond,
+            2 => Suit::Heart,
+            _ => Suit::Spade,
+        }
+    }
+}
+
+/// A card as a number from 0 to 51, ordered like [`Card`] by rank and then suit. Cheaper to copy
+/// and compare than a `Card` when simulating games.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CardIndex(u8);
+
+impl CardIndex {
+    /// The index of `card`, `None` if its rank isn't between 2 and 14
+    pub fn of(card: &Card) -> Option<Self> {
+        (2..=ACE_RANK)
+            .contains(&card.rank)
+            .then(|| Self((card.rank - 2) * 4 + card.suit.index()))
+    }
+
+    /// # Panics
+    /// If `index` isn't below 52
+    pub fn new(index: u8) -> Self {
+        assert!(index < 52, "There are 52 cards");
+        Self(index)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    pub fn rank(self) -> Rank {
+        self.0 / 4 + 2
+    }
+
+    pub fn card(self) -> Card {
+        Card {
+            rank: self.rank(),
+            suit: Suit::from_index(self.0 % 4),
+        }
+    }
+}
+
+impl From<&Card> for CardIndex {
+    /// # Panics
+    /// If the rank isn't between 2 and 14
+    fn from(card: &Card) -> Self {
+        Self::of(card).expect("Ranks go from 2 to 14")
+    }
+}
+
+impl From<CardIndex> for Card {
+    fn from(index: CardIndex) -> Self {
+        index.card()
+    }
+}
+
+impl fmt::Debug for CardIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{:?}", self.rank(), Suit::from_index(self.0 % 4))
+    }
+}
+
+/// A set of cards as a bitset, bit `i` is the card with [`CardIndex`] `i`. It is `Copy`, so a
+/// position can be cloned for free while searching. Serialized as a list of [`Card`]s, lowest
+/// first.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CardSet(u64);
+
+impl CardSet {
+    pub const EMPTY: CardSet = CardSet(0);
+    pub const FULL: CardSet = CardSet((1 << 52) - 1);
+
+    /// Every card of `rank`
+    pub fn rank(rank: Rank) -> Self {
+        debug_assert!((2..=ACE_RANK).contains(&rank), "Ranks go from 2 to 14");
+        Self(0b1111 << ((rank - 2) * 4))
+    }
+
+    /// Every card of `rank` or higher
+    pub fn at_least(rank: Rank) -> Self {
+        debug_assert!((2..=ACE_RANK).contains(&rank), "Ranks go from 2 to 14");
+        Self(Self::FULL.0 & !((1 << ((rank - 2) * 4)) - 1))
+    }
+
+    /// The cards of this set that may go on a stack topped by a card of rank `top`, see
+    /// [`can_place_on`](crate::game::can_place_on)
+    pub fn placeable_on(self, top: Option<Rank>) -> Self {
+        match top {
+            Some(top) => self & (Self::at_least(top) | Self::rank(2) | Self::rank(10)),
+            None => self,
+        }
+    }
+
+    pub fn contains(self, card: CardIndex) -> bool {
+        self.0 & (1 << card.0) != 0
+    }
+
+    /// Returns whether the card was new
+    pub fn insert(&mut self, card: CardIndex) -> bool {
+        let new = !self.contains(card);
+        self.0 |= 1 << card.0;
+        new
+    }
+
+    /// Returns whether the card was in the set
+    pub fn remove(&mut self, card: CardIndex) -> bool {
+        let had = self.contains(card);
+        self.0 &= !(1 << card.0);
+        had
+    }
+
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// How many cards of `rank` are in the set
+    pub fn rank_count(self, rank: Rank) -> usize {
+        (self & Self::rank(rank)).len()
+    }
+
+    /// How many cards of each rank are in the set, indexed by rank so 2s are at 2
+    pub fn rank_counts(self) -> [u8; 15] {
+        let mut counts = [0; 15];
+        for (rank, count) in counts.iter_mut().enumerate().skip(2) {
+            *count = self.rank_count(rank as Rank) as u8;
+        }
+        counts
+    }
+
+    /// The lowest card, by rank and then suit
+    pub fn lowest(self) -> Option<CardIndex> {
+        (!self.is_empty()).then(|| CardIndex(self.0.trailing_zeros() as u8))
+    }
+
+    /// The cards from lowest to highest
+    pub fn iter(self) -> CardSetIter {
+        CardSetIter(self)
+    }
+
+    pub fn to_cards(self) -> Vec<Card> {
+        self.iter().map(CardIndex::card).collect()
+    }
+}
+
+impl BitOr for CardSet {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitAnd for CardSet {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl Sub for CardSet {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl Not for CardSet {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(Self::FULL.0 & !self.0)
+    }
+}
+
+impl FromIterator<CardIndex> for CardSet {
+    fn from_iter<I: IntoIterator<Item = CardIndex>>(cards: I) -> Self {
+        let mut set = Self::EMPTY;
+        for card in cards {
+            set.insert(card);
+        }
+        set
+    }
+}
+
+impl<'a> FromIterator<&'a Card> for CardSet {
+    fn from_iter<I: IntoIterator<Item = &'a Card>>(cards: I) -> Self {
+        cards.into_iter().map(CardIndex::from).collect()
+    }
+}
+
+impl IntoIterator for CardSet {
+    type Item = CardIndex;
+    type IntoIter = CardSetIter;
+
+    fn into_iter(self) -> CardSetIter {
+        self.iter()
+    }
+}
+
+impl Serialize for CardSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(CardIndex::card))
+    }
+}
+
+impl<'de> Deserialize<'de> for CardSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<Card>::deserialize(deserializer)?
+            .iter()
+            .map(|card| {
+                CardIndex::of(card)
+                    .ok_or_else(|| de::Error::custom(format!("{} isn't a rank", card.rank)))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for CardSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// The cards of a [`CardSet`] from lowest to highest
+#[derive(Clone, Debug)]
+pub struct CardSetIter(CardSet);
+
+impl Iterator for CardSetIter {
+    type Item = CardIndex;
+
+    fn next(&mut self) -> Option<CardIndex> {
+        let lowest = self.0.lowest()?;
+        self.0.remove(lowest);
+        Some(lowest)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl ExactSizeIterator for CardSetIter {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Deck {
     pub cards: Vec<Card>,
 }
 
 impl Deck {
     pub fn new_deck() -> Self {
+        Self::from_seed(rand::random())
+    }
+
+    /// Creates a deck shuffled with `seed`. The same seed always gives the same order.
+    pub fn from_seed(seed: u64) -> Self {
         let mut cards = Vec::new();
 
         for rank in 2..=14 {
@@ -42,7 +314,7 @@ impl Deck {
             }
         }
 
-        let mut rng = rand::thread_rng();
+        let mut rng = StdRng::seed_from_u64(seed);
         cards.as_mut_slice().shuffle(&mut rng);
 
         Self { cards }
@@ -70,6 +342,78 @@ mod tests {
         assert_eq!(deck.cards.len(), 52);
     }
 
+    #[test]
+    fn test_from_seed_is_deterministic() {
+        let deck = Deck::from_seed(42);
+        assert_eq!(deck.cards, Deck::from_seed(42).cards);
+        assert_ne!(deck.cards, Deck::from_seed(43).cards);
+    }
+
+    #[test]
+    fn test_card_index_round_trips() {
+        let deck = Deck::from_seed(0);
+        let set: CardSet = deck.cards.iter().collect();
+        assert_eq!(set, CardSet::FULL);
+        for card in &deck.cards {
+            let index = CardIndex::from(card);
+            assert_eq!(&index.card(), card);
+            assert!(set.contains(index));
+        }
+        let mut sorted = deck.cards.clone();
+        sorted.sort();
+        assert_eq!(set.to_cards(), sorted);
+    }
+
+    #[test]
+    fn test_card_set_ranks_and_placing() {
+        let card = |rank, suit| CardIndex::from(&Card { rank, suit });
+        let hand: CardSet = [
+            card(2, Suit::Heart),
+            card(5, Suit::Club),
+            card(5, Suit::Spade),
+            card(10, Suit::Diamond),
+            card(12, Suit::Club),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(hand.len(), 5);
+        assert_eq!(hand.rank_count(5), 2);
+        assert_eq!(hand.rank_counts()[12], 1);
+        assert_eq!(hand.lowest(), Some(card(2, Suit::Heart)));
+
+        let placeable = hand.placeable_on(Some(11));
+        assert_eq!(
+            placeable.iter().collect::<Vec<_>>(),
+            [
+                card(2, Suit::Heart),
+                card(10, Suit::Diamond),
+                card(12, Suit::Club)
+            ]
+        );
+        assert_eq!(hand.placeable_on(None), hand);
+        assert_eq!((!hand).len(), 47);
+        assert_eq!(hand - CardSet::rank(5), placeable);
+    }
+
+    #[test]
+    fn test_card_set_is_serialized_as_cards() {
+        let cards = vec![
+            Card {
+                rank: 3,
+                suit: Suit::Spade,
+            },
+            Card {
+                rank: ACE_RANK,
+                suit: Suit::Club,
+            },
+        ];
+        let set: CardSet = cards.iter().collect();
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, serde_json::to_string(&cards).unwrap());
+        assert_eq!(serde_json::from_str::<CardSet>(&json).unwrap(), set);
+        assert!(serde_json::from_str::<CardSet>(r#"[{"rank":1,"suit":"Club"}]"#).is_err());
+    }
+
     #[test]
     fn test_pull_card_reduces_deck_size() {
         let mut deck = Deck::new_deck();
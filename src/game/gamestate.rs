@@ -1,50 +1,30 @@
-use crate::api::server_messages::{self, BottomCards, GameState};
-
+use super::spectators::SpectatorUpdate;
 use super::SkitGubbe;
 
 impl SkitGubbe {
-    async fn get_all_players_vis_cards(&self) -> Vec<(String, BottomCards)> {
-        let mut other_player_cards = vec![];
-
-        for player in self.players.iter() {
-            let player = player.lock().await;
-            other_player_cards.push((
-                player.user.lock().await.id.to_string(),
-                player.visible_cards(),
-            ));
-        }
-
-        other_player_cards
-    }
-
-    pub async fn send_playing_game_state(&self, cur_player_index: usize) {
-        let turn = self.players[cur_player_index as usize]
+    /// Sends the player in `seat` what they can currently see
+    pub async fn send_game_state(&self, seat: usize) {
+        let message = self.table.view_for(seat, &self.player_ids);
+        let _ = self.users[seat]
             .lock()
             .await
-            .user
-            .lock()
-            .await
-            .id
-            .to_string();
+            .send(&serde_json::to_string(&message).unwrap())
+            .await;
+    }
 
-        let other_player_cards = self.get_all_players_vis_cards().await;
+    /// Sends every player what they can currently see
+    pub async fn send_playing_game_state(&self) {
+        for seat in 0..self.users.len() {
+            self.send_game_state(seat).await;
+        }
+    }
 
-        for player in self.players.iter() {
-            let player = player.lock().await;
+    /// Sends the current state of the game to spectators and saves it for checkpoints
+    pub fn publish_spectator_update(&self) {
+        self.shared_table.lock().unwrap().clone_from(&self.table);
 
-            let message = GameState {
-                turn: turn.clone(),
-                stage: server_messages::Stage::Play,
-                cards: player.to_server_player_cards(),
-                stack: self.playing_stack.clone(),
-                other_players: other_player_cards.clone(),
-            };
-            let _ = player
-                .user
-                .lock()
-                .await
-                .send(&serde_json::to_string(&message).unwrap())
-                .await;
-        }
+        let last_action = self.events.lock().unwrap().last().cloned();
+        let (public, god) = self.table.spectator_views(&self.player_ids, last_action);
+        self.spectators.publish(SpectatorUpdate { god, public });
     }
 }
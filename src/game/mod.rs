@@ -1,214 +1,304 @@
 mod gamestate;
-mod player;
 mod playercards;
+mod rules;
+mod spectators;
+mod table;
 
-use std::sync::Arc;
+pub use playercards::{can_play_hidden, PlayerCards};
+pub use rules::{RuleSet, MAX_HAND_SIZE};
+pub use spectators::{SpectatorUpdate, Spectators};
+pub use table::{can_place_on, Outcome, Table};
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::extract::ws::Message;
+use futures::stream::FuturesUnordered;
 use futures_util::{lock::Mutex, StreamExt};
-
-use crate::deck::Card;
-use crate::deck::Deck;
-use player::Player;
+use tracing::{debug, info, instrument, warn};
 
 use crate::user::User;
 
 use crate::api::player_messages;
 use crate::api::server_messages;
 
+/// Every accepted action taken in a game in the order it was received. Shared so it can still be
+/// read if the game panics.
+pub type EventLog = Arc<std::sync::Mutex<Vec<server_messages::GameEvent>>>;
+
+/// The table as of the last accepted action. Shared so running games can be checkpointed.
+pub type SharedTable = Arc<std::sync::Mutex<Table>>;
+
+/// Called with how long a player took to send each valid action
+pub type MoveObserver = Arc<dyn Fn(server_messages::Stage, Duration) + Send + Sync>;
+
 pub struct SkitGubbe {
-    players: Vec<Arc<Mutex<Player>>>,
-    deck: Arc<Mutex<Deck>>,
-    playing_stack: Vec<Card>,
+    /// Users in seat order
+    users: Vec<Arc<Mutex<User>>>,
+    /// Ids of the users in seat order, filled in when the game starts running
+    player_ids: Vec<String>,
+    table: Table,
+    shared_table: SharedTable,
+    events: EventLog,
+    spectators: Spectators,
+    move_observer: Option<MoveObserver>,
 }
 
-const MAX_TURNS: usize = 300;
-
 impl SkitGubbe {
     pub fn new(users: Vec<Arc<Mutex<User>>>) -> Self {
-        assert!(
-            users.len() <= 4,
-            "Skit Gubbe game must be 4 players or less"
+        Self::with_seed(users, rand::random())
+    }
+
+    /// Creates a game whose deck is shuffled with `seed`, see [`Deck::from_seed`]
+    ///
+    /// [`Deck::from_seed`]: crate::deck::Deck::from_seed
+    pub fn with_seed(users: Vec<Arc<Mutex<User>>>, seed: u64) -> Self {
+        Self::with_rules(users, seed, RuleSet::default())
+    }
+
+    /// Creates a game played with `rules`, which must be [valid](RuleSet::validate)
+    pub fn with_rules(users: Vec<Arc<Mutex<User>>>, seed: u64, rules: RuleSet) -> Self {
+        let table = Table::deal(users.len(), seed, rules);
+        Self::restore(users, table, vec![])
+    }
+
+    /// Carries on a game from a checkpoint. `users` must be in the same seat order as when the
+    /// game was started.
+    pub fn restore(
+        users: Vec<Arc<Mutex<User>>>,
+        table: Table,
+        events: Vec<server_messages::GameEvent>,
+    ) -> Self {
+        assert_eq!(
+            users.len(),
+            table.seats(),
+            "Every seat at the table must have a user"
         );
 
-        let mut deck = Deck::new_deck();
-        let mut players = vec![];
-
-        for user in users.into_iter() {
-            let hidden_cards: [Option<Card>; 3] =
-                core::array::from_fn(|_| Some(deck.pull_card().expect("Should have enough cards")));
-            let visible_cards: [Vec<Card>; 3] = core::array::from_fn(|_| {
-                let x = deck.pull_cards(1);
-                assert_eq!(x.len(), 1, "Should have enough cards");
-                x
-            });
-
-            players.push(Arc::new(Mutex::new(Player::new(
-                user,
-                hidden_cards,
-                visible_cards,
-                deck.pull_cards(3),
-            ))));
+        Self {
+            users,
+            player_ids: vec![],
+            shared_table: Arc::new(std::sync::Mutex::new(table.clone())),
+            table,
+            events: Arc::new(std::sync::Mutex::new(events)),
+            spectators: Spectators::new(),
+            move_observer: None,
         }
+    }
 
-        Self {
-            deck: Arc::new(Mutex::new(deck)),
-            players,
-            playing_stack: vec![],
+    pub fn seed(&self) -> u64 {
+        self.table.seed()
+    }
+
+    pub fn rules(&self) -> &RuleSet {
+        self.table.rules()
+    }
+
+    pub fn events(&self) -> EventLog {
+        Arc::clone(&self.events)
+    }
+
+    pub fn table(&self) -> SharedTable {
+        Arc::clone(&self.shared_table)
+    }
+
+    pub fn spectators(&self) -> Spectators {
+        self.spectators.clone()
+    }
+
+    pub fn set_move_observer(&mut self, observer: MoveObserver) {
+        self.move_observer = Some(observer);
+    }
+
+    fn observe_move(&self, stage: server_messages::Stage, elapsed: Duration) {
+        if let Some(observer) = &self.move_observer {
+            observer(stage, elapsed);
         }
     }
 
     pub async fn notify_all_players(&self, msg: &str) {
-        for player in &self.players {
-            let _ = player.lock().await.user.lock().await.send(msg).await;
+        for user in &self.users {
+            let _ = user.lock().await.send(msg).await;
         }
     }
 
+    /// Plays the game from wherever the table is until someone wins or it is a draw.
+    ///
+    /// Returns: the seat of the winner, `None` if it was a draw
+    ///
+    /// # Errors
+    /// The id of the player that disconnected
+    #[instrument(skip_all, fields(seed = self.seed(), players = self.users.len()))]
     pub async fn run(mut self) -> Result<Option<usize>, String> {
-        let mut player_ids = vec![];
-        for player in &self.players {
-            player_ids.push(player.lock().await.user.lock().await.id.to_string());
+        self.player_ids.clear();
+        for user in &self.users {
+            self.player_ids.push(user.lock().await.id.to_string());
         }
-        let game_start_msg = server_messages::ServerNotification::GameStart(player_ids.clone());
+        let game_start_msg =
+            server_messages::ServerNotification::GameStart(self.player_ids.clone());
 
         self.notify_all_players(&serde_json::to_string(&game_start_msg).unwrap())
             .await;
 
         // start setup round
-        self.notify_all_players(&serde_json::to_string(&server_messages::Stage::Swap).unwrap())
-            .await;
-        self.execute_setup_round().await?;
+        if self.table.stage() == server_messages::Stage::Swap {
+            self.notify_all_players(&serde_json::to_string(&server_messages::Stage::Swap).unwrap())
+                .await;
+            self.publish_spectator_update();
+            self.execute_setup_round().await?;
+        }
 
         // start normal rounds
         self.notify_all_players(&serde_json::to_string(&server_messages::Stage::Play).unwrap())
             .await;
-        let mut winner_index = None;
-        for _ in 0..MAX_TURNS {
-            if let Some(player_index) = self.execute_round().await? {
-                winner_index = Some(player_index);
-                break;
+        let winner_index = loop {
+            match self.execute_turn().await? {
+                Outcome::Ongoing => continue,
+                Outcome::Won(seat) => break Some(seat),
+                Outcome::Draw => break None,
             }
-        }
+        };
 
+        info!(?winner_index, "Game over");
+        self.publish_spectator_update();
         self.notify_end(winner_index).await;
         Ok(winner_index)
     }
 
-    /// Executes the setup round for all players asynchronously
+    /// Waits for the next message from the user in `seat`, returning how long it took
+    async fn next_message(
+        user: Arc<Mutex<User>>,
+        seat: usize,
+    ) -> (usize, Duration, Option<Result<Message, axum::Error>>) {
+        let asked_at = Instant::now();
+        let message = user.lock().await.receiver.next().await;
+        (seat, asked_at.elapsed(), message)
+    }
+
+    /// Lets every player swap cards at the same time until they have all finished
     ///
     /// # Errors
     ///
-    /// Returns an error if player connects with player ID
+    /// Returns the player ID if a player disconnects
+    #[instrument(skip_all)]
     async fn execute_setup_round(&mut self) -> Result<(), String> {
-        let player_futures: Vec<_> = self
-            .players
-            .iter()
-            .map(|player| {
-                tokio::spawn(Self::player_setup_round(
-                    Arc::clone(&player),
-                    Arc::clone(&self.deck),
-                ))
-            })
-            .collect();
+        let mut pending = FuturesUnordered::new();
+        for seat in 0..self.users.len() {
+            if self.table.has_finished_setup(seat) {
+                continue;
+            }
+            // show players their cards
+            self.send_game_state(seat).await;
+            pending.push(Self::next_message(Arc::clone(&self.users[seat]), seat));
+        }
 
         // TODO: Add timeout period if one of the players takes too long
-        let _ = futures::future::join_all(player_futures).await;
+        while let Some((seat, elapsed, message)) = pending.next().await {
+            if self.handle_setup_message(seat, elapsed, message).await? {
+                pending.push(Self::next_message(Arc::clone(&self.users[seat]), seat));
+            }
+        }
 
         Ok(())
     }
 
-    /// Runs the player's swap round asynchronously.
-    /// Returns the players new cards
+    /// Handles a message sent by the player in `seat` during the setup round
+    ///
+    /// Returns: whether to wait for another message from the player
     ///
     /// # Errors
     ///
-    /// This function will return an error if the player disconnects.
-    /// Returns the player ID if error.
-    async fn player_setup_round(
-        player: Arc<Mutex<Player>>,
-        deck: Arc<Mutex<Deck>>,
-    ) -> Result<server_messages::Cards, String> {
-        let mut player = player.lock().await;
-        let player_id = player.user.lock().await.id.to_string();
-
-        // show players their cards
-        player.send_setup_game_state().await;
-
-        // allow players to exchange their cards
-        loop {
-            let message = player.user.lock().await.receiver.next().await;
-            // if no message
-            let Some(message) = message else {
-                continue;
-            };
-
-            // if error reading
-            let Message::Text(message) = message.map_err(|_| player_id.clone())? else {
-                continue;
-            };
-            // parse msg
-            let Ok(action) = serde_json::from_str::<player_messages::action::SetupAction>(&message)
-            else {
-                player.notify_invalid_action().await;
-                continue;
-            };
-            match action {
-                player_messages::action::SetupAction::ExchangeCard { hand, bottom } => {
-                    if let Err(e) = player.exchange_cards(hand, bottom).await {
-                        let _ = player.user.lock().await.send(e).await;
-                        continue;
-                    }
-                    player.send_setup_game_state().await;
-                }
-                player_messages::action::SetupAction::CompoundCard { hand, bottom } => {
-                    if let Err(e) = player.compound_cards(hand, bottom).await {
-                        let _ = player.user.lock().await.send(&e).await;
-                        continue;
-                    }
-
-                    if player.cards.hand.len() < 3 {
-                        let num_pick_up = 3 - player.cards.hand.len();
-                        player
-                            .cards
-                            .hand
-                            .append(&mut deck.lock().await.pull_cards(num_pick_up));
-                        player.cards.hand.sort();
-                    }
-
-                    player.send_setup_game_state().await;
-                }
-                player_messages::action::SetupAction::FinishExchange => {
-                    break;
-                }
-            };
+    /// Returns the player ID if the player disconnected
+    #[instrument(name = "player", skip(self, elapsed, message), fields(player_id = self.player_ids[seat]))]
+    async fn handle_setup_message(
+        &mut self,
+        seat: usize,
+        elapsed: Duration,
+        message: Option<Result<Message, axum::Error>>,
+    ) -> Result<bool, String> {
+        let player_id = self.player_ids[seat].clone();
+        // if disconnected
+        let Some(message) = message else {
+            warn!(player_id, "Player disconnected");
+            return Err(player_id);
+        };
+
+        // if error reading
+        let Message::Text(message) = message.map_err(|_| player_id.clone())? else {
+            return Ok(true);
+        };
+        // parse msg
+        let Ok(action) = serde_json::from_str::<player_messages::action::SetupAction>(&message)
+        else {
+            debug!(player_id, msg = message, "Couldn't parse setup action");
+            self.notify_invalid_action(seat, "Couldn't parse the action")
+                .await;
+            return Ok(true);
+        };
+        debug!(player_id, ?action, "Setup action");
+
+        // a rejected action can name cards the player doesn't have, so it is kept out of the
+        // log and away from spectators
+        match self.table.setup_action(seat, action.clone()) {
+            Ok(()) => {
+                self.observe_move(server_messages::Stage::Swap, elapsed);
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(server_messages::GameEvent::Setup {
+                        player: player_id.clone(),
+                        action,
+                    });
+                self.publish_spectator_update();
+            }
+            Err(e) => {
+                debug!(player_id, "Invalid setup: {e}");
+                self.notify(
+                    seat,
+                    &server_messages::ServerNotification::ActionRejected(e.to_string()),
+                )
+                .await;
+            }
         }
 
-        Ok(server_messages::Cards {
-            hand: player.cards.hand.to_vec(),
-            bottom_cards: player.get_bottom_cards(),
-        })
+        if self.table.has_finished_setup(seat) {
+            debug!(player_id, "Finished setup");
+            return Ok(false);
+        }
+        self.send_game_state(seat).await;
+        Ok(true)
     }
 
-    /// Executes a round where all players play, if a player wins the game stops and the index of
-    /// the winning player is returned.
+    /// Shows everyone the table and waits for a valid action from the player whose turn it is
     ///
-    /// Returns: index of winning player
-    async fn execute_round(&mut self) -> Result<Option<usize>, String> {
-        let mut player_index = 0;
-        'player_loop: while player_index <= self.players.len() {
-            let mut player = self.players[player_index].lock().await;
-            if player.has_won() {
-                return Ok(Some(player_index));
-            }
-
-            self.send_playing_game_state(player_index).await;
-
-            let player_id = player.user.lock().await.id.to_string();
+    /// # Errors
+    ///
+    /// Returns the player ID if the player disconnects
+    #[instrument(skip_all, fields(turn = self.table.turns_taken()))]
+    async fn execute_turn(&mut self) -> Result<Outcome, String> {
+        let seat = self.table.turn();
+        self.send_playing_game_state().await;
+        self.publish_spectator_update();
+        self.play_turn(seat).await
+    }
 
-            let message = player.user.lock().await.receiver.next().await;
-            // if no message
+    /// Waits for a valid action from the player in `seat` and plays it
+    ///
+    /// # Errors
+    ///
+    /// Returns the player ID if the player disconnects
+    #[instrument(name = "player", skip(self), fields(player_id = self.player_ids[seat]))]
+    async fn play_turn(&mut self, seat: usize) -> Result<Outcome, String> {
+        let player_id = self.player_ids[seat].clone();
+        loop {
+            let (_, elapsed, message) =
+                Self::next_message(Arc::clone(&self.users[seat]), seat).await;
+            // if disconnected
             let Some(message) = message else {
-                continue;
+                warn!(player_id, "Player disconnected");
+                return Err(player_id);
             };
 
             // if error reading
@@ -218,90 +308,126 @@ impl SkitGubbe {
             // parse msg
             let Ok(action) = serde_json::from_str::<player_messages::action::PlayAction>(&message)
             else {
-                player.notify_invalid_action().await;
+                debug!(player_id, msg = message, "Couldn't parse play action");
+                self.notify_invalid_action(seat, "Couldn't parse the action")
+                    .await;
                 continue;
             };
-
-            let card = match action {
-                player_messages::action::PlayAction::PlaceCard { card } => card,
-                player_messages::action::PlayAction::PickupStack => {
-                    player.hand.append(&mut self.playing_stack);
-                    player_index += 1;
-                    continue 'player_loop;
-                }
-            };
-
-            // check if actions are valid
-            let mut player_cards_copy = player.cards.clone();
-            if player_cards_copy.play_card(&card).is_none() {
-                player.notify_invalid_action().await;
-                continue 'player_loop;
-            }
-
-            match card.rank {
-                2 => {
-                    self.playing_stack.push(card);
-                }
-                10 => {
-                    self.playing_stack = vec![];
+            debug!(player_id, ?action, "Play action");
+
+            match self.table.play_action(seat, &action) {
+                Ok(outcome) => {
+                    self.observe_move(server_messages::Stage::Play, elapsed);
+                    self.events
+                        .lock()
+                        .unwrap()
+                        .push(server_messages::GameEvent::Play {
+                            player: player_id.clone(),
+                            action,
+                        });
+                    return Ok(outcome);
                 }
-                _ => {
-                    // if bad card
-                    if let Some(last_card) = self.playing_stack.last()
-                        && last_card.rank > card.rank
-                    {
-                        player.notify_invalid_action().await;
-                        continue 'player_loop;
-                    }
-
-                    self.playing_stack.push(card);
-
-                    // if last 4 are the same rank then restart the stack
-                    if self.playing_stack.len() >= 4
-                        && self.playing_stack[self.playing_stack.len() - 4..]
-                            .iter()
-                            .filter(|x| self.playing_stack.last().unwrap().rank == x.rank)
-                            .count()
-                            == 4
-                    {
-                        self.playing_stack = vec![];
-                    }
+                Err(e) => {
+                    debug!(player_id, "Invalid play: {e}");
+                    self.notify_invalid_action(seat, e).await;
                 }
             }
-
-            // confirm player actions
-            player.cards = player_cards_copy;
-
-            // pickup any cards if needed
-            if player.cards.hand.len() < 3 {
-                let pickup_num = 3 - player.cards.hand.len();
-                player
-                    .cards
-                    .hand
-                    .append(&mut self.deck.lock().await.pull_cards(pickup_num));
-            }
-
-            // player.send_cards().await;
-            // todo!("send actions of player to other players");
         }
+    }
+
+    /// Tells the player in `seat` their action was rejected and shows them the table again
+    async fn notify_invalid_action(&self, seat: usize, reason: &str) {
+        self.notify(
+            seat,
+            &server_messages::ServerNotification::ActionRejected(reason.to_string()),
+        )
+        .await;
+        self.send_game_state(seat).await;
+    }
 
-        Ok(None)
+    /// Sends `notification` to the player in `seat`
+    async fn notify(&self, seat: usize, notification: &server_messages::ServerNotification) {
+        let msg = serde_json::to_string(notification).unwrap();
+        let _ = self.users[seat].lock().await.send(&msg).await;
     }
 
     /// Notifies all players of end of game and the optional winner
     async fn notify_end(&self, winner: Option<usize>) {
-        let msg;
-        if let Some(winner) = winner {
-            msg = format!(
-                "Game finished, winner is {}!",
-                self.players[winner].lock().await.user.lock().await.id
-            );
-        } else {
-            msg = format!("Game finished, its a draw!",);
-        }
+        let msg = server_messages::ServerNotification::GameOver {
+            winner: winner.map(|winner| self.player_ids[winner].clone()),
+        };
+        self.notify_all_players(&serde_json::to_string(&msg).unwrap())
+            .await;
+    }
+}
 
-        for player in self.players.iter() {
-            let _ = player.lock().await.user.lock().await.send(&msg).await;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::player_messages::action::SetupAction;
+    use crate::deck::{Card, Suit};
+    use crate::user::testing::connect;
+    use crate::user::Heartbeat;
+    use tokio_tungstenite::tungstenite;
+
+    fn text(action: &SetupAction) -> Option<Result<Message, axum::Error>> {
+        Some(Ok(Message::Text(serde_json::to_string(action).unwrap())))
+    }
+
+    #[tokio::test]
+    async fn test_rejected_setup_actions_are_kept_from_spectators() {
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        };
+        let (a, mut a_client) = connect(heartbeat).await;
+        let (b, _b_client) = connect(heartbeat).await;
+        let mut game =
+            SkitGubbe::with_seed(vec![Arc::new(Mutex::new(a)), Arc::new(Mutex::new(b))], 0);
+        game.player_ids = vec!["a".to_string(), "b".to_string()];
+        let (_, mut updates) = game.spectators().subscribe();
+
+        // 15s aren't in anyone's hand
+        let claim = SetupAction::ExchangeCard {
+            hand: vec![Card {
+                rank: 15,
+                suit: Suit::Spade,
+            }],
+            bottom: 0,
+        };
+        assert!(game
+            .handle_setup_message(0, Duration::ZERO, text(&claim))
+            .await
+            .unwrap());
+        assert!(game.events().lock().unwrap().is_empty());
+        assert!(updates.try_recv().is_err());
+        // skip the heartbeat's pings
+        let rejection = loop {
+            match a_client.next().await {
+                Some(Ok(tungstenite::Message::Text(text))) => break text,
+                Some(Ok(_)) => continue,
+                message => panic!("Expected a rejection, got {message:?}"),
+            }
+        };
+        assert!(matches!(
+            serde_json::from_str(&rejection).unwrap(),
+            server_messages::ServerNotification::ActionRejected(_)
+        ));
+
+        let finish = SetupAction::FinishExchange;
+        assert!(!game
+            .handle_setup_message(0, Duration::ZERO, text(&finish))
+            .await
+            .unwrap());
+        assert_eq!(game.events().lock().unwrap().len(), 1);
+        let update = updates.try_recv().unwrap();
+        assert!(matches!(
+            update.public.last_action,
+            Some(server_messages::GameEvent::Setup {
+                action: SetupAction::FinishExchange,
+                ..
+            })
+        ));
+        assert!(updates.try_recv().is_err());
     }
 }
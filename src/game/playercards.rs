@@ -1,16 +1,22 @@
 use crate::api;
-use crate::deck::{self, Card};
-use std::mem;
+use crate::deck::{self, Card, CardIndex, CardSet};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+/// Whether `card` may be played from the hidden cards when `hidden_left` of them remain. The last
+/// hidden card can't be a 2, 10 or ace.
+pub fn can_play_hidden(card: &Card, hidden_left: usize) -> bool {
+    !((card.rank == 2 || card.rank == 10 || card.rank == deck::ACE_RANK) && hidden_left == 1)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PlayerCards {
     /// 3 flipped cards that are hidden from the player at the beginning
     hidden_cards: [Option<deck::Card>; 3],
-    /// Cards on top of the hidden cards. Each vector must contain only duplicate card ranks.
-    /// The vector can only have a maximum length of 4
-    visible_cards: Vec<Vec<deck::Card>>,
-    /// Cards in the players hand. Must be ordered during the setup stage.
-    pub hand: Vec<deck::Card>,
+    /// Cards on top of the hidden cards. Each set must contain only cards of one rank, so it
+    /// holds at most 4 cards
+    visible_cards: Vec<CardSet>,
+    /// Cards in the players hand, kept in order by the set
+    pub hand: CardSet,
 }
 
 impl PlayerCards {
@@ -21,18 +27,35 @@ impl PlayerCards {
     ) -> Self {
         Self {
             hidden_cards,
-            visible_cards,
-            hand,
+            visible_cards: visible_cards
+                .iter()
+                .map(|cards| cards.iter().collect())
+                .collect(),
+            hand: hand.iter().collect(),
         }
     }
 
     pub fn has_won(&self) -> bool {
-        return self.hand.is_empty() && self.get_bottom_cards().is_empty();
+        // the hidden slots are kept once played so check them directly
+        self.hand.is_empty()
+            && self.visible_cards.is_empty()
+            && self.hidden_cards.iter().all(Option::is_none)
+    }
+
+    /// Cards the player still has to get rid of
+    pub fn cards_left(&self) -> usize {
+        self.hand.len()
+            + self
+                .visible_cards
+                .iter()
+                .map(|cards| cards.len())
+                .sum::<usize>()
+            + self.hidden_cards.iter().flatten().count()
     }
     pub fn to_server_player_cards(&self) -> api::server_messages::Cards {
         let bottom_cards;
         if !self.visible_cards.is_empty() {
-            bottom_cards = self.visible_cards.to_vec();
+            bottom_cards = self.visible_cards();
         } else {
             bottom_cards = self
                 .hidden_cards
@@ -49,7 +72,7 @@ impl PlayerCards {
         }
 
         let cards = api::server_messages::Cards {
-            hand: self.hand.to_vec(),
+            hand: self.hand.to_cards(),
             bottom_cards,
         };
 
@@ -57,22 +80,49 @@ impl PlayerCards {
     }
 
     pub fn visible_cards(&self) -> api::server_messages::BottomCards {
-        return self.visible_cards.clone();
+        return self
+            .visible_cards
+            .iter()
+            .map(|cards| cards.to_cards())
+            .collect();
+    }
+
+    pub fn hidden_cards(&self) -> &[Option<Card>; 3] {
+        &self.hidden_cards
+    }
+
+    /// Every card the player could currently play, ignoring what is on the stack
+    pub fn playable_cards(&self) -> CardSet {
+        if !self.hand.is_empty() {
+            return self.hand;
+        }
+        if !self.visible_cards.is_empty() {
+            return self
+                .visible_cards
+                .iter()
+                .fold(CardSet::EMPTY, |all, &cards| all | cards);
+        }
+        let hidden_left = self.hidden_cards.iter().flatten().count();
+        self.hidden_cards
+            .iter()
+            .flatten()
+            .filter(|card| can_play_hidden(card, hidden_left))
+            .collect()
     }
 
     pub fn can_play(&self, card: &Card) -> bool {
+        let Some(index) = CardIndex::of(card) else {
+            return false;
+        };
+
         // hand
         if !self.hand.is_empty() {
-            return self.hand.contains(card);
+            return self.hand.contains(index);
         }
 
         // visible cards
         if !self.visible_cards.is_empty() {
-            return self
-                .visible_cards
-                .iter()
-                .find(|vec| vec[0] == *card)
-                .is_some();
+            return self.visible_cards.iter().any(|cards| cards.contains(index));
         }
 
         // hidden cards
@@ -82,11 +132,7 @@ impl PlayerCards {
             .filter(|x| x.is_some())
             .map(|x| x.as_ref().unwrap())
             .collect();
-        if card.rank == 2
-            || card.rank == 10
-            || card.rank == deck::ACE_RANK && hidden_cards.len() == 1
-        {
-            // Cant play rank 2, 10 or ACE for last card
+        if !can_play_hidden(card, hidden_cards.len()) {
             return false;
         }
         return hidden_cards.contains(&card);
@@ -102,59 +148,45 @@ impl PlayerCards {
     /// - The card is not accessible
     /// - Tries to play the last card as 2, 10 or ace
     pub fn play_card(&mut self, card: &Card) -> Option<Card> {
+        let index = CardIndex::of(card)?;
+
         // hand
         if !self.hand.is_empty() {
-            if let Some(index) = self.hand.iter().position(|x| x == card) {
-                return Some(self.hand.remove(index));
-            }
-            return None;
+            return self.hand.remove(index).then(|| card.clone());
         }
 
         // visible cards
         if !self.visible_cards.is_empty() {
-            // find if any stacks match
-            let vis_vec_index = self
+            // find the stack holding the card, several stacks can have the same rank
+            let vis_index = self
                 .visible_cards
                 .iter()
-                .map(|vec| &vec[0])
-                .position(|vis_card| vis_card.rank == card.rank)?;
+                .position(|cards| cards.contains(index))?;
 
-            let matching_vis_card_index = self.visible_cards[vis_vec_index]
-                .iter()
-                .position(|vis_card| vis_card == card)?;
-            let removed_card = self.visible_cards[vis_vec_index].remove(matching_vis_card_index);
-
-            if self.visible_cards[vis_vec_index].is_empty() {
-                self.visible_cards.swap_remove(vis_vec_index);
+            self.visible_cards[vis_index].remove(index);
+            if self.visible_cards[vis_index].is_empty() {
+                self.visible_cards.swap_remove(vis_index);
             }
 
-            return Some(removed_card);
+            return Some(card.clone());
         }
 
         // hidden cards
-        let hidden_cards: Vec<(usize, &Card)> = self
-            .hidden_cards
-            .iter()
-            .filter(|x| x.is_some())
-            .map(|x| x.as_ref().unwrap())
-            .enumerate()
-            .collect();
-        if card.rank == 2
-            || card.rank == 10
-            || card.rank == deck::ACE_RANK && hidden_cards.len() == 1
-        {
-            // Cant play rank 2, 10 or ACE for last card
+        let hidden_left = self.hidden_cards.iter().flatten().count();
+        if !can_play_hidden(card, hidden_left) {
             return None;
         }
-        let matching_hidden = hidden_cards
-            .into_iter()
-            .find(|(_, hidden_card)| **hidden_card == *card)?;
-        return self.hidden_cards[matching_hidden.0].take();
+        // the index must be into all the slots, including the ones already played
+        let matching_hidden = self
+            .hidden_cards
+            .iter()
+            .position(|hidden_card| hidden_card.as_ref() == Some(card))?;
+        self.hidden_cards[matching_hidden].take()
     }
 
     pub fn get_bottom_cards(&self) -> Vec<Vec<deck::Card>> {
         if !self.visible_cards.is_empty() {
-            return self.visible_cards.to_vec();
+            return self.visible_cards();
         } else {
             return self
                 .hidden_cards
@@ -176,23 +208,19 @@ impl PlayerCards {
     ///
     /// Validates that:
     ///     - `cards` all are the same rank
-    ///     - `cards` exist in `self.hand`. eg. if `cards` is `[4, 4]` then `self.hands` must contains two 4s
-    pub async fn exchange_cards(
+    ///     - `cards` are every card of their rank in `self.hand`. eg. if `cards` is `[4, 4]` then
+    ///       `self.hand` must contain those two 4s and no others
+    pub fn exchange_cards(
         &mut self,
-        mut cards: Vec<Card>,
+        cards: Vec<Card>,
         bottom_index: usize,
     ) -> Result<(), &'static str> {
-        let first_index_hand = self.check_given_cards_valid(&cards)?;
+        self.check_bottom_index(bottom_index)?;
+        let cards = self.check_given_cards_valid(&cards)?;
 
-        // remove the cards form `self.hand`
-        self.hand
-            .drain(first_index_hand..first_index_hand + cards.len());
-
-        // exchange the bottom cards into `self.hand` ensuring order
-        self.hand.append(&mut self.visible_cards[bottom_index]);
-        self.hand.sort();
-        // swap the cards into the bottom cards
-        mem::swap(&mut cards, &mut self.visible_cards[bottom_index]);
+        // exchange the bottom cards into `self.hand` and the cards into the bottom cards
+        self.hand = (self.hand - cards) | self.visible_cards[bottom_index];
+        self.visible_cards[bottom_index] = cards;
 
         Ok(())
     }
@@ -203,40 +231,51 @@ impl PlayerCards {
     /// If `cards` is empty
     /// If `cards` do not all have the same rank
     /// If `self.hand` does not contain `cards`
-    pub async fn compound_cards(
+    /// If `bottom_index` isn't one of the visible cards
+    pub fn compound_cards(
         &mut self,
-        mut cards: Vec<Card>,
+        cards: Vec<Card>,
         bottom_index: usize,
     ) -> Result<(), &'static str> {
-        let first_index_hand = self.check_given_cards_valid(&cards)?;
-
-        // remove the cards form `self.hand`
-        self.hand
-            .drain(first_index_hand..first_index_hand + cards.len());
+        self.check_bottom_index(bottom_index)?;
+        let rank = cards.first().map(|card| card.rank);
+        let cards = self.check_given_cards_valid(&cards)?;
 
         // check that card is same as bottom card
         // here the bottom cards will always be the visible cards
-        if cards[0].rank != self.visible_cards[bottom_index][0].rank {
+        if rank
+            != self.visible_cards[bottom_index]
+                .lowest()
+                .map(CardIndex::rank)
+        {
             return Err(
                 "card to compound doesn't have the same rank as the bottom card index rank",
             );
         }
 
-        // add to the bottom cards
-        self.visible_cards[bottom_index].append(&mut cards);
+        // move the cards from `self.hand` to the bottom cards
+        self.hand = self.hand - cards;
+        self.visible_cards[bottom_index] = self.visible_cards[bottom_index] | cards;
 
         Ok(())
     }
 
+    fn check_bottom_index(&self, bottom_index: usize) -> Result<(), &'static str> {
+        if bottom_index >= self.visible_cards.len() {
+            return Err("There are no bottom cards at that index");
+        }
+        Ok(())
+    }
+
     /// Check if `self.hand` contains `cards` and that given cards are all the same rank
     ///
     /// # Errors
     /// If `cards` is empty
     /// If `cards` do not all have the same rank
-    /// If `self.hand` does not contain `cards`
+    /// If `cards` aren't every card of their rank in `self.hand`
     ///
-    /// Returns: the index of the first occurence of a card with the rank `cards`
-    fn check_given_cards_valid(&mut self, cards: &[Card]) -> Result<usize, &'static str> {
+    /// Returns: `cards` as a set
+    fn check_given_cards_valid(&self, cards: &[Card]) -> Result<CardSet, &'static str> {
         if cards.is_empty() {
             return Err("The given cards was empty");
         }
@@ -244,28 +283,142 @@ impl PlayerCards {
             return Err("All the the given cards must have the same rank");
         }
 
-        // ensure hand is sorted before exchanging
-        self.hand.sort();
-
-        // ensure all the cards in `card` is in `self.hand`
-        // collect all indexes of matches
-        let first_index_hand = self
-            .hand
+        let given: CardSet = cards
             .iter()
-            .position(|x| cards[0].rank == x.rank)
+            .map(CardIndex::of)
+            .collect::<Option<_>>()
             .ok_or("Card to swap is not in hand")?;
-
-        let mut num_in_hand = 0;
-        for card in self.hand.iter().skip(first_index_hand) {
-            if num_in_hand == 4 || card.rank != cards[0].rank {
-                break;
-            }
-            num_in_hand += 1;
+        let in_hand = self.hand & CardSet::rank(cards[0].rank);
+        if in_hand.is_empty() {
+            return Err("Card to swap is not in hand");
         }
-        if num_in_hand != cards.len() {
+        // a card given twice only counts once in the set
+        if given.len() != cards.len() || given != in_hand {
             return Err("Your hand doesn't contain the given cards");
         }
 
-        Ok(first_index_hand)
+        Ok(given)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::Suit;
+
+    fn card(rank: u8, suit: Suit) -> Card {
+        Card { rank, suit }
+    }
+
+    #[test]
+    fn test_only_the_last_hidden_card_cant_be_a_2_10_or_ace() {
+        let cases = [
+            (2, 3, true),
+            (2, 2, true),
+            (2, 1, false),
+            (10, 3, true),
+            (10, 2, true),
+            (10, 1, false),
+            (deck::ACE_RANK, 2, true),
+            (deck::ACE_RANK, 1, false),
+            (5, 2, true),
+            (5, 1, true),
+            (13, 1, true),
+        ];
+        for (rank, hidden_left, playable) in cases {
+            assert_eq!(
+                can_play_hidden(&card(rank, Suit::Club), hidden_left),
+                playable,
+                "rank {rank} with {hidden_left} hidden left"
+            );
+        }
+    }
+
+    #[test]
+    fn test_hidden_cards_follow_the_hidden_card_rule() {
+        let ace = card(deck::ACE_RANK, Suit::Club);
+        let five = card(5, Suit::Club);
+        let mut cards = PlayerCards::new(
+            vec![],
+            vec![],
+            [Some(ace.clone()), None, Some(five.clone())],
+        );
+        assert!(cards.can_play(&ace));
+        assert_eq!(cards.play_card(&ace), Some(ace.clone()));
+
+        let mut cards = PlayerCards::new(vec![], vec![], [Some(ace.clone()), None, None]);
+        assert!(!cards.can_play(&ace));
+        assert_eq!(cards.play_card(&ace), None);
+    }
+
+    #[test]
+    fn test_hidden_cards_are_played_from_their_own_slot() {
+        let (five, six) = (card(5, Suit::Club), card(6, Suit::Club));
+        let mut cards = PlayerCards::new(
+            vec![],
+            vec![],
+            [None, Some(five.clone()), Some(six.clone())],
+        );
+        assert_eq!(cards.play_card(&six), Some(six));
+        assert_eq!(cards.hidden_cards(), &[None, Some(five), None]);
+    }
+
+    #[test]
+    fn test_has_won_once_every_card_is_played() {
+        let five = card(5, Suit::Club);
+        let mut cards = PlayerCards::new(vec![], vec![], [None, Some(five.clone()), None]);
+        assert!(!cards.has_won());
+        assert_eq!(cards.cards_left(), 1);
+        cards.play_card(&five).unwrap();
+        assert!(cards.has_won());
+
+        let cards = PlayerCards::new(vec![], vec![vec![five.clone()]], [None, None, None]);
+        assert!(!cards.has_won());
+        let cards = PlayerCards::new(vec![five], vec![], [None, None, None]);
+        assert!(!cards.has_won());
+    }
+
+    #[test]
+    fn test_visible_card_is_found_in_any_stack_of_its_rank() {
+        let (club, heart) = (card(7, Suit::Club), card(7, Suit::Heart));
+        let mut cards = PlayerCards::new(
+            vec![],
+            vec![vec![club.clone()], vec![heart.clone()]],
+            [None, None, None],
+        );
+        // the heart is only in the second stack, even though the first has the same rank
+        assert!(cards.can_play(&heart));
+        assert_eq!(cards.play_card(&heart), Some(heart.clone()));
+        assert_eq!(cards.visible_cards(), vec![vec![club.clone()]]);
+        assert!(!cards.can_play(&heart));
+        assert_eq!(cards.play_card(&heart), None);
+    }
+
+    #[test]
+    fn test_exchange_takes_the_cards_in_hand() {
+        let (club, heart, spade) = (
+            card(9, Suit::Club),
+            card(9, Suit::Heart),
+            card(9, Suit::Spade),
+        );
+        let four = card(4, Suit::Club);
+        let mut cards = PlayerCards::new(
+            vec![club.clone(), heart.clone()],
+            vec![vec![four.clone()]],
+            [None, None, None],
+        );
+        // a card that isn't in the hand, or one given twice, can't be swapped in
+        let wrong = [vec![club.clone(), spade], vec![club.clone(), club.clone()]];
+        for hand in wrong {
+            assert!(cards.exchange_cards(hand, 0).is_err());
+        }
+        // every card of the rank has to go
+        assert!(cards.exchange_cards(vec![club.clone()], 0).is_err());
+
+        cards
+            .exchange_cards(vec![heart.clone(), club.clone()], 0)
+            .unwrap();
+        assert_eq!(cards.hand.to_cards(), vec![four]);
+        assert_eq!(cards.visible_cards(), vec![vec![club, heart]]);
     }
 }
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+/// Variations of the rules a game is played with. The default is the classic game.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleSet {
+    /// Number of rounds before the game is declared a draw
+    pub max_turns: usize,
+    /// Players pick up from the deck until they hold this many cards in hand
+    pub hand_size: usize,
+    /// Whether four cards of the same rank on top of the stack clear it
+    pub clear_on_four_of_a_kind: bool,
+}
+
+/// Every player is dealt 3 hidden and 3 visible cards on top of their hand, and a game has up
+/// to 4 players, so a bigger hand wouldn't fit in the deck
+pub const MAX_HAND_SIZE: usize = 7;
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self {
+            max_turns: 300,
+            hand_size: 3,
+            clear_on_four_of_a_kind: true,
+        }
+    }
+}
+
+impl RuleSet {
+    /// # Errors
+    /// A description of the first rule that can't be played with
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_turns == 0 {
+            return Err("max_turns must be at least 1".to_string());
+        }
+        if !(1..=MAX_HAND_SIZE).contains(&self.hand_size) {
+            return Err(format!("hand_size must be between 1 and {MAX_HAND_SIZE}"));
+        }
+        Ok(())
+    }
+}
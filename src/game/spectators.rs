@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::api::server_messages::{GodView, SpectatorView};
+
+/// A view of the game after something changed
+#[derive(Clone, Debug)]
+pub struct SpectatorUpdate {
+    /// What anyone may see
+    pub public: SpectatorView,
+    /// Everything, including hands, hidden cards and the deck
+    pub god: GodView,
+}
+
+/// Publishes views of a game to anyone watching it
+#[derive(Clone)]
+pub struct Spectators {
+    sender: broadcast::Sender<SpectatorUpdate>,
+    latest: Arc<Mutex<Option<SpectatorUpdate>>>,
+}
+
+const SPECTATOR_BUFFER: usize = 64;
+
+impl Spectators {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(SPECTATOR_BUFFER).0,
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn latest(&self) -> Option<SpectatorUpdate> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Returns the latest update and a receiver for every update after it
+    pub fn subscribe(
+        &self,
+    ) -> (
+        Option<SpectatorUpdate>,
+        broadcast::Receiver<SpectatorUpdate>,
+    ) {
+        let latest = self.latest.lock().unwrap();
+        (latest.clone(), self.sender.subscribe())
+    }
+
+    pub(super) fn publish(&self, update: SpectatorUpdate) {
+        let mut latest = self.latest.lock().unwrap();
+        // no spectators isn't an error
+        let _ = self.sender.send(update.clone());
+        *latest = Some(update);
+    }
+}
+
+impl Default for Spectators {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,548 @@
+use serde::{Deserialize, Serialize};
+
+use super::playercards::PlayerCards;
+use super::rules::RuleSet;
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+use crate::api::server_messages::{
+    self, FullPlayerCards, GameEvent, GodView, PublicPlayer, SpectatorView, Stage,
+};
+use crate::deck::{Card, CardSet, Deck};
+
+/// Whether `card` may be placed on a stack whose top card is `top`. 2s and 10s can go on
+/// anything, other cards must be at least as high as the top card.
+pub fn can_place_on(top: Option<&Card>, card: &Card) -> bool {
+    match top {
+        _ if card.rank == 2 || card.rank == 10 => true,
+        Some(top) => top.rank <= card.rank,
+        None => true,
+    }
+}
+
+/// How a game stands after an action
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ongoing,
+    /// The seat of the winner
+    Won(usize),
+    /// Every seat used up its turns without anyone winning
+    Draw,
+}
+
+/// The cards and turn order of a game, without any connections or player ids.
+///
+/// Players are referred to by their seat, the index they were dealt in. Everything needed to
+/// carry on a game is in here so it can be checkpointed, restored or simulated.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Table {
+    players: Vec<PlayerCards>,
+    deck: Deck,
+    playing_stack: Vec<Card>,
+    stage: Stage,
+    /// Seat whose turn it is during the play stage
+    turn: usize,
+    /// Turns taken during the play stage
+    turns_taken: usize,
+    /// Seats that have finished swapping cards
+    finished_setup: Vec<bool>,
+    rules: RuleSet,
+    /// The deck was shuffled with this, nothing else in a game is random
+    seed: u64,
+}
+
+impl Table {
+    /// Deals a new game for `players` seats from a deck shuffled with `seed`
+    pub fn deal(players: usize, seed: u64, rules: RuleSet) -> Self {
+        assert!(players <= 4, "Skit Gubbe game must be 4 players or less");
+
+        let mut deck = Deck::from_seed(seed);
+        let players: Vec<PlayerCards> = (0..players)
+            .map(|_| {
+                let hidden_cards: [Option<Card>; 3] = core::array::from_fn(|_| {
+                    Some(deck.pull_card().expect("Should have enough cards"))
+                });
+                let visible_cards: [Vec<Card>; 3] = core::array::from_fn(|_| {
+                    let x = deck.pull_cards(1);
+                    assert_eq!(x.len(), 1, "Should have enough cards");
+                    x
+                });
+                let hand = deck.pull_cards(rules.hand_size);
+
+                PlayerCards::new(hand, visible_cards.to_vec(), hidden_cards)
+            })
+            .collect();
+
+        Self {
+            finished_setup: vec![false; players.len()],
+            players,
+            deck,
+            playing_stack: vec![],
+            stage: Stage::Swap,
+            turn: 0,
+            turns_taken: 0,
+            rules,
+            seed,
+        }
+    }
+
+    /// A table part way through the play stage, for simulating how a game could carry on from a
+    /// position. Turns are counted from here and the table has no seed.
+    pub fn in_play(
+        players: Vec<PlayerCards>,
+        deck: Deck,
+        playing_stack: Vec<Card>,
+        turn: usize,
+        rules: RuleSet,
+    ) -> Self {
+        Self {
+            finished_setup: vec![true; players.len()],
+            players,
+            deck,
+            playing_stack,
+            stage: Stage::Play,
+            turn,
+            turns_taken: 0,
+            rules,
+            seed: 0,
+        }
+    }
+
+    pub fn seats(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn player(&self, seat: usize) -> &PlayerCards {
+        &self.players[seat]
+    }
+
+    pub fn deck(&self) -> &Deck {
+        &self.deck
+    }
+
+    pub fn playing_stack(&self) -> &[Card] {
+        &self.playing_stack
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// Seat whose turn it is, only meaningful during the play stage
+    pub fn turn(&self) -> usize {
+        self.turn
+    }
+
+    pub fn turns_taken(&self) -> usize {
+        self.turns_taken
+    }
+
+    pub fn rules(&self) -> &RuleSet {
+        &self.rules
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn has_finished_setup(&self, seat: usize) -> bool {
+        self.finished_setup[seat]
+    }
+
+    /// Exchanges or compounds cards for `seat` during the swap stage. Once every seat has
+    /// finished the game moves to the play stage.
+    ///
+    /// # Errors
+    /// If it isn't the swap stage, the seat has already finished or the swap is invalid, in which
+    /// case nothing changes
+    pub fn setup_action(&mut self, seat: usize, action: SetupAction) -> Result<(), &'static str> {
+        if self.stage != Stage::Swap || self.finished_setup[seat] {
+            return Err("You can't swap cards now");
+        }
+
+        match action {
+            SetupAction::ExchangeCard { hand, bottom } => {
+                self.players[seat].exchange_cards(hand, bottom)?;
+            }
+            SetupAction::CompoundCard { hand, bottom } => {
+                let mut cards = self.players[seat].clone();
+                cards.compound_cards(hand, bottom)?;
+                self.players[seat] = cards;
+                self.pick_up(seat);
+            }
+            SetupAction::FinishExchange => {
+                self.finished_setup[seat] = true;
+                if self.finished_setup.iter().all(|&finished| finished) {
+                    self.stage = Stage::Play;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `card` may go on top of the stack
+    pub fn can_place(&self, card: &Card) -> bool {
+        can_place_on(self.playing_stack.last(), card)
+    }
+
+    /// Cards `seat` could place on the stack right now, none if it isn't their turn
+    pub fn placeable_cards(&self, seat: usize) -> CardSet {
+        if self.stage != Stage::Play || seat != self.turn {
+            return CardSet::EMPTY;
+        }
+        let top = self.playing_stack.last().map(|card| card.rank);
+        self.players[seat].playable_cards().placeable_on(top)
+    }
+
+    /// Every action `seat` could take right now, lowest card first, empty if it isn't their turn
+    pub fn legal_plays(&self, seat: usize) -> Vec<PlayAction> {
+        if self.stage != Stage::Play || seat != self.turn {
+            return vec![];
+        }
+
+        let mut actions: Vec<PlayAction> = self
+            .placeable_cards(seat)
+            .iter()
+            .map(|card| PlayAction::PlaceCard { card: card.card() })
+            .collect();
+        actions.push(PlayAction::PickupStack);
+        actions
+    }
+
+    /// Plays a turn for `seat`. Clearing the stack with a 10 or four of a kind lets the same
+    /// seat play again, otherwise the turn passes on.
+    ///
+    /// # Errors
+    /// If it isn't the seat's turn or the action is invalid, in which case nothing changes
+    pub fn play_action(
+        &mut self,
+        seat: usize,
+        action: &PlayAction,
+    ) -> Result<Outcome, &'static str> {
+        if self.stage != Stage::Play {
+            return Err("The game isn't in the play stage");
+        }
+        if seat != self.turn {
+            return Err("It isn't your turn");
+        }
+
+        let mut play_again = false;
+        match action {
+            PlayAction::PickupStack => {
+                let player = &mut self.players[seat];
+                player.hand = player.hand | self.playing_stack.iter().collect();
+                self.playing_stack.clear();
+            }
+            PlayAction::PlaceCard { card } => {
+                if !self.can_place(card) {
+                    return Err("Card is lower than the top of the stack");
+                }
+                // nothing is taken if the card can't be played
+                if self.players[seat].play_card(card).is_none() {
+                    return Err("You can't play that card");
+                }
+
+                self.playing_stack.push(card.clone());
+                if card.rank == 10 || self.rules.clear_on_four_of_a_kind && self.four_of_a_kind() {
+                    self.playing_stack.clear();
+                    play_again = true;
+                }
+                self.pick_up(seat);
+            }
+        }
+
+        self.turns_taken += 1;
+        if self.players[seat].has_won() {
+            return Ok(Outcome::Won(seat));
+        }
+        if !play_again {
+            self.turn = (self.turn + 1) % self.players.len();
+        }
+        if self.turns_taken >= self.rules.max_turns * self.players.len() {
+            return Ok(Outcome::Draw);
+        }
+        Ok(Outcome::Ongoing)
+    }
+
+    /// Whether the last 4 cards on the stack are the same rank
+    fn four_of_a_kind(&self) -> bool {
+        let Some(last) = self.playing_stack.last() else {
+            return false;
+        };
+        self.playing_stack.len() >= 4
+            && self.playing_stack[self.playing_stack.len() - 4..]
+                .iter()
+                .all(|card| card.rank == last.rank)
+    }
+
+    /// Fills up the seat's hand from the deck
+    fn pick_up(&mut self, seat: usize) {
+        let hand = &mut self.players[seat].hand;
+        if hand.len() < self.rules.hand_size {
+            let pickup_num = self.rules.hand_size - hand.len();
+            *hand = *hand | self.deck.pull_cards(pickup_num).iter().collect();
+        }
+    }
+
+    /// What `seat` can see, `ids` are the player ids in seat order
+    pub fn view_for(&self, seat: usize, ids: &[String]) -> server_messages::GameState {
+        let (turn, other_players) = match self.stage {
+            Stage::Swap => (String::new(), vec![]),
+            Stage::Play => (
+                ids[self.turn].clone(),
+                ids.iter()
+                    .cloned()
+                    .zip(self.players.iter().map(|player| player.visible_cards()))
+                    .collect(),
+            ),
+        };
+
+        server_messages::GameState {
+            turn,
+            stage: self.stage,
+            cards: self.players[seat].to_server_player_cards(),
+            stack: match self.stage {
+                Stage::Swap => vec![],
+                Stage::Play => self.playing_stack.clone(),
+            },
+            other_players,
+        }
+    }
+
+    /// What spectators and admins can see, `ids` are the player ids in seat order
+    pub fn spectator_views(
+        &self,
+        ids: &[String],
+        last_action: Option<GameEvent>,
+    ) -> (SpectatorView, GodView) {
+        let public = SpectatorView {
+            turn: match self.stage {
+                Stage::Swap => String::new(),
+                Stage::Play => ids[self.turn].clone(),
+            },
+            stage: self.stage,
+            stack: self.playing_stack.clone(),
+            deck_size: self.deck.cards.len(),
+            players: ids
+                .iter()
+                .zip(&self.players)
+                .map(|(id, player)| PublicPlayer {
+                    id: id.clone(),
+                    hand_size: player.hand.len(),
+                    visible_cards: player.visible_cards(),
+                    hidden_cards: player.hidden_cards().iter().flatten().count(),
+                })
+                .collect(),
+            last_action,
+        };
+        let god = GodView {
+            view: public.clone(),
+            players: ids
+                .iter()
+                .zip(&self.players)
+                .map(|(id, player)| FullPlayerCards {
+                    id: id.clone(),
+                    hand: player.hand.to_cards(),
+                    visible_cards: player.visible_cards(),
+                    hidden_cards: player.hidden_cards().to_vec(),
+                })
+                .collect(),
+            deck: self.deck.cards.clone(),
+        };
+        (public, god)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::{CardIndex, Suit};
+
+    fn card(rank: u8) -> Card {
+        Card {
+            rank,
+            suit: Suit::Heart,
+        }
+    }
+
+    fn suited(rank: u8, suit: Suit) -> Card {
+        Card { rank, suit }
+    }
+
+    fn play_table(seats: usize) -> Table {
+        let mut table = Table::deal(seats, 1, RuleSet::default());
+        for seat in 0..seats {
+            table
+                .setup_action(seat, SetupAction::FinishExchange)
+                .unwrap();
+        }
+        table
+    }
+
+    #[test]
+    fn test_play_starts_once_everyone_finished_setup() {
+        let mut table = Table::deal(2, 1, RuleSet::default());
+        table.setup_action(0, SetupAction::FinishExchange).unwrap();
+        assert_eq!(table.stage(), Stage::Swap);
+        table.setup_action(1, SetupAction::FinishExchange).unwrap();
+        assert_eq!(table.stage(), Stage::Play);
+    }
+
+    #[test]
+    fn test_setup_finishes_in_any_order() {
+        let mut table = Table::deal(3, 1, RuleSet::default());
+        table.setup_action(2, SetupAction::FinishExchange).unwrap();
+        assert!(table.has_finished_setup(2));
+        assert!(!table.has_finished_setup(0));
+        table.setup_action(0, SetupAction::FinishExchange).unwrap();
+        assert_eq!(table.stage(), Stage::Swap);
+        table.setup_action(1, SetupAction::FinishExchange).unwrap();
+        assert_eq!(table.stage(), Stage::Play);
+    }
+
+    #[test]
+    fn test_turn_passes_after_a_play() {
+        let mut table = play_table(3);
+        table.players[0].hand = [card(5), card(6)].iter().collect();
+        assert_eq!(
+            table.play_action(0, &PlayAction::PlaceCard { card: card(5) }),
+            Ok(Outcome::Ongoing)
+        );
+        assert_eq!(table.turn(), 1);
+        assert!(table
+            .play_action(0, &PlayAction::PlaceCard { card: card(6) })
+            .is_err());
+        assert!(table.play_action(2, &PlayAction::PickupStack).is_err());
+
+        assert_eq!(
+            table.play_action(1, &PlayAction::PickupStack),
+            Ok(Outcome::Ongoing)
+        );
+        assert_eq!(table.turn(), 2);
+    }
+
+    #[test]
+    fn test_lower_card_is_rejected() {
+        let mut table = play_table(2);
+        table.playing_stack.push(card(13));
+        table.players[0].hand = [card(5)].iter().collect();
+        assert!(table.legal_plays(0) == [PlayAction::PickupStack]);
+        assert!(table
+            .play_action(0, &PlayAction::PlaceCard { card: card(5) })
+            .is_err());
+        assert_eq!(table.player(0).hand.to_cards(), vec![card(5)]);
+    }
+
+    #[test]
+    fn test_ten_clears_the_stack_and_plays_again() {
+        let mut table = play_table(2);
+        table.playing_stack.push(card(13));
+        table.players[0].hand.insert(CardIndex::from(&card(10)));
+        table
+            .play_action(0, &PlayAction::PlaceCard { card: card(10) })
+            .unwrap();
+        // the 10 is played onto the stack, which is then cleared along with it
+        assert!(table.playing_stack().is_empty());
+        assert!(!table.player(0).hand.contains(CardIndex::from(&card(10))));
+        assert_eq!(table.turn(), 0);
+    }
+
+    #[test]
+    fn test_four_of_a_kind_clears_the_stack_and_plays_again() {
+        let mut table = play_table(2);
+        table.playing_stack = vec![
+            suited(7, Suit::Club),
+            suited(7, Suit::Diamond),
+            suited(7, Suit::Spade),
+        ];
+        let seven = suited(7, Suit::Heart);
+        table.players[0].hand.insert(CardIndex::from(&seven));
+        table
+            .play_action(0, &PlayAction::PlaceCard { card: seven })
+            .unwrap();
+        assert!(table.playing_stack().is_empty());
+        assert_eq!(table.turn(), 0);
+
+        let mut table = play_table(2);
+        table.rules.clear_on_four_of_a_kind = false;
+        table.playing_stack = vec![
+            suited(7, Suit::Club),
+            suited(7, Suit::Diamond),
+            suited(7, Suit::Spade),
+        ];
+        let seven = suited(7, Suit::Heart);
+        table.players[0].hand.insert(CardIndex::from(&seven));
+        table
+            .play_action(0, &PlayAction::PlaceCard { card: seven })
+            .unwrap();
+        assert_eq!(table.playing_stack().len(), 4);
+        assert_eq!(table.turn(), 1);
+    }
+
+    #[test]
+    fn test_playing_the_last_hidden_card_wins() {
+        let mut table = play_table(2);
+        table.deck.cards.clear();
+        table.players[0] = PlayerCards::new(vec![], vec![], [None, Some(card(8)), Some(card(9))]);
+        table
+            .play_action(0, &PlayAction::PlaceCard { card: card(8) })
+            .unwrap();
+        table.play_action(1, &PlayAction::PickupStack).unwrap();
+        assert_eq!(
+            table.play_action(0, &PlayAction::PlaceCard { card: card(9) }),
+            Ok(Outcome::Won(0))
+        );
+    }
+
+    #[test]
+    fn test_game_is_a_draw_after_max_turns() {
+        let rules = RuleSet {
+            max_turns: 1,
+            ..RuleSet::default()
+        };
+        let mut table = Table::deal(2, 1, rules);
+        table.setup_action(0, SetupAction::FinishExchange).unwrap();
+        table.setup_action(1, SetupAction::FinishExchange).unwrap();
+        assert_eq!(
+            table.play_action(0, &PlayAction::PickupStack),
+            Ok(Outcome::Ongoing)
+        );
+        assert_eq!(
+            table.play_action(1, &PlayAction::PickupStack),
+            Ok(Outcome::Draw)
+        );
+
+        // max_turns is per seat, so three players get three turns
+        let mut table = Table::deal(
+            3,
+            1,
+            RuleSet {
+                max_turns: 1,
+                ..RuleSet::default()
+            },
+        );
+        for seat in 0..3 {
+            table
+                .setup_action(seat, SetupAction::FinishExchange)
+                .unwrap();
+        }
+        for seat in 0..2 {
+            assert_eq!(
+                table.play_action(seat, &PlayAction::PickupStack),
+                Ok(Outcome::Ongoing)
+            );
+        }
+        assert_eq!(
+            table.play_action(2, &PlayAction::PickupStack),
+            Ok(Outcome::Draw)
+        );
+    }
+
+    #[test]
+    fn test_table_survives_a_round_trip() {
+        let table = play_table(4);
+        let json = serde_json::to_string(&table).unwrap();
+        let restored: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.deck().cards, table.deck().cards);
+        assert_eq!(restored.player(3).hand, table.player(3).hand);
+        assert_eq!(restored.stage(), table.stage());
+    }
+}
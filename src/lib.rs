@@ -4,3 +4,7 @@ pub mod game;
 pub mod user;
 pub mod api;
 pub mod deck;
+pub mod bot;
+pub mod stats;
+pub mod simulation;
+pub mod tournament;
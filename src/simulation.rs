@@ -0,0 +1,270 @@
+//! Plays large batches of games between the same bots on every core.
+//!
+//! Deals are split into chunks of a fixed size that a pool of threads takes turns picking up.
+//! Every deal is played by bots made for it from its own seed, and the statistics of each chunk
+//! are merged in order at the end, so a simulation comes out the same whatever the number of
+//! threads.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::bot::local::{play_duplicate, play_game, DuplicateGame};
+use crate::bot::Strategy;
+use crate::game::{Outcome, RuleSet};
+use crate::stats::Scores;
+use crate::tournament::{game_seed, FailedToStart, Participant};
+
+/// Deals a thread plays before it records them and picks up more
+const CHUNK_DEALS: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    pub deals: usize,
+    /// Plays each deal from every seating of the bots, so everyone gets the same cards
+    pub duplicate: bool,
+    pub seed: u64,
+    /// Games played at the same time
+    pub threads: usize,
+    pub rules: RuleSet,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            deals: 1,
+            duplicate: false,
+            seed: 0,
+            threads: std::thread::available_parallelism().map_or(1, usize::from),
+            rules: RuleSet::default(),
+        }
+    }
+}
+
+/// How one of the bots did
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BotStats {
+    pub wins: usize,
+    pub forfeits: usize,
+    pub rejections: usize,
+    /// Deals where the bot did best over all of the deal's games, shared between any that tied
+    pub deals_won: f64,
+    /// The first deal the bot forfeited and why
+    pub first_forfeit: Option<(usize, String)>,
+}
+
+/// What happened over the deals of a simulation
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationStats {
+    pub deals: usize,
+    pub games: usize,
+    pub draws: usize,
+    /// In the order of the participants
+    pub bots: Vec<BotStats>,
+    /// `head_to_head[a][b]` has a score per deal of 1 for every game `a` finished ahead of `b`
+    /// and ½ for every tie, averaged over the deal's games
+    pub head_to_head: Vec<Vec<Scores>>,
+}
+
+impl SimulationStats {
+    pub fn new(bots: usize) -> Self {
+        Self {
+            deals: 0,
+            games: 0,
+            draws: 0,
+            bots: vec![BotStats::default(); bots],
+            head_to_head: vec![vec![Scores::default(); bots]; bots],
+        }
+    }
+
+    /// Adds the games played with deal number `deal`
+    pub fn record(&mut self, deal: usize, games: &[DuplicateGame]) {
+        let bots = self.bots.len();
+        // what each bot scored with these cards, wherever it sat
+        let mut points = vec![0.0; bots];
+        let mut ahead = vec![vec![0.0; bots]; bots];
+        for DuplicateGame { seating, result } in games {
+            self.games += 1;
+            match result.outcome {
+                Outcome::Won(seat) => self.bots[seating[seat]].wins += 1,
+                Outcome::Draw | Outcome::Ongoing => self.draws += 1,
+            }
+            for (seat, rejected) in result.rejections.iter().enumerate() {
+                self.bots[seating[seat]].rejections += rejected;
+            }
+            let positions = result.positions();
+            for (seat, position) in positions.iter().enumerate() {
+                points[seating[seat]] += bots as f64 - position;
+                for (other, other_position) in positions.iter().enumerate() {
+                    let score = head_to_head_score(*position, *other_position);
+                    ahead[seating[seat]][seating[other]] += score;
+                }
+            }
+            if let Some(forfeit) = &result.forfeit {
+                let bot = &mut self.bots[seating[forfeit.seat]];
+                bot.forfeits += 1;
+                bot.first_forfeit
+                    .get_or_insert_with(|| (deal, forfeit.reason.clone()));
+            }
+        }
+
+        self.deals += 1;
+        for (scores, ahead) in self.head_to_head.iter_mut().zip(ahead) {
+            for (scores, ahead) in scores.iter_mut().zip(ahead) {
+                scores.add(ahead / games.len() as f64);
+            }
+        }
+        let best = points.iter().copied().fold(f64::MIN, f64::max);
+        let leaders = points.iter().filter(|&&p| p == best).count();
+        for (bot, &p) in self.bots.iter_mut().zip(&points) {
+            if p == best {
+                bot.deals_won += 1.0 / leaders as f64;
+            }
+        }
+    }
+
+    /// Adds the deals of `other`, which were played after these
+    pub fn merge(&mut self, other: &SimulationStats) {
+        self.deals += other.deals;
+        self.games += other.games;
+        self.draws += other.draws;
+        for (bot, other) in self.bots.iter_mut().zip(&other.bots) {
+            bot.wins += other.wins;
+            bot.forfeits += other.forfeits;
+            bot.rejections += other.rejections;
+            bot.deals_won += other.deals_won;
+            if bot.first_forfeit.is_none() {
+                bot.first_forfeit.clone_from(&other.first_forfeit);
+            }
+        }
+        for (scores, other) in self.head_to_head.iter_mut().zip(&other.head_to_head) {
+            for (scores, other) in scores.iter_mut().zip(other) {
+                scores.merge(other);
+            }
+        }
+    }
+}
+
+/// 1 for finishing ahead, ½ for a tie and 0 for finishing behind
+pub fn head_to_head_score(position: f64, other: f64) -> f64 {
+    match position.total_cmp(&other) {
+        std::cmp::Ordering::Less => 1.0,
+        std::cmp::Ordering::Equal => 0.5,
+        std::cmp::Ordering::Greater => 0.0,
+    }
+}
+
+/// Plays `config.deals` deals between `participants` on `config.threads` threads
+pub fn run(participants: &[Participant], config: &SimulationConfig) -> SimulationStats {
+    assert!(
+        (2..=4).contains(&participants.len()),
+        "A game needs 2 to 4 bots"
+    );
+    let chunks = config.deals.div_ceil(CHUNK_DEALS);
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; chunks]);
+    std::thread::scope(|scope| {
+        for _ in 0..config.threads.clamp(1, chunks.max(1)) {
+            scope.spawn(|| loop {
+                let chunk = next.fetch_add(1, Ordering::Relaxed);
+                if chunk >= chunks {
+                    break;
+                }
+                let start = chunk * CHUNK_DEALS;
+                let mut stats = SimulationStats::new(participants.len());
+                for deal in start..config.deals.min(start + CHUNK_DEALS) {
+                    let games = play_deal(participants, deal, config);
+                    stats.record(deal, &games);
+                }
+                results.lock().unwrap()[chunk] = Some(stats);
+            });
+        }
+    });
+
+    let mut stats = SimulationStats::new(participants.len());
+    for chunk in results.into_inner().unwrap() {
+        stats.merge(&chunk.expect("Every chunk is played"));
+    }
+    stats
+}
+
+fn play_deal(
+    participants: &[Participant],
+    deal: usize,
+    config: &SimulationConfig,
+) -> Vec<DuplicateGame> {
+    let seed = game_seed(config.seed, deal as u64);
+    let mut bots: Vec<Box<dyn Strategy>> = participants
+        .iter()
+        .enumerate()
+        .map(|(i, participant)| {
+            (participant.make)(seed.wrapping_add(i as u64 + 1))
+                .unwrap_or_else(|reason| Box::new(FailedToStart(reason)))
+        })
+        .collect();
+    let mut strategies: Vec<&mut dyn Strategy> = bots
+        .iter_mut()
+        .map(|bot| bot.as_mut() as &mut dyn Strategy)
+        .collect();
+    if config.duplicate {
+        play_duplicate(&mut strategies, seed, config.rules.clone())
+    } else {
+        let result = play_game(&mut strategies, seed, config.rules.clone());
+        let seating = (0..strategies.len()).collect();
+        vec![DuplicateGame { seating, result }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::reference::{Heuristic, LowestCard, Random};
+
+    fn participants() -> Vec<Participant> {
+        vec![
+            Participant::new("random", |seed| Ok(Box::new(Random::new(seed)))),
+            Participant::new("lowest", |_| Ok(Box::new(LowestCard))),
+            Participant::new("heuristic", |_| Ok(Box::new(Heuristic))),
+        ]
+    }
+
+    #[test]
+    fn test_results_dont_depend_on_threads() {
+        let config = SimulationConfig {
+            deals: 150,
+            seed: 7,
+            threads: 1,
+            ..SimulationConfig::default()
+        };
+        let single = run(&participants(), &config);
+        assert_eq!(single.deals, 150);
+        assert_eq!(single.games, 150);
+        let wins: usize = single.bots.iter().map(|bot| bot.wins).sum();
+        assert_eq!(wins + single.draws, 150);
+
+        let threaded = run(
+            &participants(),
+            &SimulationConfig {
+                threads: 4,
+                ..config
+            },
+        );
+        assert_eq!(single, threaded);
+    }
+
+    #[test]
+    fn test_duplicate_plays_every_seating() {
+        let config = SimulationConfig {
+            deals: 3,
+            duplicate: true,
+            threads: 2,
+            ..SimulationConfig::default()
+        };
+        let stats = run(&participants(), &config);
+        assert_eq!(stats.games, 3 * 6);
+        let deals_won: f64 = stats.bots.iter().map(|bot| bot.deals_won).sum();
+        assert!((deals_won - 3.0).abs() < 1e-9);
+        // every pair scores 1 between them
+        let pair = stats.head_to_head[0][1].mean() + stats.head_to_head[1][0].mean();
+        assert!((pair - 1.0).abs() < 1e-9);
+    }
+}
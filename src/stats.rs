@@ -0,0 +1,213 @@
+//! Statistics for telling whether one bot really is better than another.
+
+/// How many standard deviations either side of the mean cover 95% of a normal distribution
+pub const Z_95: f64 = 1.959_964;
+
+/// A range that the true value is within at the confidence it was made with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    pub low: f64,
+    pub high: f64,
+}
+
+/// The Wilson score interval of a win rate, which unlike mean ± error stays between 0 and 1
+/// even with few games or lopsided results
+pub fn win_rate_interval(wins: usize, games: usize, z: f64) -> Interval {
+    if games == 0 {
+        return Interval {
+            low: 0.0,
+            high: 1.0,
+        };
+    }
+    let (n, p) = (games as f64, wins as f64 / games as f64);
+    let centre = p + z * z / (2.0 * n);
+    let spread = z * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt();
+    let scale = 1.0 + z * z / n;
+    Interval {
+        low: (centre - spread) / scale,
+        high: (centre + spread) / scale,
+    }
+}
+
+/// A running mean and variance of scores, eg. 1 for a win, ½ for a tie and 0 for a loss
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scores {
+    count: usize,
+    sum: f64,
+    sum_of_squares: f64,
+}
+
+impl Scores {
+    pub fn add(&mut self, score: f64) {
+        self.count += 1;
+        self.sum += score;
+        self.sum_of_squares += score * score;
+    }
+
+    /// Adds every score of `other`
+    pub fn merge(&mut self, other: &Scores) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_of_squares += other.sum_of_squares;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count.max(1) as f64
+    }
+
+    /// The sample variance, 0 until there are two scores
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        let n = self.count as f64;
+        ((self.sum_of_squares - self.sum * self.sum / n) / (n - 1.0)).max(0.0)
+    }
+
+    /// Where the true mean is, from the normal approximation
+    pub fn interval(&self, z: f64) -> Interval {
+        let error = z * (self.variance() / self.count.max(1) as f64).sqrt();
+        Interval {
+            low: self.mean() - error,
+            high: self.mean() + error,
+        }
+    }
+}
+
+/// What a sequential probability ratio test decided
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The candidate is better by at least as much as was asked
+    Accept,
+    /// The candidate is no better than the baseline
+    Reject,
+}
+
+/// A sequential probability ratio test of whether a candidate scores better than a baseline.
+///
+/// Scores are added until the log likelihood ratio of "the candidate is better by
+/// `better_by`" against "the candidate is no better" leaves the bounds set by the error rates,
+/// which usually takes far fewer games than a fixed size test. The likelihoods are
+/// approximated with a normal distribution of the scores, so they can be averages of a few
+/// games, eg. a deal played from both seats.
+#[derive(Clone, Debug)]
+pub struct Sprt {
+    /// The candidate's expected score if it is no better
+    s0: f64,
+    /// The candidate's expected score if it is better by as much as asked
+    s1: f64,
+    lower: f64,
+    upper: f64,
+    scores: Scores,
+}
+
+impl Sprt {
+    /// Tests whether the candidate is better by `better_by`, eg. 0.1 for 10%. Being 10% better
+    /// means its odds of beating the baseline are 1.1 to 1, an expected score of 1.1 / 2.1.
+    /// `alpha` is the chance of accepting a candidate that is no better and `beta` the chance
+    /// of rejecting one that is.
+    pub fn new(better_by: f64, alpha: f64, beta: f64) -> Self {
+        Self {
+            s0: 0.5,
+            s1: (1.0 + better_by) / (2.0 + better_by),
+            lower: (beta / (1.0 - alpha)).ln(),
+            upper: ((1.0 - beta) / alpha).ln(),
+            scores: Scores::default(),
+        }
+    }
+
+    pub fn add(&mut self, score: f64) {
+        self.scores.add(score);
+    }
+
+    pub fn scores(&self) -> &Scores {
+        &self.scores
+    }
+
+    /// The log likelihood ratio so far, the test ends once it leaves [`Sprt::bounds`]
+    pub fn llr(&self) -> f64 {
+        // scores that are all the same, eg. a candidate that wins every deal, have no variance to
+        // go on, so those fall back on the variance of a coin flip at the baseline's score
+        let variance = match self.scores.variance() {
+            variance if variance > 0.0 => variance,
+            _ => self.s0 * (1.0 - self.s0),
+        };
+        let n = self.scores.len() as f64;
+        let (s0, s1) = (self.s0, self.s1);
+        (s1 - s0) * (2.0 * self.scores.sum - n * (s0 + s1)) / (2.0 * variance)
+    }
+
+    pub fn bounds(&self) -> (f64, f64) {
+        (self.lower, self.upper)
+    }
+
+    /// `None` while more scores are needed
+    pub fn decision(&self) -> Option<Decision> {
+        let llr = self.llr();
+        if llr >= self.upper {
+            Some(Decision::Accept)
+        } else if llr <= self.lower {
+            Some(Decision::Reject)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_win_rate_interval() {
+        let interval = win_rate_interval(50, 100, Z_95);
+        assert!((interval.low - 0.4038).abs() < 1e-4, "{interval:?}");
+        assert!((interval.high - 0.5962).abs() < 1e-4, "{interval:?}");
+
+        let interval = win_rate_interval(0, 10, Z_95);
+        assert_eq!(interval.low, 0.0);
+        assert!(interval.high > 0.0 && interval.high < 0.35);
+    }
+
+    #[test]
+    fn test_sprt_decides() {
+        // wins, ties and losses in a repeating pattern, scoring 0.6 and 0.5 on average
+        let better = [1.0, 1.0, 1.0, 0.5, 0.0, 0.0, 1.0, 0.5, 0.5, 0.5];
+        let equal = [1.0, 0.0, 0.5, 1.0, 0.0, 0.5, 0.5, 1.0, 0.0, 0.5];
+
+        for (scores, expected) in [(better, Decision::Accept), (equal, Decision::Reject)] {
+            let mut sprt = Sprt::new(0.1, 0.05, 0.05);
+            let mut games = 0;
+            while sprt.decision().is_none() {
+                sprt.add(scores[games % scores.len()]);
+                games += 1;
+                assert!(games < 100_000, "The test should end");
+            }
+            assert_eq!(sprt.decision(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_sprt_decides_on_constant_scores() {
+        for (score, expected) in [
+            (1.0, Decision::Accept),
+            (0.5, Decision::Reject),
+            (0.0, Decision::Reject),
+        ] {
+            let mut sprt = Sprt::new(0.1, 0.05, 0.05);
+            while sprt.decision().is_none() {
+                sprt.add(score);
+                assert!(sprt.scores().len() < 100_000, "The test should end");
+            }
+            assert_eq!(sprt.decision(), Some(expected), "{score}");
+        }
+    }
+}
@@ -0,0 +1,604 @@
+//! Competitions between bots.
+//!
+//! A tournament is played in rounds of matches. At a match a table of participants plays a game
+//! from every rotation of the seats, `games_per_seat` times over, each with a deal of its own.
+//! The games of a round are played in parallel and the standings are saved after every round.
+//!
+//! Tournaments are run offline, away from any server, so they are stored in a JSON file of their
+//! own rather than in the server's storage. The file holds the whole [`Tournament`], so standings
+//! and every game can be read back with [`Tournament::load`].
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::player_messages::action::{PlayAction, SetupAction};
+use crate::bot::local::{permutations, play_game, GameResult};
+use crate::bot::{Strategy, View};
+use crate::game::{Outcome, RuleSet};
+
+/// Makes a bot for a game, given a seed for it to use
+pub type BotFactory = Arc<dyn Fn(u64) -> Result<Box<dyn Strategy>, String> + Send + Sync>;
+
+/// A bot entered in a tournament. A new one is made for every game, so they can be played at
+/// the same time.
+#[derive(Clone)]
+pub struct Participant {
+    pub name: String,
+    pub(crate) make: BotFactory,
+}
+
+impl Participant {
+    pub fn new(
+        name: impl Into<String>,
+        make: impl Fn(u64) -> Result<Box<dyn Strategy>, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            make: Arc::new(make),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Every combination of participants meets at a table
+    RoundRobin,
+    /// Each round participants meet others with about as many points, avoiding anyone they have
+    /// met before
+    Swiss { rounds: usize },
+    /// Only the winner of each table goes through to the next round, until one is left
+    Knockout,
+}
+
+#[derive(Clone, Debug)]
+pub struct TournamentConfig {
+    pub format: Format,
+    /// Seats at a table, some tables are smaller when the participants don't divide evenly
+    pub table_size: usize,
+    /// Games a match plays from each rotation of the seats, or deals it plays in duplicate
+    pub games_per_seat: usize,
+    /// Plays each deal from every seating of the table, so everyone gets the same cards
+    pub duplicate: bool,
+    pub seed: u64,
+    /// Games played at the same time
+    pub threads: usize,
+    pub rules: RuleSet,
+    /// The JSON file the tournament is saved to after every round, `None` keeps it in memory only
+    pub storage_path: Option<PathBuf>,
+}
+
+impl Default for TournamentConfig {
+    fn default() -> Self {
+        Self {
+            format: Format::RoundRobin,
+            table_size: 2,
+            games_per_seat: 1,
+            duplicate: false,
+            seed: 0,
+            threads: std::thread::available_parallelism().map_or(1, usize::from),
+            rules: RuleSet::default(),
+            storage_path: None,
+        }
+    }
+}
+
+/// A game played in a tournament. Participants are referred to by their index.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentGame {
+    pub round: usize,
+    /// In seat order
+    pub players: Vec<usize>,
+    pub seed: u64,
+    /// Finishing positions in seat order, see [`GameResult::positions`]
+    pub positions: Vec<f64>,
+    pub winner: Option<usize>,
+    /// Who forfeited and why
+    pub forfeit: Option<(usize, String)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Standing {
+    pub name: String,
+    pub games: usize,
+    /// A point for every opponent finished ahead of, half for every one tied with
+    pub points: f64,
+    pub wins: usize,
+    pub forfeits: usize,
+    /// Rounds sat out because there was no table left, which score as if every game was a tie
+    pub byes: usize,
+    /// Sum of finishing positions
+    pub positions: f64,
+    /// The round a knockout participant was knocked out in
+    pub eliminated: Option<usize>,
+}
+
+impl Standing {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            games: 0,
+            points: 0.0,
+            wins: 0,
+            forfeits: 0,
+            byes: 0,
+            positions: 0.0,
+            eliminated: None,
+        }
+    }
+
+    pub fn average_position(&self) -> f64 {
+        self.positions / self.games.max(1) as f64
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tournament {
+    pub format: Format,
+    pub seed: u64,
+    pub rounds_played: usize,
+    /// In the order the participants were entered
+    pub standings: Vec<Standing>,
+    pub games: Vec<TournamentGame>,
+}
+
+impl Tournament {
+    /// The standings best first. Knockouts are ranked by how far participants got, everything
+    /// else by points.
+    pub fn ranking(&self) -> Vec<&Standing> {
+        let mut ranking: Vec<&Standing> = self.standings.iter().collect();
+        ranking.sort_by(|a, b| {
+            // still in beats knocked out, and knocked out later beats knocked out earlier
+            let got_to = |standing: &Standing| standing.eliminated.unwrap_or(usize::MAX);
+            got_to(b)
+                .cmp(&got_to(a))
+                .then(b.points.total_cmp(&a.points))
+                .then(a.average_position().total_cmp(&b.average_position()))
+        });
+        ranking
+    }
+
+    /// Saves the tournament as JSON, replacing the file at `path` only once it is written
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(self)?)?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Reads back a tournament written by [`Tournament::save`]
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn record(&mut self, game: TournamentGame) {
+        let seats = game.players.len() as f64;
+        for (&player, &position) in game.players.iter().zip(&game.positions) {
+            let standing = &mut self.standings[player];
+            standing.games += 1;
+            standing.points += seats - position;
+            standing.positions += position;
+        }
+        if let Some(winner) = game.winner {
+            self.standings[winner].wins += 1;
+        }
+        if let Some((player, _)) = game.forfeit {
+            self.standings[player].forfeits += 1;
+        }
+        self.games.push(game);
+    }
+
+    /// Sits `player` out of a round where its table would have had `seats` seats and played
+    /// `games` games
+    fn bye(&mut self, player: usize, seats: usize, games: usize) {
+        let standing = &mut self.standings[player];
+        standing.byes += 1;
+        standing.points += (seats - 1) as f64 / 2.0 * games as f64;
+    }
+
+    /// Points a participant has won in the games of this round
+    fn round_points(&self, round: usize, player: usize) -> f64 {
+        self.games
+            .iter()
+            .filter(|game| game.round == round)
+            .flat_map(|game| {
+                let seats = game.players.len() as f64;
+                game.players
+                    .iter()
+                    .zip(&game.positions)
+                    .filter(move |(&p, _)| p == player)
+                    .map(move |(_, position)| seats - position)
+            })
+            .sum()
+    }
+}
+
+/// Plays a tournament between `participants` to the end
+///
+/// # Errors
+/// If the tournament can't be saved to `config.storage_path`
+///
+/// # Panics
+/// If there are fewer than 2 participants or the tables have fewer than 2 seats
+pub fn run(participants: &[Participant], config: &TournamentConfig) -> io::Result<Tournament> {
+    assert!(participants.len() >= 2, "A tournament needs 2 participants");
+    assert!(config.table_size >= 2, "A table needs 2 seats");
+    let mut tournament = Tournament {
+        format: config.format,
+        seed: config.seed,
+        rounds_played: 0,
+        standings: participants
+            .iter()
+            .map(|participant| Standing::new(participant.name.clone()))
+            .collect(),
+        games: vec![],
+    };
+    let every: Vec<usize> = (0..participants.len()).collect();
+
+    match config.format {
+        Format::RoundRobin => {
+            let tables = combinations(&every, config.table_size.min(every.len()));
+            play_round(participants, config, &mut tournament, &tables)?;
+        }
+        Format::Swiss { rounds } => {
+            for _ in 0..rounds {
+                let tables = swiss_tables(&mut tournament, config);
+                play_round(participants, config, &mut tournament, &tables)?;
+            }
+        }
+        Format::Knockout => {
+            let mut remaining = every;
+            while remaining.len() > 1 {
+                let round = tournament.rounds_played;
+                let mut through = vec![];
+                if remaining.len() % config.table_size == 1 {
+                    // the best seed goes through without playing
+                    let seats = config.table_size;
+                    tournament.bye(remaining[0], seats, seats * config.games_per_seat);
+                    through.push(remaining.remove(0));
+                }
+                let tables = deal_tables(&remaining, config.table_size);
+                play_round(participants, config, &mut tournament, &tables)?;
+
+                for table in tables {
+                    // ties go to the better seed, which is earlier in the table
+                    let winner = *table
+                        .iter()
+                        .rev()
+                        .max_by(|&&a, &&b| {
+                            let points = |p| tournament.round_points(round, p);
+                            points(a).total_cmp(&points(b))
+                        })
+                        .unwrap();
+                    for &player in &table {
+                        if player != winner {
+                            tournament.standings[player].eliminated = Some(round);
+                        }
+                    }
+                    through.push(winner);
+                }
+                remaining = through;
+            }
+        }
+    }
+
+    Ok(tournament)
+}
+
+/// Plays a match at each table, then saves the tournament
+fn play_round(
+    participants: &[Participant],
+    config: &TournamentConfig,
+    tournament: &mut Tournament,
+    tables: &[Vec<usize>],
+) -> io::Result<()> {
+    let round = tournament.rounds_played;
+    let mut schedule = vec![];
+    for table in tables {
+        if config.duplicate {
+            for _ in 0..config.games_per_seat {
+                let seed = game_seed(
+                    config.seed,
+                    (tournament.games.len() + schedule.len()) as u64,
+                );
+                for seating in permutations(table.len()) {
+                    let players = seating.iter().map(|&i| table[i]).collect();
+                    schedule.push((players, seed));
+                }
+            }
+            continue;
+        }
+        for rotation in 0..table.len() {
+            for _ in 0..config.games_per_seat {
+                let mut players = table.clone();
+                players.rotate_left(rotation);
+                let seed = game_seed(
+                    config.seed,
+                    (tournament.games.len() + schedule.len()) as u64,
+                );
+                schedule.push((players, seed));
+            }
+        }
+    }
+
+    let results = play_games(participants, &schedule, config);
+    for ((players, seed), result) in schedule.into_iter().zip(results) {
+        let winner = match result.outcome {
+            Outcome::Won(seat) => Some(players[seat]),
+            _ => None,
+        };
+        let forfeit = result
+            .forfeit
+            .as_ref()
+            .map(|forfeit| (players[forfeit.seat], forfeit.reason.clone()));
+        tournament.record(TournamentGame {
+            round,
+            positions: result.positions(),
+            players,
+            seed,
+            winner,
+            forfeit,
+        });
+    }
+
+    tournament.rounds_played += 1;
+    match &config.storage_path {
+        Some(path) => tournament.save(path),
+        None => Ok(()),
+    }
+}
+
+/// Plays the games in `schedule` on `config.threads` threads, returning the results in order
+fn play_games(
+    participants: &[Participant],
+    schedule: &[(Vec<usize>, u64)],
+    config: &TournamentConfig,
+) -> Vec<GameResult> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..schedule.len()).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..config.threads.max(1) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((players, seed)) = schedule.get(i) else {
+                    break;
+                };
+                let result = play(participants, players, *seed, &config.rules);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("Every game is played"))
+        .collect()
+}
+
+fn play(participants: &[Participant], players: &[usize], seed: u64, rules: &RuleSet) -> GameResult {
+    let mut bots: Vec<Box<dyn Strategy>> = players
+        .iter()
+        .enumerate()
+        .map(|(seat, &player)| {
+            let make = &participants[player].make;
+            make(seed.wrapping_add(seat as u64 + 1))
+                .unwrap_or_else(|reason| Box::new(FailedToStart(reason)))
+        })
+        .collect();
+    let mut strategies: Vec<&mut dyn Strategy> = bots
+        .iter_mut()
+        .map(|bot| bot.as_mut() as &mut dyn Strategy)
+        .collect();
+    play_game(&mut strategies, seed, rules.clone())
+}
+
+/// Stands in for a bot that couldn't be made, forfeiting straight away
+pub(crate) struct FailedToStart(pub(crate) String);
+
+impl Strategy for FailedToStart {
+    fn on_setup(&mut self, _view: &View) -> SetupAction {
+        SetupAction::FinishExchange
+    }
+
+    fn on_turn(&mut self, _view: &View) -> PlayAction {
+        PlayAction::PickupStack
+    }
+
+    fn forfeit(&self) -> Option<String> {
+        Some(format!("Couldn't start: {}", self.0))
+    }
+}
+
+/// Spreads the `n`th game's seeds apart so games next to each other don't share bot seeds
+pub(crate) fn game_seed(seed: u64, n: u64) -> u64 {
+    seed.wrapping_add(n.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+/// Every way of picking `k` of `players`, in order
+fn combinations(players: &[usize], k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![vec![]];
+    }
+    let mut tables = vec![];
+    for (i, &first) in players.iter().enumerate() {
+        for mut rest in combinations(&players[i + 1..], k - 1) {
+            rest.insert(0, first);
+            tables.push(rest);
+        }
+    }
+    tables
+}
+
+/// Splits `players` into tables of `table_size` or one less, dealing them out like cards so
+/// that the first players are at different tables
+fn deal_tables(players: &[usize], table_size: usize) -> Vec<Vec<usize>> {
+    let count = players.len().div_ceil(table_size);
+    let mut tables = vec![vec![]; count];
+    for (i, &player) in players.iter().enumerate() {
+        let (lap, table) = (i / count, i % count);
+        // back and forth so the tables are evenly matched
+        let table = if lap % 2 == 0 {
+            table
+        } else {
+            count - 1 - table
+        };
+        tables[table].push(player);
+    }
+    tables
+}
+
+/// Seats the participants with those on about as many points, preferring opponents they have
+/// met least. If one participant would be left alone at a table it sits the round out.
+fn swiss_tables(tournament: &mut Tournament, config: &TournamentConfig) -> Vec<Vec<usize>> {
+    let players = tournament.standings.len();
+    let mut met = vec![vec![0; players]; players];
+    for game in &tournament.games {
+        for &a in &game.players {
+            for &b in &game.players {
+                met[a][b] += 1;
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..players).collect();
+    order.sort_by(|&a, &b| {
+        let standings = &tournament.standings;
+        standings[b].points.total_cmp(&standings[a].points)
+    });
+    if players % config.table_size == 1 {
+        // the lowest placed of those who have sat out the fewest rounds
+        let standings = &tournament.standings;
+        let fewest = order.iter().map(|&p| standings[p].byes).min().unwrap();
+        let i = order
+            .iter()
+            .rposition(|&p| standings[p].byes == fewest)
+            .unwrap();
+        let seats = config.table_size;
+        tournament.bye(order.remove(i), seats, seats * config.games_per_seat);
+    }
+
+    let sizes: Vec<usize> = deal_tables(&order, config.table_size)
+        .iter()
+        .map(Vec::len)
+        .collect();
+    let mut tables = vec![];
+    for size in sizes {
+        let mut table = vec![order.remove(0)];
+        while table.len() < size {
+            let i = (0..order.len())
+                .min_by_key(|&i| table.iter().map(|&p| met[p][order[i]]).sum::<usize>())
+                .unwrap();
+            table.push(order.remove(i));
+        }
+        tables.push(table);
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::reference::{Heuristic, LowestCard, Random};
+
+    fn participants() -> Vec<Participant> {
+        vec![
+            Participant::new("random", |seed| Ok(Box::new(Random::new(seed)))),
+            Participant::new("lowest", |_| Ok(Box::new(LowestCard))),
+            Participant::new("heuristic", |_| Ok(Box::new(Heuristic))),
+            Participant::new("broken", |_| Err("no such bot".to_string())),
+            Participant::new("random 2", |seed| Ok(Box::new(Random::new(seed)))),
+        ]
+    }
+
+    #[test]
+    fn test_round_robin_plays_every_table_from_every_seat() {
+        let config = TournamentConfig {
+            table_size: 3,
+            games_per_seat: 2,
+            threads: 3,
+            ..TournamentConfig::default()
+        };
+        let tournament = run(&participants(), &config).unwrap();
+        // 10 tables of 3, each playing 2 games from 3 rotations
+        assert_eq!(tournament.games.len(), 60);
+        for standing in &tournament.standings {
+            assert_eq!(standing.games, 36);
+        }
+        assert_eq!(tournament.standings[3].forfeits, 36);
+        assert_eq!(tournament.ranking().last().unwrap().name, "broken");
+
+        let again = run(&participants(), &config).unwrap();
+        assert_eq!(
+            serde_json::to_string(&tournament).unwrap(),
+            serde_json::to_string(&again).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tournament_is_stored_in_its_file() {
+        let path = std::env::temp_dir()
+            .join(format!("skitgubbe-tournament-{}", std::process::id()))
+            .join("tournament.json");
+        let config = TournamentConfig {
+            threads: 2,
+            storage_path: Some(path.clone()),
+            ..TournamentConfig::default()
+        };
+        let tournament = run(&participants(), &config).unwrap();
+        let stored = Tournament::load(&path).unwrap();
+        assert_eq!(
+            serde_json::to_string(&tournament).unwrap(),
+            serde_json::to_string(&stored).unwrap()
+        );
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_matches_play_each_deal_from_every_seating() {
+        let config = TournamentConfig {
+            games_per_seat: 2,
+            duplicate: true,
+            ..TournamentConfig::default()
+        };
+        let tournament = run(&participants()[..3], &config).unwrap();
+        // 3 tables, 2 deals each from 2 seatings
+        assert_eq!(tournament.games.len(), 12);
+        for pair in tournament.games.chunks(2) {
+            assert_eq!(pair[0].seed, pair[1].seed);
+            assert_eq!(pair[0].players, [pair[1].players[1], pair[1].players[0]]);
+        }
+    }
+
+    #[test]
+    fn test_swiss_and_knockout() {
+        let config = TournamentConfig {
+            format: Format::Swiss { rounds: 3 },
+            ..TournamentConfig::default()
+        };
+        let tournament = run(&participants(), &config).unwrap();
+        assert_eq!(tournament.rounds_played, 3);
+        // 5 players leave one out each round
+        let byes: usize = tournament.standings.iter().map(|s| s.byes).sum();
+        assert_eq!(byes, 3);
+        assert!(tournament.standings.iter().all(|s| s.byes <= 1));
+
+        let config = TournamentConfig {
+            format: Format::Knockout,
+            ..TournamentConfig::default()
+        };
+        let tournament = run(&participants(), &config).unwrap();
+        let still_in: Vec<&Standing> = tournament
+            .standings
+            .iter()
+            .filter(|standing| standing.eliminated.is_none())
+            .collect();
+        assert_eq!(still_in.len(), 1);
+        assert_ne!(still_in[0].name, "broken");
+    }
+}
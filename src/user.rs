@@ -1,23 +1,235 @@
-use axum::extract::ws::{WebSocket, Message};
-use futures_util::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::ws::{Message, WebSocket};
+use futures::channel::mpsc;
+use futures_util::{
+    lock::Mutex,
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use tokio::task::AbortHandle;
+
+/// How often a user's socket is pinged and how long it may stay silent before it is considered
+/// dead.
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// Cheap handle to a [`User`]'s connection that can be used without locking the user
+#[derive(Clone)]
+pub struct Connection {
+    connected: Arc<AtomicBool>,
+    tasks: [AbortHandle; 2],
+}
+
+impl Connection {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Stops reading from and pinging the socket. Anyone waiting on the user's receiver sees the
+    /// user as disconnected.
+    pub fn close(&self) {
+        self.connected.store(false, Ordering::Relaxed);
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
 
 pub struct User {
     pub id: uuid::Uuid,
-    pub sender: SplitSink<WebSocket, axum::extract::ws::Message>,
-    pub receiver: SplitStream<WebSocket>,
+    pub sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    /// Text and binary messages from the socket. Ping/pong frames are consumed by the heartbeat.
+    pub receiver: mpsc::UnboundedReceiver<Result<Message, axum::Error>>,
+    connection: Connection,
 }
 
 impl User {
-    pub fn new(socket: WebSocket) -> Self {
-        let (sender, receiver) = socket.split();
+    pub fn new(socket: WebSocket, heartbeat: Heartbeat) -> Self {
+        let (sender, stream) = socket.split();
+        let sender = Arc::new(Mutex::new(sender));
+        let (forward, receiver) = mpsc::unbounded();
+        let connected = Arc::new(AtomicBool::new(true));
+        let last_seen = Arc::new(std::sync::Mutex::new(Instant::now()));
+
+        let reader = tokio::spawn(Self::read_socket(
+            stream,
+            forward,
+            Arc::clone(&connected),
+            Arc::clone(&last_seen),
+        ));
+        let pinger = tokio::spawn(Self::ping_socket(
+            Arc::clone(&sender),
+            heartbeat,
+            Arc::clone(&connected),
+            last_seen,
+        ));
+
         Self {
             id: uuid::Uuid::new_v4(),
             sender,
             receiver,
+            connection: Connection {
+                connected,
+                tasks: [reader.abort_handle(), pinger.abort_handle()],
+            },
         }
     }
 
+    #[tracing::instrument(level = "trace", skip_all, fields(user_id = %self.id))]
     pub async fn send(&mut self, s: &str) -> Result<(), axum::Error> {
-        self.sender.send(Message::Text(s.to_string())).await
+        tracing::trace!(msg = s, "Sending");
+        let result = self
+            .sender
+            .lock()
+            .await
+            .send(Message::Text(s.to_string()))
+            .await;
+        if let Err(e) = &result {
+            tracing::debug!("Couldn't send message: {e}");
+        }
+        result
+    }
+
+    /// Whether the socket is still open and has answered the heartbeat in time
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_connected()
+    }
+
+    pub fn connection(&self) -> Connection {
+        self.connection.clone()
+    }
+
+    /// Forwards messages from the socket to `forward`, recording every frame as a sign of life
+    async fn read_socket(
+        mut stream: SplitStream<WebSocket>,
+        forward: mpsc::UnboundedSender<Result<Message, axum::Error>>,
+        connected: Arc<AtomicBool>,
+        last_seen: Arc<std::sync::Mutex<Instant>>,
+    ) {
+        while let Some(message) = stream.next().await {
+            *last_seen.lock().unwrap() = Instant::now();
+
+            match message {
+                Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                Ok(Message::Close(_)) => break,
+                Ok(message) => {
+                    if forward.unbounded_send(Ok(message)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = forward.unbounded_send(Err(e));
+                    break;
+                }
+            }
+        }
+
+        connected.store(false, Ordering::Relaxed);
+    }
+
+    /// Pings the socket every `heartbeat.interval` until it stops responding
+    async fn ping_socket(
+        sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+        heartbeat: Heartbeat,
+        connected: Arc<AtomicBool>,
+        last_seen: Arc<std::sync::Mutex<Instant>>,
+    ) {
+        let mut interval = tokio::time::interval(heartbeat.interval);
+        loop {
+            interval.tick().await;
+            if !connected.load(Ordering::Relaxed) {
+                break;
+            }
+
+            if last_seen.lock().unwrap().elapsed() > heartbeat.timeout {
+                connected.store(false, Ordering::Relaxed);
+                let _ = sender.lock().await.close().await;
+                break;
+            }
+
+            if sender
+                .lock()
+                .await
+                .send(Message::Ping(vec![]))
+                .await
+                .is_err()
+            {
+                connected.store(false, Ordering::Relaxed);
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for User {
+    fn drop(&mut self) {
+        self.connection.close();
+    }
+}
+
+/// Sockets for tests, here and in the server
+#[doc(hidden)]
+pub mod testing {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::WebSocketUpgrade, routing::get, Router};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    use super::{Heartbeat, User};
+
+    pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// A user whose socket is connected to the returned client over localhost
+    pub async fn connect(heartbeat: Heartbeat) -> (User, Client) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (send, receive) = tokio::sync::oneshot::channel();
+        let send = Arc::new(Mutex::new(Some(send)));
+        let router = Router::new().route(
+            "/",
+            get(move |ws: WebSocketUpgrade| async move {
+                let send = send.lock().unwrap().take();
+                ws.on_upgrade(move |socket| async move {
+                    if let Some(send) = send {
+                        let _ = send.send(socket);
+                    }
+                })
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        (User::new(receive.await.unwrap(), heartbeat), client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::connect;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_silent_socket_times_out() {
+        let heartbeat = Heartbeat {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+        let (silent, _silent_client) = connect(heartbeat).await;
+        let (answering, mut client) = connect(heartbeat).await;
+        // the client only answers pings while its socket is being read
+        tokio::spawn(async move { while client.next().await.is_some() {} });
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(!silent.is_connected());
+        assert!(answering.is_connected());
     }
 }